@@ -0,0 +1,411 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::Result;
+use futures_util::TryStreamExt;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{debug, info, warn};
+use tokio_util::io::{ReaderStream, StreamReader, SyncIoBridge};
+
+use crate::{messages::ArchiveFormat, throttle::Throttle, utils::http_get};
+
+/// Reject absolute paths and `..` components in archive entry names.
+fn check_entry_path(path: &Path) -> Result<()> {
+    for component in path.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            _ => anyhow::bail!("Archive entry escapes target directory: {}", path.display()),
+        }
+    }
+    Ok(())
+}
+
+fn unpack_tar<R: Read>(reader: R, dir: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        check_entry_path(&path)?;
+        if entry.header().entry_type().is_hard_link()
+            && let Some(target) = entry.link_name()?
+        {
+            check_entry_path(&target)?;
+        }
+        debug!("Extracting {}", path.display());
+        if !entry.unpack_in(dir)? {
+            anyhow::bail!("Archive entry escapes target directory: {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn unpack_zip(file: File, dir: &Path) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(file)?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = match entry.enclosed_name() {
+            Some(name) => name,
+            None => anyhow::bail!("Archive entry escapes target directory: {}", entry.name()),
+        };
+        let out = dir.join(&name);
+        debug!("Extracting {}", name.display());
+        if entry.is_dir() {
+            create_dirs(dir, &name)?;
+        } else {
+            create_dirs(dir, name.parent().unwrap_or(Path::new("")))?;
+            // Replace rather than write through whatever an earlier entry left here
+            if out.symlink_metadata().is_ok_and(|meta| meta.is_symlink()) {
+                fs::remove_file(&out)?;
+            }
+            if entry.is_symlink() {
+                let mut target = String::new();
+                entry.read_to_string(&mut target)?;
+                let resolved = name.parent().unwrap_or(Path::new("")).join(&target);
+                if Path::new(&target).is_absolute() || !stays_inside(&resolved) {
+                    anyhow::bail!(
+                        "Archive symlink escapes target directory: {}",
+                        name.display()
+                    );
+                }
+                if out.symlink_metadata().is_ok() {
+                    fs::remove_file(&out)?;
+                }
+                std::os::unix::fs::symlink(&target, &out)?;
+                continue;
+            }
+            let mut file = File::create(&out)?;
+            io::copy(&mut entry, &mut file)?;
+        }
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&out, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+    }
+    Ok(())
+}
+
+/// Create the directories of a relative path under `dir` without following symlinks,
+/// so links extracted from earlier entries cannot redirect later ones.
+fn create_dirs(dir: &Path, rel: &Path) -> Result<()> {
+    let mut path = dir.to_path_buf();
+    for component in rel.components() {
+        path.push(component);
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => {}
+            Ok(_) => anyhow::bail!(
+                "Archive entry passes through a non-directory: {}",
+                rel.display()
+            ),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => fs::create_dir(&path)?,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Lexically check that a relative path never climbs above its root.
+fn stays_inside(path: &Path) -> bool {
+    let mut depth = 0i32;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                depth -= 1;
+                if depth < 0 {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Download an archive from the given URL and extract it into the given directory while streaming.
+///
+/// Zip archives need random access and are spooled to a temporary file first.
+pub(crate) async fn download_and_extract(
+    url: &str,
+    dir: &str,
+    format: ArchiveFormat,
+    throttle: &Throttle,
+) -> Result<()> {
+    info!("Downloading {:?} archive from {} into {}", format, url, dir);
+    let response = http_get(url).await?;
+    let dir = PathBuf::from(dir);
+    fs::create_dir_all(&dir)?;
+    let stream = throttle.clone().wrap(response.bytes_stream());
+    let mut reader = SyncIoBridge::new(StreamReader::new(stream.map_err(io::Error::other)));
+    tokio::task::spawn_blocking(move || match format {
+        ArchiveFormat::Tar => unpack_tar(reader, &dir),
+        ArchiveFormat::TarGz => unpack_tar(flate2::read::GzDecoder::new(reader), &dir),
+        ArchiveFormat::TarZst => unpack_tar(zstd::stream::read::Decoder::new(reader)?, &dir),
+        ArchiveFormat::Zip => {
+            let mut spool = tempfile::tempfile()?;
+            io::copy(&mut reader, &mut spool)?;
+            unpack_zip(spool, &dir)
+        }
+    })
+    .await?
+}
+
+fn build_globset(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Selects which directory entries end up in an uploaded archive.
+struct EntryFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl EntryFilter {
+    fn excluded(&self, rel: &Path) -> bool {
+        self.exclude.as_ref().is_some_and(|g| g.is_match(rel))
+    }
+
+    fn included(&self, rel: &Path) -> bool {
+        self.include.as_ref().is_none_or(|g| g.is_match(rel))
+    }
+}
+
+fn append_dir<W: Write>(
+    builder: &mut tar::Builder<W>,
+    root: &Path,
+    dir: &Path,
+    filter: &EntryFilter,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let rel = path.strip_prefix(root)?;
+        if filter.excluded(rel) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            append_dir(builder, root, &path, filter)?;
+        } else if filter.included(rel) {
+            debug!("Archiving {}", rel.display());
+            builder.append_path_with_name(&path, rel)?;
+        }
+    }
+    Ok(())
+}
+
+fn pack_tar<W: Write>(writer: W, dir: &Path, filter: &EntryFilter) -> Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    append_dir(&mut builder, dir, dir, filter)?;
+    Ok(builder.into_inner()?)
+}
+
+/// Archive a directory and stream it to the given URL.
+///
+/// Include and exclude globs are matched against paths relative to the directory;
+/// an excluded directory is skipped entirely.
+pub(crate) async fn upload_directory(
+    url: &str,
+    dir: &str,
+    format: ArchiveFormat,
+    include: &[String],
+    exclude: &[String],
+    throttle: &Throttle,
+) -> Result<()> {
+    info!("Uploading {} as {:?} archive to {}", dir, format, url);
+    let filter = EntryFilter {
+        include: build_globset(include)?,
+        exclude: build_globset(exclude)?,
+    };
+    let dir = PathBuf::from(dir);
+    if !dir.is_dir() {
+        anyhow::bail!("{} is not a directory", dir.display());
+    }
+    let (reader, writer) = tokio::io::duplex(256 * 1024);
+    let writer = SyncIoBridge::new(writer);
+    let packer = tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut writer = match format {
+            ArchiveFormat::Tar => pack_tar(writer, &dir, &filter)?,
+            ArchiveFormat::TarGz => {
+                let encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+                pack_tar(encoder, &dir, &filter)?.finish()?
+            }
+            ArchiveFormat::TarZst => {
+                pack_tar(zstd::stream::write::Encoder::new(writer, 0)?, &dir, &filter)?.finish()?
+            }
+            ArchiveFormat::Zip => anyhow::bail!("Zip is not supported for directory upload"),
+        };
+        writer.shutdown()?;
+        Ok(())
+    });
+    let client = reqwest::Client::new();
+    let response = client
+        .put(url)
+        .body(reqwest::Body::wrap_stream(
+            throttle.clone().wrap(ReaderStream::new(reader)),
+        ))
+        .send()
+        .await;
+    let packed = packer.await?;
+    if !response?.status().is_success() {
+        warn!(
+            "Failed to upload archive to {}. Server returned an error.",
+            url
+        );
+        anyhow::bail!("Failed to upload archive to {}", url);
+    }
+    packed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zip::write::SimpleFileOptions;
+
+    /// A tar stream with one entry, its name written into the header as is,
+    /// since the tar builder refuses unsafe names.
+    fn tar_entry(name: &str, kind: tar::EntryType, link: Option<&str>, data: &[u8]) -> Vec<u8> {
+        let mut header = tar::Header::new_gnu();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        if let Some(link) = link {
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        }
+        header.set_entry_type(kind);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, data).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn unpack(archive: Vec<u8>) -> (tempfile::TempDir, Result<()>) {
+        let dir = tempfile::tempdir().unwrap();
+        let result = unpack_tar(&archive[..], dir.path());
+        (dir, result)
+    }
+
+    #[test]
+    fn entry_paths() {
+        assert!(check_entry_path(Path::new("a/./b")).is_ok());
+        assert!(check_entry_path(Path::new("a/../b")).is_err());
+        assert!(check_entry_path(Path::new("/etc/passwd")).is_err());
+        assert!(stays_inside(Path::new("a/b/../../c")));
+        assert!(!stays_inside(Path::new("a/../../c")));
+        assert!(!stays_inside(Path::new("/c")));
+    }
+
+    #[test]
+    fn tar_traversal() {
+        let file = tar::EntryType::Regular;
+        let (dir, result) = unpack(tar_entry("ok/file", file, None, b"data"));
+        result.unwrap();
+        assert_eq!(fs::read(dir.path().join("ok/file")).unwrap(), b"data");
+
+        for name in ["../escaped", "ok/../../escaped", "/tmp/escaped"] {
+            let (dir, result) = unpack(tar_entry(name, file, None, b"data"));
+            assert!(result.is_err(), "{}", name);
+            assert!(!dir.path().parent().unwrap().join("escaped").exists());
+        }
+        let link = tar::EntryType::Link;
+        let (_, result) = unpack(tar_entry("link", link, Some("../../etc/passwd"), b""));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn tar_through_symlink() {
+        let outside = tempfile::tempdir().unwrap();
+        let target = outside.path().to_str().unwrap();
+        let mut archive = tar_entry("out", tar::EntryType::Symlink, Some(target), b"");
+        // Drop the end-of-archive blocks before appending a second entry
+        archive.truncate(archive.len() - 1024);
+        archive.extend(tar_entry(
+            "out/file",
+            tar::EntryType::Regular,
+            None,
+            b"data",
+        ));
+        let (_, result) = unpack(archive);
+        assert!(result.is_err());
+        assert!(!outside.path().join("file").exists());
+    }
+
+    #[test]
+    fn zip_symlinks() {
+        let zip = |target: &str| {
+            let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+            let options = SimpleFileOptions::default();
+            writer.add_symlink("dir/link", target, options).unwrap();
+            let mut spool = tempfile::tempfile().unwrap();
+            spool
+                .write_all(&writer.finish().unwrap().into_inner())
+                .unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            (unpack_zip(spool, dir.path()), dir)
+        };
+        let (result, dir) = zip("../sibling");
+        result.unwrap();
+        let link = fs::read_link(dir.path().join("dir/link")).unwrap();
+        assert_eq!(link, Path::new("../sibling"));
+        assert!(zip("../../escaped").0.is_err());
+        assert!(zip("/etc/passwd").0.is_err());
+    }
+
+    #[test]
+    fn zip_symlink_chain() {
+        // Each link is lexically inside the target, but l2 lands in the target root
+        // once l1 is followed on disk, and evil would then land outside it.
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        writer.add_symlink("d/l1", "..", options).unwrap();
+        writer.add_symlink("d/l1/l2", "..", options).unwrap();
+        writer.start_file("d/l1/l2/evil", options).unwrap();
+        writer.write_all(b"data").unwrap();
+        let mut spool = tempfile::tempfile().unwrap();
+        spool
+            .write_all(&writer.finish().unwrap().into_inner())
+            .unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let dir = outside.path().join("target");
+        fs::create_dir(&dir).unwrap();
+        assert!(unpack_zip(spool, &dir).is_err());
+        assert!(!outside.path().join("evil").exists());
+        assert!(!dir.join("l2").exists());
+    }
+
+    #[test]
+    fn packed_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        for path in ["a.log", "b.txt", "cache/c.log", "sub/d.log"] {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"x").unwrap();
+        }
+        let filter = EntryFilter {
+            include: build_globset(&["**/*.log".to_string()]).unwrap(),
+            exclude: build_globset(&["cache".to_string()]).unwrap(),
+        };
+        let archive = pack_tar(Vec::new(), dir.path(), &filter).unwrap();
+        let mut names: Vec<String> = tar::Archive::new(&archive[..])
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["a.log", "sub/d.log"]);
+    }
+}
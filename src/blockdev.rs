@@ -0,0 +1,161 @@
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+    facts::{read_trimmed, sorted_entries},
+    hostfs,
+    messages::{BlockDevice, BlockPartition, Transport},
+};
+
+/// Link directories under `/dev/disk`, kept up by udev.
+const BY_ID: &str = "/dev/disk/by-id";
+const BY_PATH: &str = "/dev/disk/by-path";
+
+fn read_u64(path: impl AsRef<Path>) -> Option<u64> {
+    read_trimmed(path)?.parse().ok()
+}
+
+/// Names of the devices below `dir/holders`.
+pub(crate) fn holders(dir: &Path) -> Vec<String> {
+    let holders = dir.join("holders");
+    if !holders.is_dir() {
+        return Vec::new();
+    }
+    sorted_entries(&holders)
+}
+
+/// Map device names to the symlinks pointing at them in a `/dev/disk` directory.
+fn links(dir: &str) -> HashMap<String, Vec<String>> {
+    let mut links: HashMap<String, Vec<String>> = HashMap::new();
+    let Ok(entries) = std::fs::read_dir(hostfs::path(dir)) else {
+        return links;
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let Some(name) = std::fs::read_link(entry.path())
+            .ok()
+            .and_then(|target| target.file_name().map(|n| n.to_string_lossy().to_string()))
+        else {
+            continue;
+        };
+        links.entry(name).or_default().push(format!(
+            "{}/{}",
+            dir,
+            entry.file_name().to_string_lossy()
+        ));
+    }
+    for paths in links.values_mut() {
+        paths.sort();
+    }
+    links
+}
+
+/// Tell the transport from where the disk sits in the device tree.
+fn transport(name: &str, dir: &Path) -> Option<Transport> {
+    let path = std::fs::canonicalize(dir).ok()?;
+    let path = path.to_string_lossy();
+    if name.starts_with("nvme") {
+        Some(Transport::Nvme)
+    } else if name.starts_with("mmcblk") {
+        Some(Transport::Mmc)
+    } else if path.contains("/usb") {
+        // Checked before SATA, USB bridges show up as ATA devices too
+        Some(Transport::Usb)
+    } else if path.contains("/ata") {
+        Some(Transport::Sata)
+    } else if path.contains("/end_device-") {
+        Some(Transport::Sas)
+    } else if path.contains("/virtio") {
+        Some(Transport::Virtio)
+    } else if dir.join("device/scsi_level").exists() {
+        Some(Transport::Scsi)
+    } else {
+        None
+    }
+}
+
+/// Partitions of the disk `name` found in its sysfs directory.
+pub(crate) fn partitions(name: &str, dir: &Path) -> Vec<BlockPartition> {
+    sorted_entries(dir)
+        .into_iter()
+        .filter(|entry| entry.starts_with(name))
+        .filter_map(|part| {
+            let pdir = dir.join(&part);
+            let number = read_u64(pdir.join("partition"))?;
+            Some(BlockPartition {
+                path: format!("/dev/{}", part),
+                number: Some(number as u32),
+                start: read_u64(pdir.join("start")).unwrap_or(0) * 512,
+                size: read_u64(pdir.join("size")).unwrap_or(0) * 512,
+                holders: holders(&pdir),
+                name: part,
+            })
+        })
+        .collect()
+}
+
+/// Disks backed by a device, with what sysfs and udev know about them. Loop,
+/// ram, dm and md devices are left out; they show up as holders instead.
+pub(crate) fn disks() -> Vec<BlockDevice> {
+    let (by_id, by_path) = (links(BY_ID), links(BY_PATH));
+    let block = hostfs::path("/sys/block");
+    sorted_entries(&block)
+        .into_iter()
+        .filter(|name| block.join(name).join("device").exists())
+        .map(|name| {
+            let dir = block.join(&name);
+            let attr = |attr: &str| read_trimmed(dir.join(attr));
+            let flag = |attr: &str| read_u64(dir.join(attr)) == Some(1);
+            // virtio-blk keeps the serial on the disk, SCSI and NVMe on the device
+            let serial = attr("device/serial").or_else(|| attr("serial"));
+            let wwn = attr("wwid").or_else(|| attr("device/wwid"));
+            BlockDevice {
+                path: format!("/dev/{}", name),
+                size: read_u64(dir.join("size")).unwrap_or(0) * 512,
+                logical_sector_size: read_u64(dir.join("queue/logical_block_size")).unwrap_or(512),
+                physical_sector_size: read_u64(dir.join("queue/physical_block_size"))
+                    .unwrap_or(512),
+                rotational: flag("queue/rotational"),
+                removable: flag("removable"),
+                read_only: flag("ro"),
+                vendor: attr("device/vendor").filter(|v| !v.is_empty()),
+                model: attr("device/model").filter(|m| !m.is_empty()),
+                serial: serial.filter(|s| !s.is_empty()),
+                wwn: wwn.filter(|w| !w.is_empty()),
+                transport: transport(&name, &dir),
+                partitions: partitions(&name, &dir),
+                holders: holders(&dir),
+                by_id: by_id.get(&name).cloned().unwrap_or_default(),
+                by_path: by_path.get(&name).cloned().unwrap_or_default(),
+                name,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disks_from_fixture() {
+        let disks = disks();
+        assert_eq!(disks.len(), 1);
+        let vda = &disks[0];
+        assert_eq!(vda.name, "vda");
+        assert_eq!(vda.path, "/dev/vda");
+        assert_eq!(vda.size, 536870912 * 512);
+        assert_eq!(vda.logical_sector_size, 512);
+        assert_eq!(vda.physical_sector_size, 4096);
+        assert!(vda.rotational);
+        assert!(!vda.removable);
+        assert_eq!(vda.serial.as_deref(), Some("overlayblk"));
+        assert!(matches!(vda.transport, Some(Transport::Virtio)));
+        assert_eq!(vda.by_id, ["/dev/disk/by-id/virtio-overlayblk"]);
+        assert_eq!(vda.by_path, ["/dev/disk/by-path/pci-0000:00:02.0"]);
+        assert_eq!(vda.partitions.len(), 1);
+        let part = &vda.partitions[0];
+        assert_eq!(part.path, "/dev/vda1");
+        assert_eq!(part.number, Some(1));
+        assert_eq!(part.start, 2048 * 512);
+        assert_eq!(part.size, 1046528 * 512);
+    }
+}
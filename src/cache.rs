@@ -0,0 +1,299 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use tempfile::NamedTempFile;
+
+use crate::{
+    messages::CacheEntry,
+    segmented::download_segmented,
+    throttle::Throttle,
+    utils::{sha256_file, verify_digest},
+};
+
+const DEFAULT_CACHE_DIR: &str = "/var/cache/mxa";
+const DEFAULT_MAX_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+/// Download cache shared by all requests, configured with `MXA_CACHE_DIR` and `MXA_CACHE_MAX_SIZE`.
+static CACHE: LazyLock<Cache> = LazyLock::new(|| {
+    let dir = std::env::var("MXA_CACHE_DIR").unwrap_or_else(|_| DEFAULT_CACHE_DIR.to_string());
+    let max_size = std::env::var("MXA_CACHE_MAX_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_SIZE);
+    Cache::new(PathBuf::from(dir), max_size)
+});
+
+pub(crate) fn global() -> &'static Cache {
+    &CACHE
+}
+
+/// Normalise a SHA-256 digest to lowercase hex, rejecting anything else.
+pub(crate) fn normalize_digest(digest: &str) -> Result<String> {
+    let hex = digest.strip_prefix("sha256:").unwrap_or(digest);
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid SHA-256 digest: {}", digest);
+    }
+    Ok(hex.to_ascii_lowercase())
+}
+
+/// Place `src` at `dest` as a copy, or as a hard link when asked and possible.
+async fn place(src: &Path, dest: &Path, link: bool) -> Result<()> {
+    if link {
+        if dest.symlink_metadata().is_ok() {
+            tokio::fs::remove_file(dest).await?;
+        }
+        match tokio::fs::hard_link(src, dest).await {
+            Ok(()) => return Ok(()),
+            Err(err) => debug!(
+                "Failed to link {} to {}, copying: {}",
+                src.display(),
+                dest.display(),
+                err
+            ),
+        }
+    }
+    tokio::fs::copy(src, dest).await?;
+    Ok(())
+}
+
+/// Content-addressed store of downloaded files with size-bounded LRU eviction.
+///
+/// Entries are stored as `<dir>/sha256/<hex>`; the modification time records the
+/// last use. Entries are always copied in, but may be hard linked out, so they
+/// are hashed again before each use.
+pub(crate) struct Cache {
+    dir: PathBuf,
+    max_size: u64,
+    lock: Mutex<()>,
+}
+
+impl Cache {
+    fn new(dir: PathBuf, max_size: u64) -> Self {
+        Cache {
+            dir,
+            max_size,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn entries_dir(&self) -> PathBuf {
+        self.dir.join("sha256")
+    }
+
+    fn entry_path(&self, digest: &str) -> Result<PathBuf> {
+        Ok(self.entries_dir().join(normalize_digest(digest)?))
+    }
+
+    /// Copy or link the cached file with the given digest to `dest`.
+    ///
+    /// Returns `false` on a cache miss. An entry that no longer matches its
+    /// digest, for instance because a linked copy was modified, is dropped.
+    pub(crate) async fn fetch(&self, digest: &str, dest: &str, link: bool) -> Result<bool> {
+        let entry = self.entry_path(digest)?;
+        if !entry.is_file() {
+            return Ok(false);
+        }
+        let actual = sha256_file(&entry.to_string_lossy()).await?;
+        if verify_digest(Some(digest), &actual).is_err() {
+            warn!("Cached {} was modified, dropping it", digest);
+            let _guard = self.lock.lock().unwrap();
+            fs::remove_file(&entry)?;
+            return Ok(false);
+        }
+        info!("Cache hit for {}, placing it at {}", digest, dest);
+        if let Ok(file) = fs::File::open(&entry) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        place(&entry, Path::new(dest), link).await?;
+        Ok(true)
+    }
+
+    /// A temporary file next to the entries, removed unless stored.
+    async fn temp_file(&self) -> Result<NamedTempFile> {
+        tokio::fs::create_dir_all(self.entries_dir()).await?;
+        Ok(tempfile::Builder::new()
+            .prefix(".")
+            .tempfile_in(self.entries_dir())?)
+    }
+
+    /// Turn a temporary file holding the content of `digest` into its entry.
+    fn store(&self, tmp: NamedTempFile, entry: &Path, src: &str) -> Result<()> {
+        if tmp.as_file().metadata()?.len() > self.max_size {
+            debug!("{} is larger than the cache, not cached", src);
+            return Ok(());
+        }
+        tmp.as_file().set_modified(SystemTime::now())?;
+        tmp.persist(entry)?;
+        info!("Cached {} as {}", src, entry.display());
+        self.evict()
+    }
+
+    /// Add a copy of a file whose content has already been verified against
+    /// `digest`. The file itself stays with its owner.
+    pub(crate) async fn insert(&self, digest: &str, src: &str) -> Result<()> {
+        let entry = self.entry_path(digest)?;
+        if entry.is_file() {
+            return Ok(());
+        }
+        let tmp = self.temp_file().await?;
+        tokio::fs::copy(src, tmp.path()).await?;
+        self.store(tmp, &entry, src)
+    }
+
+    /// Download a file straight into the cache.
+    pub(crate) async fn prewarm(
+        &self,
+        urls: &[String],
+        digest: &str,
+        throttle: &Throttle,
+    ) -> Result<()> {
+        let entry = self.entry_path(digest)?;
+        if entry.is_file() {
+            return Ok(());
+        }
+        let tmp = self.temp_file().await?;
+        let tmp_path = tmp.path().to_string_lossy().to_string();
+        download_segmented(urls, &tmp_path, 1, throttle).await?;
+        verify_digest(Some(digest), &sha256_file(&tmp_path).await?)?;
+        self.store(tmp, &entry, &urls.join(", "))
+    }
+
+    /// Remove one entry, or every entry when no digest is given.
+    pub(crate) fn purge(&self, digest: Option<&str>) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        match digest {
+            Some(digest) => {
+                let entry = self.entry_path(digest)?;
+                if entry.is_file() {
+                    fs::remove_file(entry)?;
+                }
+            }
+            None => {
+                for entry in self.list_locked()? {
+                    fs::remove_file(self.entries_dir().join(&entry.digest))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn list(&self) -> Result<Vec<CacheEntry>> {
+        let _guard = self.lock.lock().unwrap();
+        self.list_locked()
+    }
+
+    /// List entries, least recently used first.
+    fn list_locked(&self) -> Result<Vec<CacheEntry>> {
+        let dir = match fs::read_dir(self.entries_dir()) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut entries = Vec::new();
+        for item in dir {
+            let item = item?;
+            let name = item.file_name().to_string_lossy().to_string();
+            if normalize_digest(&name).is_err() {
+                continue;
+            }
+            let meta = item.metadata()?;
+            let last_used = meta
+                .modified()?
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            entries.push(CacheEntry {
+                digest: name,
+                size: meta.len(),
+                last_used,
+            });
+        }
+        entries.sort_by_key(|e| e.last_used);
+        Ok(entries)
+    }
+
+    /// Drop least recently used entries until the cache fits its size bound.
+    fn evict(&self) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let entries = self.list_locked()?;
+        let mut total: u64 = entries.iter().map(|e| e.size).sum();
+        for entry in entries {
+            if total <= self.max_size {
+                break;
+            }
+            info!("Evicting {} from cache", entry.digest);
+            if let Err(err) = fs::remove_file(self.entries_dir().join(&entry.digest)) {
+                warn!("Failed to evict {}: {}", entry.digest, err);
+                continue;
+            }
+            total -= entry.size;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn file(dir: &Path, name: &str, content: &[u8]) -> (String, String) {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        (
+            path.to_string_lossy().to_string(),
+            hex::encode(Sha256::digest(content)),
+        )
+    }
+
+    #[tokio::test]
+    async fn linked_copies_cannot_poison_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().join("cache"), 1 << 20);
+        let (src, digest) = file(dir.path(), "src", b"payload");
+        cache.insert(&digest, &src).await.unwrap();
+        // The entry is a copy, not the caller's file
+        fs::write(&src, b"changed").unwrap();
+        let dest = dir.path().join("dest").to_string_lossy().to_string();
+        assert!(cache.fetch(&digest, &dest, true).await.unwrap());
+        assert_eq!(fs::read(&dest).unwrap(), b"payload");
+
+        // Writing through the hard link is caught on the next hit
+        fs::write(&dest, b"tampered").unwrap();
+        let other = dir.path().join("other").to_string_lossy().to_string();
+        assert!(!cache.fetch(&digest, &other, true).await.unwrap());
+        assert!(cache.list().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_inserts() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().join("cache"), 1 << 20);
+        let content = vec![7u8; 256 * 1024];
+        let (a, digest) = file(dir.path(), "a", &content);
+        let (b, _) = file(dir.path(), "b", &content);
+        let (first, second) = tokio::join!(cache.insert(&digest, &a), cache.insert(&digest, &b));
+        first.unwrap();
+        second.unwrap();
+        let entries = cache.list().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].size, content.len() as u64);
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(cache.entries_dir()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn entries_larger_than_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().join("cache"), 4);
+        let (src, digest) = file(dir.path(), "src", b"too large");
+        cache.insert(&digest, &src).await.unwrap();
+        assert_eq!(fs::read_dir(cache.entries_dir()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    ffi::CString,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use tokio::process::Command;
+
+/// Host directories bound into the target root while commands run in it.
+const BINDS: &[&str] = &["/dev", "/proc", "/sys", "/run"];
+
+/// Number of commands running in the target root; the binds stay up while any is.
+static USERS: Mutex<u32> = Mutex::new(0);
+
+fn cstring(path: &Path) -> Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+fn mount(source: Option<&Path>, target: &Path, flags: libc::c_ulong) -> Result<()> {
+    let source = source.map(cstring).transpose()?;
+    let target = cstring(target)?;
+    let source = source.as_ref().map_or(std::ptr::null(), |s| s.as_ptr());
+    // SAFETY: mount with valid C strings or null where allowed
+    let ret = unsafe {
+        libc::mount(
+            source,
+            target.as_ptr(),
+            std::ptr::null(),
+            flags,
+            std::ptr::null(),
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn detach(path: &Path) -> Result<()> {
+    let target = cstring(path)?;
+    // SAFETY: umount2 with a valid C string
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn teardown(mounted: &[PathBuf]) {
+    for path in mounted.iter().rev() {
+        debug!("Detaching {}", path.display());
+        if let Err(err) = detach(path) {
+            warn!("Failed to unmount {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Bind mounts of [`BINDS`] under a root, shared by concurrent commands and
+/// torn down when the last one is dropped.
+struct Binds {
+    root: PathBuf,
+}
+
+impl Binds {
+    fn setup(root: &Path) -> Result<Self> {
+        let mut users = USERS.lock().unwrap();
+        if *users == 0 {
+            let mut mounted = Vec::new();
+            for dir in BINDS {
+                let target = root.join(dir.trim_start_matches('/'));
+                // Slave propagation keeps mounts made inside from showing up on the host
+                let result = std::fs::create_dir_all(&target)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| {
+                        mount(Some(Path::new(dir)), &target, libc::MS_BIND | libc::MS_REC)
+                    })
+                    .inspect(|_| mounted.push(target.clone()))
+                    .and_then(|_| mount(None, &target, libc::MS_SLAVE | libc::MS_REC));
+                if let Err(err) = result {
+                    teardown(&mounted);
+                    anyhow::bail!("Failed to bind {} into {}: {}", dir, root.display(), err);
+                }
+            }
+            info!("Bound {} into {}", BINDS.join(", "), root.display());
+        }
+        *users += 1;
+        Ok(Binds {
+            root: root.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for Binds {
+    fn drop(&mut self) {
+        let mut users = USERS.lock().unwrap();
+        *users -= 1;
+        if *users == 0 {
+            let mounted: Vec<PathBuf> = BINDS
+                .iter()
+                .map(|dir| self.root.join(dir.trim_start_matches('/')))
+                .collect();
+            teardown(&mounted);
+            info!("Unbound {} from {}", BINDS.join(", "), self.root.display());
+        }
+    }
+}
+
+/// Kills the whole process group of a command unless it was waited for, so
+/// nothing it started keeps the binds busy.
+struct Group {
+    pid: Option<i32>,
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid {
+            warn!("Killing process group {}", pid);
+            // SAFETY: kill takes no pointers
+            unsafe { libc::kill(-pid, libc::SIGKILL) };
+        }
+    }
+}
+
+/// Run a shell command chrooted into `root`, with `/dev`, `/proc`, `/sys` and
+/// `/run` bound inside it. Dropping the future kills the command and unbinds.
+pub(crate) async fn execute_shell(cmd: &str, root: &Path) -> Result<(i32, String, String)> {
+    if !root.is_dir() {
+        anyhow::bail!("Target root {} does not exist", root.display());
+    }
+    let _binds = Binds::setup(root)?;
+    info!("Executing in {}: {}", root.display(), cmd);
+    let root_c = cstring(root)?;
+    let mut command = Command::new("/bin/sh");
+    command
+        .args(["-c", cmd])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true);
+    // SAFETY: only async-signal-safe calls between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if libc::chroot(root_c.as_ptr()) < 0 || libc::chdir(c"/".as_ptr()) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command
+        .spawn()
+        .map_err(|err| anyhow::anyhow!("Failed to run /bin/sh in {}: {}", root.display(), err))?;
+    let mut group = Group {
+        pid: child.id().map(|pid| pid as i32),
+    };
+    let output = child.wait_with_output().await?;
+    group.pid = None;
+    Ok((
+        output.status.code().unwrap_or(-1),
+        String::from_utf8_lossy(&output.stdout).to_string(),
+        String::from_utf8_lossy(&output.stderr).to_string(),
+    ))
+}
@@ -56,7 +56,7 @@ async fn recv_pack(socket: &UdpSocket) -> Result<Vec<String>> {
                 Ok(wss)
             } else {
                 error!("Invalid magic: {}", resp.magic);
-                Err(anyhow!("Invalid magic").into())
+                Err(anyhow!("Invalid magic"))
             }
         }
         Err(err) => {
@@ -0,0 +1,87 @@
+use std::{
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, trace};
+use tokio::sync::broadcast;
+
+use crate::messages::{AgentEvent, AgentEventPayload};
+
+/// Events waiting for the connection to send them; older ones are dropped
+/// when it falls further behind.
+const CAPACITY: usize = 256;
+
+static SEQ: AtomicU64 = AtomicU64::new(0);
+/// Held while numbering and queueing an event, so events are queued in the
+/// order of their numbers.
+static ORDER: Mutex<()> = Mutex::new(());
+
+static EVENTS: LazyLock<broadcast::Sender<AgentEvent>> =
+    LazyLock::new(|| broadcast::Sender::new(CAPACITY));
+
+/// Number and timestamp an event.
+fn event(payload: AgentEventPayload) -> AgentEvent {
+    AgentEvent {
+        seq: SEQ.fetch_add(1, Ordering::Relaxed),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0),
+        event: payload,
+    }
+}
+
+/// Queue an event for the controller. It is lost when no connection is up.
+pub(crate) fn emit(payload: AgentEventPayload) {
+    let _order = ORDER.lock().unwrap();
+    let event = event(payload);
+    trace!("Emitting event {}", event.seq);
+    if EVENTS.send(event).is_err() {
+        debug!("Not connected, event dropped");
+    }
+}
+
+/// Number `first`, to be sent directly, and subscribe to every event numbered
+/// after it.
+pub(crate) fn subscribe(first: AgentEventPayload) -> (AgentEvent, broadcast::Receiver<AgentEvent>) {
+    let _order = ORDER.lock().unwrap();
+    (event(first), EVENTS.subscribe())
+}
+
+/// Number of the latest event.
+pub(crate) fn last_seq() -> u64 {
+    SEQ.load(Ordering::Relaxed).saturating_sub(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::AgentState;
+
+    #[test]
+    fn queued_in_order_after_the_first() {
+        let emitters: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    for _ in 0..50 {
+                        emit(AgentEventPayload::StateChanged(AgentState::Ready));
+                    }
+                })
+            })
+            .collect();
+        let (first, mut queued) = subscribe(AgentEventPayload::StateChanged(AgentState::Ready));
+        for emitter in emitters {
+            emitter.join().unwrap();
+        }
+        let mut previous = first.seq;
+        while let Ok(event) = queued.try_recv() {
+            assert!(event.seq > previous, "{} after {}", event.seq, previous);
+            previous = event.seq;
+        }
+        assert_eq!(last_seq(), previous);
+    }
+}
@@ -1,57 +1,676 @@
+use std::time::Duration;
+
 use anyhow::Result;
 use log::{trace, warn};
 
+use crate::archive;
+use crate::cache;
+use crate::chroot;
+use crate::filesystem;
+use crate::follow;
+use crate::hostfs;
+use crate::image;
+use crate::inventory;
+use crate::kexec;
 use crate::messages::{
-    AgentResponsePayload, CommandExecutionResponse, ControllerRequest, FileOperationResponse,
+    AgentResponsePayload, ArchiveFormat, CacheRequest, CacheResponse, CancelResponse,
+    CommandExecutionResponse, ControllerRequest, DiskInspectResponse, FileFollowResponse,
+    FileOperationRequest, FileOperationResponse, FileTransport, FilesystemRequest,
+    FilesystemResponse, ImageWriteRequest, ImageWriteResponse, KexecRequest, KexecResponse,
+    PartitionRequest, PartitionResponse, PersonalizeRequest, PersonalizeResponse, PowerAction,
+    PowerRequest, PowerResponse, RateLimitResponse, RenderFileRequest, RenderFileResponse,
+    TelemetryRequest, TelemetryResponse, WipeRequest, WipeResponse,
 };
+use crate::mounts;
 use crate::net::{Context, Request};
-use crate::utils::{download_file, execute_shell_with_output, upload_file};
+use crate::partition;
+use crate::personalize;
+use crate::power;
+use crate::render;
+use crate::segmented;
+use crate::telemetry;
+use crate::throttle::{self, Throttle};
+use crate::transfer;
+use crate::utils::{
+    download_file, execute_shell_with_output, sha256_file, upload_file, verify_digest,
+};
+use crate::wipe;
 
 struct FileDownloadUploadTask {
     url: String,
     path: String,
+    transport: FileTransport,
+    offset: u64,
+    digest: Option<String>,
+    chunk_size: u32,
+    window: u32,
+    archive: Option<ArchiveFormat>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    throttle: Throttle,
+    mirrors: Vec<String>,
+    parallelism: u32,
+    cache_link: bool,
 }
 
 impl FileDownloadUploadTask {
+    fn new(req: &FileOperationRequest) -> Self {
+        FileDownloadUploadTask {
+            url: req.url.clone(),
+            path: req.path.clone(),
+            transport: req.transport.clone(),
+            offset: req.offset,
+            digest: req.digest.clone(),
+            chunk_size: req.chunk_size.unwrap_or(transfer::DEFAULT_CHUNK_SIZE),
+            window: req.window.unwrap_or(transfer::DEFAULT_WINDOW),
+            archive: req.archive,
+            include: req.include.clone(),
+            exclude: req.exclude.clone(),
+            throttle: Throttle::new(req.rate_limit),
+            mirrors: req.mirrors.clone(),
+            parallelism: req.parallelism.unwrap_or(1).max(1),
+            cache_link: req.cache_link,
+        }
+    }
+
+    async fn download(&self, ctx: &Context) -> Result<FileOperationResponse> {
+        if let Some(format) = self.archive {
+            if let FileTransport::WebSocket = self.transport {
+                anyhow::bail!("Archive extraction is only supported over HTTP transport");
+            }
+            archive::download_and_extract(&self.url, &self.path, format, &self.throttle).await?;
+            return Ok(FileOperationResponse {
+                success: true,
+                ..Default::default()
+            });
+        }
+        match self.transport {
+            FileTransport::Http => {
+                if self.offset != 0 {
+                    anyhow::bail!("Resume offset is only supported over websocket transport");
+                }
+                if let Some(digest) = &self.digest
+                    && cache::global()
+                        .fetch(digest, &self.path, self.cache_link)
+                        .await?
+                {
+                    return Ok(FileOperationResponse {
+                        success: true,
+                        size: Some(tokio::fs::metadata(&self.path).await?.len()),
+                        digest: Some(cache::normalize_digest(digest)?),
+                        cache_hit: true,
+                        error: None,
+                    });
+                }
+                if self.mirrors.is_empty() && self.parallelism == 1 {
+                    download_file(&self.url, &self.path, &self.throttle).await?;
+                } else {
+                    let urls: Vec<String> = std::iter::once(&self.url)
+                        .filter(|url| !url.is_empty())
+                        .chain(&self.mirrors)
+                        .cloned()
+                        .collect();
+                    segmented::download_segmented(
+                        &urls,
+                        &self.path,
+                        self.parallelism,
+                        &self.throttle,
+                    )
+                    .await?;
+                }
+                let digest = sha256_file(&self.path).await?;
+                verify_digest(self.digest.as_deref(), &digest)?;
+                if self.digest.is_some()
+                    && let Err(err) = cache::global().insert(&digest, &self.path).await
+                {
+                    warn!("Failed to cache '{}': {}", self.path, err);
+                }
+                Ok(FileOperationResponse {
+                    success: true,
+                    size: Some(tokio::fs::metadata(&self.path).await?.len()),
+                    digest: Some(digest),
+                    cache_hit: false,
+                    error: None,
+                })
+            }
+            FileTransport::WebSocket => {
+                transfer::receive_file(ctx, &self.path, self.offset, self.digest.as_deref()).await
+            }
+        }
+    }
+
+    async fn upload(&self, ctx: &Context) -> Result<FileOperationResponse> {
+        if let Some(format) = self.archive {
+            if let FileTransport::WebSocket = self.transport {
+                anyhow::bail!("Directory upload is only supported over HTTP transport");
+            }
+            archive::upload_directory(
+                &self.url,
+                &self.path,
+                format,
+                &self.include,
+                &self.exclude,
+                &self.throttle,
+            )
+            .await?;
+            return Ok(FileOperationResponse {
+                success: true,
+                ..Default::default()
+            });
+        }
+        match self.transport {
+            FileTransport::Http => {
+                if self.offset != 0 {
+                    anyhow::bail!("Resume offset is only supported over websocket transport");
+                }
+                upload_file(&self.url, &self.path, &self.throttle).await?;
+                Ok(FileOperationResponse {
+                    success: true,
+                    ..Default::default()
+                })
+            }
+            FileTransport::WebSocket => {
+                transfer::send_file(
+                    ctx,
+                    &self.path,
+                    self.offset,
+                    self.chunk_size,
+                    self.window,
+                    &self.throttle,
+                )
+                .await
+            }
+        }
+    }
+
     async fn handle_download(self, ctx: Context) -> Result<()> {
-        if let Err(err) = download_file(&self.url, &self.path).await {
-            warn!(
-                "Failed to download file from '{}' to '{}': {}",
-                self.url, self.path, err
-            );
+        match self.download(&ctx).await {
+            Ok(resp) => {
+                ctx.respond2(true, AgentResponsePayload::FileOperationResponse(resp))
+                    .await
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to download file from '{}' to '{}': {}",
+                    self.url, self.path, err
+                );
+                ctx.respond2(
+                    false,
+                    AgentResponsePayload::FileOperationResponse(FileOperationResponse {
+                        success: false,
+                        error: Some(err.to_string()),
+                        ..Default::default()
+                    }),
+                )
+                .await
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_upload(self, ctx: Context) -> Result<()> {
+        match self.upload(&ctx).await {
+            Ok(resp) => {
+                ctx.respond2(true, AgentResponsePayload::FileOperationResponse(resp))
+                    .await
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to upload file from '{}' to '{}': {}",
+                    self.path, self.url, err
+                );
+                ctx.respond2(
+                    false,
+                    AgentResponsePayload::FileOperationResponse(FileOperationResponse {
+                        success: false,
+                        error: Some(err.to_string()),
+                        ..Default::default()
+                    }),
+                )
+                .await
+            }
+        }
+        Ok(())
+    }
+}
+
+enum CacheTask {
+    Prewarm { urls: Vec<String>, digest: String },
+    Purge { digest: Option<String> },
+    List,
+}
+
+impl CacheTask {
+    async fn run(&self) -> Result<()> {
+        match self {
+            CacheTask::Prewarm { urls, digest } => {
+                cache::global()
+                    .prewarm(urls, digest, &Throttle::default())
+                    .await
+            }
+            CacheTask::Purge { digest } => cache::global().purge(digest.as_deref()),
+            CacheTask::List => Ok(()),
+        }
+    }
+
+    async fn handle(self, ctx: Context) -> Result<()> {
+        let result = self.run().await.and_then(|_| cache::global().list());
+        match result {
+            Ok(entries) => {
+                ctx.respond2(
+                    true,
+                    AgentResponsePayload::CacheResponse(CacheResponse { entries }),
+                )
+                .await
+            }
+            Err(err) => {
+                warn!("Failed to handle cache request: {}", err);
+                ctx.respond2(
+                    false,
+                    AgentResponsePayload::CacheResponse(CacheResponse {
+                        entries: Vec::new(),
+                    }),
+                )
+                .await
+            }
+        }
+        Ok(())
+    }
+}
+
+struct FollowTask {
+    path: String,
+    offset: Option<u64>,
+    lines: Option<usize>,
+}
+
+impl FollowTask {
+    async fn handle(self, ctx: Context) -> Result<()> {
+        if let Err(err) = follow::follow_file(&ctx, &self.path, self.offset, self.lines).await {
+            warn!("Failed to follow file '{}': {}", self.path, err);
             ctx.respond2(
                 false,
-                AgentResponsePayload::FileOperationResponse(FileOperationResponse {
-                    success: false,
+                AgentResponsePayload::FileFollowResponse(FileFollowResponse {
+                    done: true,
+                    error: Some(err.to_string()),
+                    ..Default::default()
                 }),
             )
             .await;
         }
+        Ok(())
+    }
+}
+
+struct RenderTask {
+    req: RenderFileRequest,
+}
+
+impl RenderTask {
+    async fn handle(self, ctx: Context) -> Result<()> {
+        let req = self.req;
+        let path = req.path.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            render::render_file(
+                &req.template,
+                &req.variables,
+                &req.path,
+                req.mode,
+                req.dry_run,
+            )
+        })
+        .await?;
+        match result {
+            Ok(resp) => {
+                ctx.respond2(true, AgentResponsePayload::RenderFileResponse(resp))
+                    .await
+            }
+            Err(err) => {
+                warn!("Failed to render '{}': {}", path, err);
+                ctx.respond2(
+                    false,
+                    AgentResponsePayload::RenderFileResponse(RenderFileResponse {
+                        changed: false,
+                        diff: String::new(),
+                        error: Some(err.to_string()),
+                    }),
+                )
+                .await
+            }
+        }
+        Ok(())
+    }
+}
+
+struct DiskInspectTask {
+    path: String,
+}
+
+impl DiskInspectTask {
+    async fn handle(self, ctx: Context) -> Result<()> {
+        let path = self.path.clone();
+        match tokio::task::spawn_blocking(move || partition::inspect(&path)).await? {
+            Ok(resp) => {
+                ctx.respond2(true, AgentResponsePayload::DiskInspectResponse(resp))
+                    .await
+            }
+            Err(err) => {
+                warn!("Failed to inspect '{}': {}", self.path, err);
+                ctx.respond2(
+                    false,
+                    AgentResponsePayload::DiskInspectResponse(DiskInspectResponse {
+                        error: Some(err.to_string()),
+                        ..Default::default()
+                    }),
+                )
+                .await
+            }
+        }
+        Ok(())
+    }
+}
+
+struct PartitionTask {
+    req: PartitionRequest,
+}
+
+impl PartitionTask {
+    async fn handle(self, ctx: Context) -> Result<()> {
+        let req = self.req.clone();
+        match tokio::task::spawn_blocking(move || partition::partition(&req)).await? {
+            Ok(resp) => {
+                ctx.respond2(true, AgentResponsePayload::PartitionResponse(resp))
+                    .await
+            }
+            Err(err) => {
+                warn!("Failed to partition {:?}: {}", self.req.disk, err);
+                ctx.respond2(
+                    false,
+                    AgentResponsePayload::PartitionResponse(PartitionResponse {
+                        error: Some(err.to_string()),
+                        ..Default::default()
+                    }),
+                )
+                .await
+            }
+        }
+        Ok(())
+    }
+}
+
+struct TelemetryTask {
+    req: TelemetryRequest,
+}
+
+impl TelemetryTask {
+    async fn handle(self, ctx: Context) -> Result<()> {
+        telemetry::configure(self.req.interval.map(Duration::from_secs));
         ctx.respond2(
             true,
-            AgentResponsePayload::FileOperationResponse(FileOperationResponse { success: true }),
+            AgentResponsePayload::TelemetryResponse(TelemetryResponse {
+                interval: telemetry::interval().map(|i| i.as_secs()),
+            }),
         )
         .await;
         Ok(())
     }
+}
 
-    async fn handle_upload(self, ctx: Context) -> Result<()> {
-        if let Err(err) = upload_file(&self.url, &self.path).await {
-            warn!(
-                "Failed to upload file from '{}' to '{}': {}",
-                self.path, self.url, err
-            );
+struct InventoryTask {}
+
+impl InventoryTask {
+    async fn handle(self, ctx: Context) -> Result<()> {
+        let resp = tokio::task::spawn_blocking(inventory::collect).await?;
+        ctx.respond2(true, AgentResponsePayload::InventoryResponse(resp))
+            .await;
+        Ok(())
+    }
+}
+
+struct PersonalizeTask {
+    req: PersonalizeRequest,
+}
+
+impl PersonalizeTask {
+    async fn handle(self, ctx: Context) -> Result<()> {
+        let req = self.req.clone();
+        match tokio::task::spawn_blocking(move || personalize::personalize(&req)).await? {
+            Ok(resp) => {
+                ctx.respond2(true, AgentResponsePayload::PersonalizeResponse(resp))
+                    .await
+            }
+            Err(err) => {
+                warn!("Failed to personalize the target: {}", err);
+                ctx.respond2(
+                    false,
+                    AgentResponsePayload::PersonalizeResponse(PersonalizeResponse {
+                        error: Some(err.to_string()),
+                        ..Default::default()
+                    }),
+                )
+                .await
+            }
+        }
+        Ok(())
+    }
+}
+
+struct FilesystemTask {
+    req: FilesystemRequest,
+}
+
+impl FilesystemTask {
+    async fn run(&self, ctx: &Context) -> Result<FilesystemResponse> {
+        let mut resp = FilesystemResponse::default();
+        match &self.req {
+            FilesystemRequest::Create {
+                device,
+                kind,
+                label,
+                uuid,
+                args,
+            } => {
+                let info = tokio::select! {
+                    info = filesystem::create(device, *kind, label.as_deref(), uuid.as_deref(), args) => info?,
+                    _ = ctx.cancelled() => anyhow::bail!("Creating filesystem cancelled"),
+                };
+                resp.filesystem = Some(info);
+            }
+            FilesystemRequest::Mount { mounts } => {
+                mounts::global().mount_all(mounts, ctx.token())?
+            }
+            FilesystemRequest::Unmount { mount_point, lazy } => {
+                mounts::global().unmount(mount_point.as_deref(), *lazy)?
+            }
+            FilesystemRequest::WriteFstab => resp.fstab = Some(mounts::global().write_fstab()?),
+            FilesystemRequest::List => {}
+        }
+        Ok(resp)
+    }
+
+    async fn handle(self, ctx: Context) -> Result<()> {
+        let (ok, mut resp) = match self.run(&ctx).await {
+            Ok(resp) => (true, resp),
+            Err(err) => {
+                warn!("Failed to handle filesystem request: {}", err);
+                let resp = FilesystemResponse {
+                    error: Some(err.to_string()),
+                    ..Default::default()
+                };
+                (false, resp)
+            }
+        };
+        resp.mounts = mounts::global().list();
+        ctx.respond2(ok, AgentResponsePayload::FilesystemResponse(resp))
+            .await;
+        Ok(())
+    }
+}
+
+struct ImageWriteTask {
+    req: ImageWriteRequest,
+}
+
+impl ImageWriteTask {
+    async fn handle(self, ctx: Context) -> Result<()> {
+        match image::write_image(&ctx, &self.req).await {
+            Ok(resp) => {
+                ctx.respond2(true, AgentResponsePayload::ImageWriteResponse(resp))
+                    .await
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to write image '{}' to '{}': {}",
+                    self.req.url, self.req.target, err
+                );
+                ctx.respond2(
+                    false,
+                    AgentResponsePayload::ImageWriteResponse(ImageWriteResponse {
+                        done: true,
+                        error: Some(err.to_string()),
+                        ..Default::default()
+                    }),
+                )
+                .await
+            }
+        }
+        Ok(())
+    }
+}
+
+struct WipeTask {
+    req: WipeRequest,
+}
+
+impl WipeTask {
+    async fn handle(self, ctx: Context) -> Result<()> {
+        let (ok, resp) = match wipe::wipe(&ctx, &self.req).await {
+            Ok(resp) => (resp.error.is_none(), resp),
+            Err(err) => {
+                warn!("Failed to wipe '{}': {}", self.req.target, err);
+                let resp = WipeResponse {
+                    done: true,
+                    error: Some(err.to_string()),
+                    ..Default::default()
+                };
+                (false, resp)
+            }
+        };
+        ctx.respond2(ok, AgentResponsePayload::WipeResponse(resp))
+            .await;
+        Ok(())
+    }
+}
+
+struct KexecTask {
+    req: KexecRequest,
+}
+
+impl KexecTask {
+    async fn run(&self, ctx: &Context) -> Result<()> {
+        let resp = kexec::load(&self.req).await?;
+        if self.req.load_only {
+            ctx.respond2(true, AgentResponsePayload::KexecResponse(resp))
+                .await;
+            return Ok(());
+        }
+        // Acknowledge first, the connection is gone once the new kernel runs
+        power::plan(ctx.id, PowerAction::Kexec)?;
+        let ack = KexecResponse {
+            executing: true,
+            ..resp
+        };
+        ctx.respond2(true, AgentResponsePayload::KexecResponse(ack))
+            .await;
+        ctx.close().await;
+        power::execute(PowerAction::Kexec, Duration::ZERO).await
+    }
+
+    async fn handle(self, ctx: Context) -> Result<()> {
+        if let Err(err) = self.run(&ctx).await {
+            warn!("Failed to kexec into '{}': {}", self.req.kernel, err);
+            ctx.respond2(
+                false,
+                AgentResponsePayload::KexecResponse(KexecResponse {
+                    error: Some(err.to_string()),
+                    ..Default::default()
+                }),
+            )
+            .await;
+        }
+        Ok(())
+    }
+}
+
+struct PowerTask {
+    req: PowerRequest,
+}
+
+impl PowerTask {
+    async fn run(&self, ctx: &Context) -> Result<()> {
+        if self.req.action == PowerAction::Kexec {
+            anyhow::bail!("Kexec needs a KexecRequest");
+        }
+        let delay = self.req.delay.unwrap_or(0);
+        power::plan(ctx.id, self.req.action)?;
+        ctx.respond2(
+            true,
+            AgentResponsePayload::PowerResponse(PowerResponse {
+                action: Some(self.req.action),
+                delay,
+                error: None,
+            }),
+        )
+        .await;
+        ctx.close().await;
+        power::execute(self.req.action, Duration::from_secs(delay)).await
+    }
+
+    async fn handle(self, ctx: Context) -> Result<()> {
+        if let Err(err) = self.run(&ctx).await {
+            warn!("Failed to carry out {:?}: {}", self.req.action, err);
             ctx.respond2(
                 false,
-                AgentResponsePayload::FileOperationResponse(FileOperationResponse {
-                    success: false,
+                AgentResponsePayload::PowerResponse(PowerResponse {
+                    action: Some(self.req.action),
+                    error: Some(err.to_string()),
+                    ..Default::default()
                 }),
             )
             .await;
         }
+        Ok(())
+    }
+}
+
+struct CancelTask {
+    id: u64,
+}
+
+impl CancelTask {
+    async fn handle(self, ctx: Context) -> Result<()> {
+        let found = ctx.cancel(self.id);
+        ctx.respond2(
+            true,
+            AgentResponsePayload::CancelResponse(CancelResponse { found }),
+        )
+        .await;
+        Ok(())
+    }
+}
+
+struct RateLimitTask {
+    bytes_per_second: Option<u64>,
+}
+
+impl RateLimitTask {
+    async fn handle(self, ctx: Context) -> Result<()> {
+        throttle::global().set_rate(self.bytes_per_second);
         ctx.respond2(
             true,
-            AgentResponsePayload::FileOperationResponse(FileOperationResponse { success: true }),
+            AgentResponsePayload::RateLimitResponse(RateLimitResponse {
+                bytes_per_second: throttle::global().rate(),
+            }),
         )
         .await;
         Ok(())
@@ -60,11 +679,29 @@ impl FileDownloadUploadTask {
 
 struct ExecuteTask {
     cmd: String,
+    target_root: bool,
+    timeout: Option<u64>,
 }
 
 impl ExecuteTask {
+    async fn run(&self, ctx: &Context) -> Result<(i32, String, String)> {
+        let run = async {
+            if self.target_root {
+                chroot::execute_shell(&self.cmd, mounts::global().root()).await
+            } else {
+                execute_shell_with_output(&self.cmd).await
+            }
+        };
+        let timeout = self.timeout.map_or(Duration::MAX, Duration::from_secs);
+        tokio::select! {
+            result = tokio::time::timeout(timeout, run) => result
+                .map_err(|_| anyhow::anyhow!("Command timed out after {}s", timeout.as_secs()))?,
+            _ = ctx.cancelled() => anyhow::bail!("Command cancelled"),
+        }
+    }
+
     async fn handle(self, ctx: Context) -> Result<()> {
-        match execute_shell_with_output(&self.cmd).await {
+        match self.run(&ctx).await {
             Ok((code, stdout, stderr)) => {
                 trace!(
                     "Command '{}' executed with code {}: {} {}",
@@ -100,14 +737,59 @@ enum Task {
     Download(FileDownloadUploadTask),
     Upload(FileDownloadUploadTask),
     Execute(ExecuteTask),
+    RateLimit(RateLimitTask),
+    Cache(CacheTask),
+    Follow(FollowTask),
+    Cancel(CancelTask),
+    Render(RenderTask),
+    ImageWrite(ImageWriteTask),
+    DiskInspect(DiskInspectTask),
+    Partition(PartitionTask),
+    Filesystem(FilesystemTask),
+    Wipe(WipeTask),
+    Kexec(KexecTask),
+    Power(PowerTask),
+    Personalize(PersonalizeTask),
+    Inventory(InventoryTask),
+    Telemetry(TelemetryTask),
 }
 
 impl Task {
+    /// Tasks that write to disks or power the host down. Their safety checks
+    /// read host information, which must then come from the running host.
+    fn changes_host(&self) -> bool {
+        matches!(
+            self,
+            Task::ImageWrite(_)
+                | Task::Partition(_)
+                | Task::Filesystem(_)
+                | Task::Wipe(_)
+                | Task::Kexec(_)
+                | Task::Power(_)
+                | Task::Personalize(_)
+        )
+    }
+
     async fn handle(self, ctx: Context) -> Result<()> {
         match self {
             Task::Download(task) => task.handle_download(ctx).await,
             Task::Upload(task) => task.handle_upload(ctx).await,
             Task::Execute(task) => task.handle(ctx).await,
+            Task::RateLimit(task) => task.handle(ctx).await,
+            Task::Cache(task) => task.handle(ctx).await,
+            Task::Follow(task) => task.handle(ctx).await,
+            Task::Cancel(task) => task.handle(ctx).await,
+            Task::Render(task) => task.handle(ctx).await,
+            Task::ImageWrite(task) => task.handle(ctx).await,
+            Task::DiskInspect(task) => task.handle(ctx).await,
+            Task::Partition(task) => task.handle(ctx).await,
+            Task::Filesystem(task) => task.handle(ctx).await,
+            Task::Wipe(task) => task.handle(ctx).await,
+            Task::Kexec(task) => task.handle(ctx).await,
+            Task::Power(task) => task.handle(ctx).await,
+            Task::Personalize(task) => task.handle(ctx).await,
+            Task::Inventory(task) => task.handle(ctx).await,
+            Task::Telemetry(task) => task.handle(ctx).await,
         }
     }
 }
@@ -120,22 +802,84 @@ impl TryFrom<&ControllerRequest> for Task {
             crate::messages::ControllerRequestPayload::FileOperationRequest(req) => {
                 match req.operation {
                     crate::messages::FileOperation::Download => {
-                        Ok(Task::Download(FileDownloadUploadTask {
-                            url: req.url.clone(),
-                            path: req.path.clone(),
-                        }))
+                        Ok(Task::Download(FileDownloadUploadTask::new(req)))
                     }
                     crate::messages::FileOperation::Upload => {
-                        Ok(Task::Upload(FileDownloadUploadTask {
-                            url: req.url.clone(),
-                            path: req.path.clone(),
-                        }))
+                        Ok(Task::Upload(FileDownloadUploadTask::new(req)))
                     }
                 }
             }
             crate::messages::ControllerRequestPayload::CommandExecutionRequest(req) => {
                 Ok(Task::Execute(ExecuteTask {
                     cmd: req.command.clone(),
+                    target_root: req.target_root,
+                    timeout: req.timeout,
+                }))
+            }
+            crate::messages::ControllerRequestPayload::CacheRequest(req) => {
+                Ok(Task::Cache(match req {
+                    CacheRequest::Prewarm {
+                        url,
+                        digest,
+                        mirrors,
+                    } => CacheTask::Prewarm {
+                        urls: std::iter::once(url).chain(mirrors).cloned().collect(),
+                        digest: digest.clone(),
+                    },
+                    CacheRequest::Purge { digest } => CacheTask::Purge {
+                        digest: digest.clone(),
+                    },
+                    CacheRequest::List => CacheTask::List,
+                }))
+            }
+            crate::messages::ControllerRequestPayload::FileFollowRequest(req) => {
+                Ok(Task::Follow(FollowTask {
+                    path: req.path.clone(),
+                    offset: req.offset,
+                    lines: req.lines,
+                }))
+            }
+            crate::messages::ControllerRequestPayload::CancelRequest(req) => {
+                Ok(Task::Cancel(CancelTask { id: req.id }))
+            }
+            crate::messages::ControllerRequestPayload::RenderFileRequest(req) => {
+                Ok(Task::Render(RenderTask { req: req.clone() }))
+            }
+            crate::messages::ControllerRequestPayload::ImageWriteRequest(req) => {
+                Ok(Task::ImageWrite(ImageWriteTask { req: req.clone() }))
+            }
+            crate::messages::ControllerRequestPayload::DiskInspectRequest(req) => {
+                Ok(Task::DiskInspect(DiskInspectTask {
+                    path: req.path.clone(),
+                }))
+            }
+            crate::messages::ControllerRequestPayload::PartitionRequest(req) => {
+                Ok(Task::Partition(PartitionTask { req: req.clone() }))
+            }
+            crate::messages::ControllerRequestPayload::FilesystemRequest(req) => {
+                Ok(Task::Filesystem(FilesystemTask { req: req.clone() }))
+            }
+            crate::messages::ControllerRequestPayload::WipeRequest(req) => {
+                Ok(Task::Wipe(WipeTask { req: req.clone() }))
+            }
+            crate::messages::ControllerRequestPayload::KexecRequest(req) => {
+                Ok(Task::Kexec(KexecTask { req: req.clone() }))
+            }
+            crate::messages::ControllerRequestPayload::PowerRequest(req) => {
+                Ok(Task::Power(PowerTask { req: req.clone() }))
+            }
+            crate::messages::ControllerRequestPayload::PersonalizeRequest(req) => {
+                Ok(Task::Personalize(PersonalizeTask { req: req.clone() }))
+            }
+            crate::messages::ControllerRequestPayload::InventoryRequest(_) => {
+                Ok(Task::Inventory(InventoryTask {}))
+            }
+            crate::messages::ControllerRequestPayload::TelemetryRequest(req) => {
+                Ok(Task::Telemetry(TelemetryTask { req: req.clone() }))
+            }
+            crate::messages::ControllerRequestPayload::RateLimitRequest(req) => {
+                Ok(Task::RateLimit(RateLimitTask {
+                    bytes_per_second: req.bytes_per_second,
                 }))
             }
         }
@@ -155,6 +899,14 @@ impl TryFrom<&Request> for Task {
 pub(crate) async fn handle_event(ctx: Context) -> Result<()> {
     let task = Task::try_from(&ctx.request);
     match task {
+        Ok(task) if task.changes_host() => {
+            if let Err(err) = hostfs::ensure_live() {
+                warn!("Refusing request[id={}]: {}", ctx.id, err);
+                ctx.respond2(false, AgentResponsePayload::None).await;
+                return Err(err);
+            }
+            task.handle(ctx).await
+        }
         Ok(task) => task.handle(ctx).await,
         Err(_) => {
             warn!("Received an invalid task: {:?}", ctx.request);
@@ -0,0 +1,86 @@
+use std::{fs, path::Path};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::{hostfs, hostid};
+
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct InterfaceFact {
+    pub name: String,
+    pub mac: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct DiskFact {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Facts about the host that templates can refer to as `facts`.
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct HostFacts {
+    /// The host id the agent presents to the controller, in one of the
+    /// formats described on `HostId`.
+    pub machine_id: Option<String>,
+    pub hostname: String,
+    pub interfaces: Vec<InterfaceFact>,
+    pub disks: Vec<DiskFact>,
+}
+
+pub(crate) fn read_trimmed(path: impl AsRef<Path>) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+/// Last component of a symlink's target, such as the driver a device is bound to.
+pub(crate) fn link_name(path: impl AsRef<Path>) -> Option<String> {
+    let target = fs::read_link(path).ok()?;
+    Some(target.file_name()?.to_string_lossy().to_string())
+}
+
+pub(crate) fn sorted_entries(dir: impl AsRef<Path>) -> Vec<String> {
+    let dir = dir.as_ref();
+    let mut names: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(err) => {
+            warn!("Failed to list {}: {}", dir.display(), err);
+            Vec::new()
+        }
+    };
+    names.sort();
+    names
+}
+
+/// Collect host facts from the running system.
+pub(crate) fn collect() -> HostFacts {
+    let net = hostfs::path("/sys/class/net");
+    let interfaces = sorted_entries(&net)
+        .into_iter()
+        .filter(|name| name != "lo")
+        .filter_map(|name| {
+            let mac = read_trimmed(net.join(&name).join("address"))?;
+            Some(InterfaceFact { name, mac })
+        })
+        .collect();
+    let block = hostfs::path("/sys/block");
+    let disks = sorted_entries(&block)
+        .into_iter()
+        .filter(|name| !name.starts_with("loop") && !name.starts_with("ram"))
+        .filter_map(|name| {
+            let sectors: u64 = read_trimmed(block.join(&name).join("size"))?.parse().ok()?;
+            Some(DiskFact {
+                name,
+                size: sectors * 512,
+            })
+        })
+        .collect();
+    HostFacts {
+        machine_id: hostid::current().map(|host_id| host_id.id.clone()),
+        hostname: read_trimmed(hostfs::path("/proc/sys/kernel/hostname")).unwrap_or_default(),
+        interfaces,
+        disks,
+    }
+}
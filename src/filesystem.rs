@@ -0,0 +1,392 @@
+use anyhow::Result;
+use log::info;
+
+use crate::{
+    image::read_full,
+    messages::{FilesystemInfo, FilesystemKind},
+    partition::mounted,
+    utils::execute_command_with_output,
+};
+
+/// Bytes read from the start of a partition to look for signatures.
+pub(crate) const PROBE_SIZE: usize = 64 * 1024;
+
+const EXT_SUPERBLOCK: usize = 1024;
+const EXT_MAGIC: u16 = 0xef53;
+const EXT_COMPAT_HAS_JOURNAL: u32 = 0x4;
+/// Incompatible and read-only features an ext3 driver understands; anything else means ext4.
+const EXT3_INCOMPAT: u32 = 0x2 | 0x4 | 0x10;
+const EXT3_RO_COMPAT: u32 = 0x1 | 0x2 | 0x4;
+const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+const SWAP_PAGE_SIZE: usize = 4096;
+
+fn u16_at(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(buf[pos..pos + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+/// A NUL or space padded string, `None` when empty.
+fn text(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    let text = String::from_utf8_lossy(&bytes[..end]).trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+/// A UUID stored as 16 bytes in big-endian order, `None` when all zero.
+fn uuid(bytes: &[u8]) -> Option<String> {
+    if bytes.iter().all(|b| *b == 0) {
+        return None;
+    }
+    let hex = hex::encode(bytes);
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+
+fn ext(buf: &[u8]) -> Option<FilesystemInfo> {
+    let sb = buf.get(EXT_SUPERBLOCK..EXT_SUPERBLOCK + 1024)?;
+    if u16_at(sb, 56) != EXT_MAGIC {
+        return None;
+    }
+    let (compat, incompat, ro_compat) = (u32_at(sb, 92), u32_at(sb, 96), u32_at(sb, 100));
+    let kind = if incompat & !EXT3_INCOMPAT != 0 || ro_compat & !EXT3_RO_COMPAT != 0 {
+        FilesystemKind::Ext4
+    } else if compat & EXT_COMPAT_HAS_JOURNAL != 0 {
+        FilesystemKind::Ext3
+    } else {
+        FilesystemKind::Ext2
+    };
+    Some(FilesystemInfo {
+        kind,
+        uuid: uuid(&sb[104..120]),
+        label: text(&sb[120..136]),
+    })
+}
+
+fn xfs(buf: &[u8]) -> Option<FilesystemInfo> {
+    if !buf.starts_with(b"XFSB") || buf.len() < 120 {
+        return None;
+    }
+    Some(FilesystemInfo {
+        kind: FilesystemKind::Xfs,
+        uuid: uuid(&buf[32..48]),
+        label: text(&buf[108..120]),
+    })
+}
+
+fn vfat(buf: &[u8]) -> Option<FilesystemInfo> {
+    if buf.len() < 512 || buf[510..512] != [0x55, 0xaa] {
+        return None;
+    }
+    let sector_size = u16_at(buf, 11);
+    if !sector_size.is_power_of_two() || sector_size < 512 {
+        return None;
+    }
+    // FAT32 keeps the volume id and label further in than FAT12/16
+    let (id, label) = if &buf[82..87] == b"FAT32" {
+        (67, 71)
+    } else if &buf[54..57] == b"FAT" {
+        (39, 43)
+    } else {
+        return None;
+    };
+    let id = u32_at(buf, id);
+    Some(FilesystemInfo {
+        kind: FilesystemKind::Vfat,
+        uuid: (id != 0).then(|| format!("{:04X}-{:04X}", id >> 16, id & 0xffff)),
+        label: text(&buf[label..label + 11]).filter(|l| l != "NO NAME"),
+    })
+}
+
+fn luks(buf: &[u8]) -> Option<FilesystemInfo> {
+    if !buf.starts_with(LUKS_MAGIC) || buf.len() < 208 {
+        return None;
+    }
+    let version = u16::from_be_bytes([buf[6], buf[7]]);
+    Some(FilesystemInfo {
+        kind: FilesystemKind::Luks,
+        uuid: text(&buf[168..208]),
+        // Only LUKS2 has a label
+        label: if version == 2 {
+            text(&buf[24..72])
+        } else {
+            None
+        },
+    })
+}
+
+fn lvm(buf: &[u8]) -> Option<FilesystemInfo> {
+    // The label may sit in any of the first four sectors
+    for sector in 0..4 {
+        let label = buf.get(sector * 512..sector * 512 + 512)?;
+        if !label.starts_with(b"LABELONE") || &label[24..32] != b"LVM2 001" {
+            continue;
+        }
+        let header = u32_at(label, 20) as usize;
+        let id = label.get(header..header + 32)?;
+        // Split the raw bytes first, a lossy conversion would shift the offsets
+        let parts = [0..6, 6..10, 10..14, 14..18, 18..22, 22..26, 26..32];
+        let uuid = parts
+            .map(|r| String::from_utf8_lossy(&id[r]).into_owned())
+            .join("-");
+        return Some(FilesystemInfo {
+            kind: FilesystemKind::LvmPv,
+            uuid: Some(uuid),
+            label: None,
+        });
+    }
+    None
+}
+
+fn swap(buf: &[u8]) -> Option<FilesystemInfo> {
+    let magic = buf.get(SWAP_PAGE_SIZE - 10..SWAP_PAGE_SIZE)?;
+    if magic != b"SWAPSPACE2" && magic != b"SWAP-SPACE" {
+        return None;
+    }
+    Some(FilesystemInfo {
+        kind: FilesystemKind::Swap,
+        uuid: uuid(&buf[1036..1052]),
+        label: text(&buf[1052..1068]),
+    })
+}
+
+/// Identify the filesystem or volume signature at the start of `buf`, which
+/// should hold the first [`PROBE_SIZE`] bytes of a partition or disk.
+pub(crate) fn probe(buf: &[u8]) -> Option<FilesystemInfo> {
+    luks(buf)
+        .or_else(|| lvm(buf))
+        .or_else(|| xfs(buf))
+        .or_else(|| ext(buf))
+        .or_else(|| swap(buf))
+        .or_else(|| vfat(buf))
+}
+
+/// Read the start of a device and identify what is on it.
+pub(crate) fn probe_device(device: &str) -> Result<Option<FilesystemInfo>> {
+    let mut file = std::fs::File::open(device)
+        .map_err(|err| anyhow::anyhow!("Failed to open {}: {}", device, err))?;
+    let mut buf = vec![0u8; PROBE_SIZE];
+    let len = read_full(&mut file, &mut buf)?;
+    Ok(probe(&buf[..len]))
+}
+
+/// Type name used by `mount` and fstab, for kinds that can be mounted or swapped on.
+pub(crate) fn fstype(kind: FilesystemKind) -> Option<&'static str> {
+    match kind {
+        FilesystemKind::Ext2 => Some("ext2"),
+        FilesystemKind::Ext3 => Some("ext3"),
+        FilesystemKind::Ext4 => Some("ext4"),
+        FilesystemKind::Xfs => Some("xfs"),
+        FilesystemKind::Vfat => Some("vfat"),
+        FilesystemKind::Swap => Some("swap"),
+        FilesystemKind::Luks | FilesystemKind::LvmPv => None,
+    }
+}
+
+fn mkfs_command(
+    kind: FilesystemKind,
+    label: Option<&str>,
+    uuid: Option<&str>,
+) -> Result<(&'static str, Vec<String>)> {
+    // Tool, flag to overwrite existing signatures and flag for the label
+    let (cmd, force, label_flag) = match kind {
+        FilesystemKind::Ext2 => ("mkfs.ext2", Some("-F"), "-L"),
+        FilesystemKind::Ext3 => ("mkfs.ext3", Some("-F"), "-L"),
+        FilesystemKind::Ext4 => ("mkfs.ext4", Some("-F"), "-L"),
+        FilesystemKind::Xfs => ("mkfs.xfs", Some("-f"), "-L"),
+        FilesystemKind::Vfat => ("mkfs.vfat", None, "-n"),
+        FilesystemKind::Swap => ("mkswap", Some("-f"), "-L"),
+        FilesystemKind::Luks | FilesystemKind::LvmPv => {
+            anyhow::bail!("Creating {:?} is not supported", kind)
+        }
+    };
+    let mut args: Vec<String> = force.into_iter().map(String::from).collect();
+    if let Some(label) = label {
+        args.extend([label_flag.to_string(), label.to_string()]);
+    }
+    if let Some(uuid) = uuid {
+        match kind {
+            FilesystemKind::Xfs => args.extend(["-m".to_string(), format!("uuid={}", uuid)]),
+            FilesystemKind::Vfat => args.extend(["-i".to_string(), uuid.replace('-', "")]),
+            _ => args.extend(["-U".to_string(), uuid.to_string()]),
+        }
+    }
+    Ok((cmd, args))
+}
+
+/// Create a filesystem on `device` and report what ended up on it.
+pub(crate) async fn create(
+    device: &str,
+    kind: FilesystemKind,
+    label: Option<&str>,
+    uuid: Option<&str>,
+    extra: &[String],
+) -> Result<FilesystemInfo> {
+    if mounted(device) {
+        anyhow::bail!(
+            "{} is mounted, used as swap or held by another device",
+            device
+        );
+    }
+    let (cmd, mut args) = mkfs_command(kind, label, uuid)?;
+    args.extend(extra.iter().cloned());
+    args.push(device.to_string());
+    info!("Creating {:?} filesystem on {}", kind, device);
+    let (code, _, stderr) = execute_command_with_output(&cmd.to_string(), args).await?;
+    if code != 0 {
+        anyhow::bail!("{} exited with {}: {}", cmd, code, stderr.trim());
+    }
+    let device = device.to_string();
+    tokio::task::spawn_blocking(move || probe_device(&device))
+        .await??
+        .ok_or_else(|| anyhow::anyhow!("No filesystem found after {}", cmd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first 4 KiB of images made with `mkfs.ext4 -L rootfs` and `mkswap -L swp`.
+    const EXT4: &[u8] = include_bytes!("../tests/fixtures/signatures/ext4.bin");
+    const SWAP: &[u8] = include_bytes!("../tests/fixtures/signatures/swap.bin");
+
+    fn probe_padded(head: &[u8]) -> Option<FilesystemInfo> {
+        let mut buf = head.to_vec();
+        buf.resize(PROBE_SIZE, 0);
+        probe(&buf)
+    }
+
+    #[test]
+    fn ext4_from_mkfs() {
+        let info = probe_padded(EXT4).unwrap();
+        assert_eq!(info.kind, FilesystemKind::Ext4);
+        assert_eq!(
+            info.uuid.as_deref(),
+            Some("0a1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d")
+        );
+        assert_eq!(info.label.as_deref(), Some("rootfs"));
+    }
+
+    #[test]
+    fn ext_feature_levels() {
+        let mut buf = EXT4.to_vec();
+        // Clear every feature the ext4 image has, leaving plain ext2
+        buf[1024 + 92..1024 + 104].fill(0);
+        assert_eq!(probe_padded(&buf).unwrap().kind, FilesystemKind::Ext2);
+        buf[1024 + 92] = EXT_COMPAT_HAS_JOURNAL as u8;
+        assert_eq!(probe_padded(&buf).unwrap().kind, FilesystemKind::Ext3);
+    }
+
+    #[test]
+    fn swap_from_mkswap() {
+        let info = probe_padded(SWAP).unwrap();
+        assert_eq!(info.kind, FilesystemKind::Swap);
+        assert_eq!(
+            info.uuid.as_deref(),
+            Some("11111111-2222-4333-8444-555555555555")
+        );
+        assert_eq!(info.label.as_deref(), Some("swp"));
+    }
+
+    #[test]
+    fn xfs_signature() {
+        let mut buf = vec![0u8; PROBE_SIZE];
+        buf[..4].copy_from_slice(b"XFSB");
+        buf[32..48].copy_from_slice(&[0xab; 16]);
+        buf[108..112].copy_from_slice(b"data");
+        let info = probe(&buf).unwrap();
+        assert_eq!(info.kind, FilesystemKind::Xfs);
+        assert_eq!(
+            info.uuid.as_deref(),
+            Some("abababab-abab-abab-abab-abababababab")
+        );
+        assert_eq!(info.label.as_deref(), Some("data"));
+    }
+
+    #[test]
+    fn vfat_signatures() {
+        let mut buf = vec![0u8; PROBE_SIZE];
+        buf[11..13].copy_from_slice(&512u16.to_le_bytes());
+        buf[82..87].copy_from_slice(b"FAT32");
+        buf[67..71].copy_from_slice(&0x1234abcdu32.to_le_bytes());
+        buf[71..82].copy_from_slice(b"EFI        ");
+        buf[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let info = probe(&buf).unwrap();
+        assert_eq!(info.kind, FilesystemKind::Vfat);
+        assert_eq!(info.uuid.as_deref(), Some("1234-ABCD"));
+        assert_eq!(info.label.as_deref(), Some("EFI"));
+
+        // FAT16 keeps them earlier, and "NO NAME" means no label
+        let mut buf = vec![0u8; PROBE_SIZE];
+        buf[11..13].copy_from_slice(&512u16.to_le_bytes());
+        buf[54..59].copy_from_slice(b"FAT16");
+        buf[43..54].copy_from_slice(b"NO NAME    ");
+        buf[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let info = probe(&buf).unwrap();
+        assert_eq!(info.kind, FilesystemKind::Vfat);
+        assert!(info.uuid.is_none());
+        assert!(info.label.is_none());
+    }
+
+    #[test]
+    fn luks_signature() {
+        let mut buf = vec![0u8; PROBE_SIZE];
+        buf[..6].copy_from_slice(LUKS_MAGIC);
+        buf[6..8].copy_from_slice(&2u16.to_be_bytes());
+        buf[24..29].copy_from_slice(b"vault");
+        let uuid = b"0f1e2d3c-4b5a-4968-8776-655443322110";
+        buf[168..168 + uuid.len()].copy_from_slice(uuid);
+        let info = probe(&buf).unwrap();
+        assert_eq!(info.kind, FilesystemKind::Luks);
+        assert_eq!(info.uuid.as_deref(), Some(&*String::from_utf8_lossy(uuid)));
+        assert_eq!(info.label.as_deref(), Some("vault"));
+    }
+
+    fn lvm_label(sector: usize, id: &[u8; 32]) -> Vec<u8> {
+        let mut buf = vec![0u8; PROBE_SIZE];
+        let label = &mut buf[sector * 512..sector * 512 + 512];
+        label[..8].copy_from_slice(b"LABELONE");
+        label[20..24].copy_from_slice(&32u32.to_le_bytes());
+        label[24..32].copy_from_slice(b"LVM2 001");
+        label[32..64].copy_from_slice(id);
+        buf
+    }
+
+    #[test]
+    fn lvm_signature() {
+        let buf = lvm_label(1, b"AbCdEf123456GhIjKlMnOpQrStUvWxYz");
+        let info = probe(&buf).unwrap();
+        assert_eq!(info.kind, FilesystemKind::LvmPv);
+        assert_eq!(
+            info.uuid.as_deref(),
+            Some("AbCdEf-1234-56Gh-IjKl-MnOp-QrSt-UvWxYz")
+        );
+    }
+
+    #[test]
+    fn lvm_signature_with_invalid_utf8() {
+        let mut id = *b"AbCdEf123456GhIjKlMnOpQrStUvWxYz";
+        // A byte that is not UTF-8 at the end of a group, and one inside a later one
+        id[5] = 0xff;
+        id[12] = 0xc3;
+        let info = probe(&lvm_label(0, &id)).unwrap();
+        assert_eq!(
+            info.uuid.as_deref(),
+            Some("AbCdE\u{fffd}-1234-56\u{fffd}h-IjKl-MnOp-QrSt-UvWxYz")
+        );
+    }
+
+    #[test]
+    fn nothing_on_blank_disk() {
+        assert!(probe(&vec![0u8; PROBE_SIZE]).is_none());
+        assert!(probe(&[]).is_none());
+    }
+}
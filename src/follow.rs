@@ -0,0 +1,225 @@
+use std::{io::SeekFrom, os::unix::fs::MetadataExt, path::Path, time::Duration};
+
+use anyhow::Result;
+use futures_util::StreamExt;
+use inotify::{Inotify, WatchMask};
+use log::{debug, info};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use crate::{
+    messages::{AgentResponsePayload, FileFollowResponse},
+    net::Context,
+};
+
+/// Largest piece of content sent in one message.
+const MAX_CHUNK: usize = 64 * 1024;
+/// Re-check the file this often even without inotify events.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Find the offset at which the last `lines` lines of the file start.
+async fn last_lines_offset(file: &mut File, size: u64, lines: usize) -> Result<u64> {
+    if lines == 0 {
+        return Ok(size);
+    }
+    let mut buf = vec![0u8; 8192];
+    let mut end = size;
+    let mut seen = 0;
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let len = (end - start) as usize;
+        file.seek(SeekFrom::Start(start)).await?;
+        file.read_exact(&mut buf[..len]).await?;
+        for i in (0..len).rev() {
+            let pos = start + i as u64;
+            // A trailing newline terminates the last line rather than starting a new one
+            if buf[i] == b'\n' && pos + 1 != size {
+                seen += 1;
+                if seen == lines {
+                    return Ok(pos + 1);
+                }
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// Length of `data` without an incomplete UTF-8 sequence at its end, which is
+/// left in the file to be sent with the rest of the character.
+fn complete_len(data: &[u8]) -> usize {
+    for start in data.len().saturating_sub(3)..data.len() {
+        if let Err(err) = std::str::from_utf8(&data[start..])
+            && err.valid_up_to() == 0
+            && err.error_len().is_none()
+        {
+            return start;
+        }
+    }
+    data.len()
+}
+
+/// Follows one file on behalf of a request, sending appended content as it arrives.
+struct Follower<'a> {
+    ctx: &'a Context,
+    path: &'a str,
+    file: File,
+    inode: u64,
+    offset: u64,
+}
+
+impl Follower<'_> {
+    async fn send(&self, data: &[u8], truncated: bool, rotated: bool, done: bool) {
+        self.ctx
+            .respond2(
+                true,
+                AgentResponsePayload::FileFollowResponse(FileFollowResponse {
+                    offset: self.offset,
+                    data: String::from_utf8_lossy(data).to_string(),
+                    truncated,
+                    rotated,
+                    done,
+                    error: None,
+                }),
+            )
+            .await;
+    }
+
+    /// Send everything between the current offset and the end of the open file.
+    async fn drain(&mut self, mut truncated: bool, mut rotated: bool) -> Result<()> {
+        let len = self.file.metadata().await?.len();
+        if len < self.offset {
+            info!("{} was truncated, following from the start", self.path);
+            self.offset = 0;
+            truncated = true;
+        }
+        self.file.seek(SeekFrom::Start(self.offset)).await?;
+        let mut buf = vec![0u8; MAX_CHUNK];
+        loop {
+            let n = self.file.read(&mut buf).await?;
+            let len = complete_len(&buf[..n]);
+            if len == 0 {
+                break;
+            }
+            self.send(&buf[..len], truncated, rotated, false).await;
+            self.offset += len as u64;
+            if len < n {
+                self.file.seek(SeekFrom::Start(self.offset)).await?;
+            }
+            truncated = false;
+            rotated = false;
+        }
+        if truncated || rotated {
+            self.send(&[], truncated, rotated, false).await;
+        }
+        Ok(())
+    }
+
+    /// Switch to a new file at the followed path once the old one has been drained.
+    async fn check_rotation(&mut self) -> Result<bool> {
+        let meta = match tokio::fs::metadata(self.path).await {
+            Ok(meta) => meta,
+            // Rotated away and not recreated yet
+            Err(_) => return Ok(false),
+        };
+        if meta.ino() == self.inode {
+            return Ok(false);
+        }
+        info!("{} was rotated, following the new file", self.path);
+        self.file = File::open(self.path).await?;
+        self.inode = meta.ino();
+        self.offset = 0;
+        Ok(true)
+    }
+}
+
+/// Stream content appended to a file until the request is cancelled.
+///
+/// Starts at the last `lines` lines, at `offset`, or at the end of the file, and
+/// survives truncation and rotation of the file.
+pub(crate) async fn follow_file(
+    ctx: &Context,
+    path: &str,
+    offset: Option<u64>,
+    lines: Option<usize>,
+) -> Result<()> {
+    info!("Following file {}", path);
+    let mut file = File::open(path).await?;
+    let meta = file.metadata().await?;
+    let start = match (lines, offset) {
+        (Some(lines), _) => last_lines_offset(&mut file, meta.len(), lines).await?,
+        (None, Some(offset)) => offset.min(meta.len()),
+        (None, None) => meta.len(),
+    };
+
+    let inotify = Inotify::init()?;
+    let file_mask = WatchMask::MODIFY | WatchMask::ATTRIB | WatchMask::MOVE_SELF;
+    inotify.watches().add(path, file_mask)?;
+    let parent = Path::new(path)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    inotify
+        .watches()
+        .add(parent, WatchMask::CREATE | WatchMask::MOVED_TO)?;
+    let mut events = inotify.into_event_stream([0u8; 4096])?;
+
+    let mut follower = Follower {
+        ctx,
+        path,
+        file,
+        inode: meta.ino(),
+        offset: start,
+    };
+    follower.drain(false, false).await?;
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = ctx.cancelled() => break,
+            event = events.next() => match event {
+                Some(event) => debug!("inotify event on {}: {:?}", path, event?.mask),
+                None => anyhow::bail!("inotify stream ended"),
+            },
+            _ = poll.tick() => {}
+        }
+        follower.drain(false, false).await?;
+        if follower.check_rotation().await? {
+            events.watches().add(path, file_mask)?;
+            follower.drain(false, true).await?;
+        }
+    }
+    info!("Stopped following file {}", path);
+    follower.send(&[], false, false, true).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incomplete_characters_are_held_back() {
+        let text = "price: 5€".as_bytes();
+        assert_eq!(complete_len(text), text.len());
+        for cut in 1..3 {
+            let data = &text[..text.len() - cut];
+            assert_eq!(complete_len(data), text.len() - 3, "{:?}", data);
+        }
+        let emoji = "🦀".as_bytes();
+        assert_eq!(complete_len(&emoji[..3]), 0);
+        assert_eq!(complete_len(emoji), 4);
+        assert_eq!(complete_len(b""), 0);
+    }
+
+    #[test]
+    fn invalid_bytes_are_not_held_back() {
+        // Stray continuation bytes and bytes that never start a character
+        assert_eq!(complete_len(b"abc\x80"), 4);
+        assert_eq!(complete_len(b"abc\xff"), 4);
+        assert_eq!(complete_len(b"\xe2\x82\xacx\x82\xac"), 6);
+        // Invalid data earlier does not hide an incomplete tail
+        assert_eq!(complete_len(b"\xffab\xe2\x82"), 3);
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::Read,
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+    sync::LazyLock,
+};
+
+use anyhow::Result;
+use log::{debug, info};
+
+/// Root that `/sys`, `/proc`, `/dev` and `/etc` are read from for host
+/// introspection, from `MXA_HOST_ROOT`. Pointing it at a snapshot lets the
+/// collectors run against another machine's captured files.
+static ROOT: LazyLock<PathBuf> = LazyLock::new(|| {
+    // Tests run the collectors against a tree captured from a small VM
+    if cfg!(test) {
+        return PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/host"));
+    }
+    let root = std::env::var("MXA_HOST_ROOT").unwrap_or_else(|_| "/".to_string());
+    if root != "/" {
+        info!("Reading host information from {}", root);
+    }
+    PathBuf::from(root)
+});
+
+/// Class directories whose device trees are captured by a snapshot.
+const CLASS_DIRS: &[&str] = &[
+    "/sys/block",
+    "/sys/class/net",
+    "/sys/bus/pci/devices",
+    "/sys/class/hwmon",
+];
+/// Directories of symlinks captured as they are.
+const LINK_DIRS: &[&str] = &["/dev/disk/by-id", "/dev/disk/by-path"];
+const FILES: &[&str] = &[
+    "/sys/firmware/dmi/tables/DMI",
+    "/sys/firmware/dmi/tables/smbios_entry_point",
+    "/etc/machine-id",
+    "/proc/net/route",
+    "/proc/net/vlan/config",
+    "/proc/sys/kernel/hostname",
+    "/proc/sys/kernel/random/boot_id",
+    "/proc/self/mounts",
+    "/proc/stat",
+    "/proc/loadavg",
+    "/proc/meminfo",
+    "/proc/diskstats",
+    "/proc/net/dev",
+];
+/// Subdirectories of devices no collector reads.
+const SKIP_DIRS: &[&str] = &["power", "mq", "trace", "msi_irqs", "queues", "statistics"];
+/// Attributes that are slow, have side effects or cannot be read as files.
+const SKIP_FILES: &[&str] = &["vpd", "rom", "config", "reset"];
+/// Levels of subdirectories captured below each device, enough for queue
+/// attributes, holders and partitions.
+const DEVICE_LEVELS: u32 = 2;
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+/// Symlinks followed by [`resolve`] before giving up on a loop.
+const MAX_LINKS: u32 = 40;
+
+/// Whether introspection reads the running host rather than a snapshot.
+pub(crate) fn is_live() -> bool {
+    ROOT.as_path() == Path::new("/")
+}
+
+/// Fail unless introspection reads the running host, so nothing that changes
+/// the host acts on what a snapshot says about it.
+pub(crate) fn ensure_live() -> Result<()> {
+    if !is_live() {
+        anyhow::bail!(
+            "Host information is read from {}, refusing to change this host",
+            ROOT.display()
+        );
+    }
+    Ok(())
+}
+
+/// Where an absolute host path such as `/sys/block` is read from.
+pub(crate) fn path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    ROOT.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Follow the symlink at the end of a host path, such as `/dev/disk/by-id/*`
+/// or `/dev/mapper/*`, to the path it names on the host. The target need not
+/// exist, as a snapshot holds no device nodes.
+pub(crate) fn resolve(host_path: impl AsRef<Path>) -> PathBuf {
+    let mut resolved = host_path.as_ref().to_path_buf();
+    for _ in 0..MAX_LINKS {
+        let Ok(target) = fs::read_link(path(&resolved)) else {
+            break;
+        };
+        let joined = resolved.parent().unwrap_or(Path::new("/")).join(target);
+        let mut normal = PathBuf::from("/");
+        for component in joined.components() {
+            match component {
+                Component::ParentDir => {
+                    normal.pop();
+                }
+                Component::Normal(name) => normal.push(name),
+                _ => {}
+            }
+        }
+        resolved = normal;
+    }
+    resolved
+}
+
+struct Snapshot {
+    dest: PathBuf,
+    /// Directories already captured, with the levels below them.
+    dirs: HashMap<PathBuf, u32>,
+    files: usize,
+}
+
+impl Snapshot {
+    fn target(&self, path: &Path) -> PathBuf {
+        self.dest.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    /// Copy a file, or recreate a symlink with its target unchanged so relative
+    /// sysfs links keep resolving inside the snapshot.
+    fn copy_file(&mut self, path: &Path) -> Result<()> {
+        let meta = fs::symlink_metadata(path)?;
+        let target = self.target(path);
+        fs::create_dir_all(target.parent().unwrap())?;
+        if meta.file_type().is_symlink() {
+            let link = fs::read_link(path)?;
+            if fs::symlink_metadata(&target).is_ok() {
+                fs::remove_file(&target)?;
+            }
+            std::os::unix::fs::symlink(link, &target)?;
+        } else if meta.is_file() && meta.permissions().mode() & 0o444 != 0 {
+            // sysfs reports a nominal size, so read what the kernel hands out
+            let mut content = Vec::new();
+            fs::File::open(path)?
+                .take(MAX_FILE_SIZE)
+                .read_to_end(&mut content)?;
+            fs::write(&target, content)?;
+        } else {
+            return Ok(());
+        }
+        self.files += 1;
+        Ok(())
+    }
+
+    fn copy_dir(&mut self, dir: &Path, levels: u32) -> Result<()> {
+        if self.dirs.get(dir).is_some_and(|done| *done >= levels) {
+            return Ok(());
+        }
+        self.dirs.insert(dir.to_path_buf(), levels);
+        fs::create_dir_all(self.target(dir))?;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                if levels > 0 && !SKIP_DIRS.contains(&name.as_str()) {
+                    self.copy_dir(&path, levels - 1)?;
+                }
+            } else if !SKIP_FILES.contains(&name.as_str()) && !name.starts_with("resource") {
+                // Some attributes fail to read in some states, such as the
+                // carrier of a link that is down
+                if let Err(err) = self.copy_file(&path) {
+                    debug!("Skipping {}: {}", path.display(), err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Capture a class entry, the device directory it points at, and the
+    /// attributes of the devices above it.
+    fn copy_device(&mut self, link: &Path) -> Result<()> {
+        self.copy_file(link)?;
+        let dir = fs::canonicalize(link)?;
+        self.copy_dir(&dir, DEVICE_LEVELS)?;
+        for parent in dir.ancestors().skip(1) {
+            if !parent.starts_with("/sys/devices") {
+                break;
+            }
+            self.copy_dir(parent, 0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy what the collectors read from this host into `dest`, laid out so it
+/// can serve as `MXA_HOST_ROOT`. Returns the number of files captured.
+pub(crate) fn snapshot(dest: &Path) -> Result<usize> {
+    let mut snapshot = Snapshot {
+        dest: dest.to_path_buf(),
+        dirs: HashMap::new(),
+        files: 0,
+    };
+    fs::create_dir_all(dest)?;
+    for file in FILES {
+        if let Err(err) = snapshot.copy_file(Path::new(file)) {
+            debug!("Skipping {}: {}", file, err);
+        }
+    }
+    for dir in LINK_DIRS.iter().chain(CLASS_DIRS) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            debug!("Skipping {}: not readable", dir);
+            continue;
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let result = if LINK_DIRS.contains(dir) {
+                snapshot.copy_file(&path)
+            } else {
+                snapshot.copy_device(&path)
+            };
+            if let Err(err) = result {
+                debug!("Skipping {}: {}", path.display(), err);
+            }
+        }
+    }
+    info!(
+        "Captured {} file(s) into {}",
+        snapshot.files,
+        dest.display()
+    );
+    Ok(snapshot.files)
+}
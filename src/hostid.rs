@@ -0,0 +1,148 @@
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use log::{info, warn};
+use rand::RngCore;
+
+use crate::{
+    facts::{read_trimmed, sorted_entries},
+    messages::HostIdSource,
+    render::write_atomic,
+    smbios,
+    utils::state_dir,
+};
+
+const GENERATED_FILE: &str = "host-id";
+const DEFAULT_SOURCES: &[HostIdSource] = &[
+    HostIdSource::Dmi,
+    HostIdSource::MachineId,
+    HostIdSource::Mac,
+    HostIdSource::Generated,
+];
+
+/// The id the agent presents to the controller, and where it came from. Always
+/// taken from the running host, never from `MXA_HOST_ROOT`, so an agent
+/// pointed at another host's snapshot cannot take over its identity.
+///
+/// The format depends on the source, always in lowercase: a dashed UUID from
+/// DMI or the generated fallback, 32 hex digits from machine-id, a
+/// colon-separated MAC address.
+#[derive(Clone, Debug)]
+pub(crate) struct HostId {
+    pub(crate) id: String,
+    pub(crate) source: HostIdSource,
+}
+
+/// Sources to try in order, from the comma-separated `MXA_HOST_ID_SOURCES`
+/// (`dmi`, `machine-id`, `mac`, `generated`).
+fn sources() -> Result<Vec<HostIdSource>> {
+    let Some(value) = std::env::var("MXA_HOST_ID_SOURCES")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+    else {
+        return Ok(DEFAULT_SOURCES.to_vec());
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| match name.to_lowercase().as_str() {
+            "dmi" => Ok(HostIdSource::Dmi),
+            "machine-id" => Ok(HostIdSource::MachineId),
+            "mac" => Ok(HostIdSource::Mac),
+            "generated" => Ok(HostIdSource::Generated),
+            _ => anyhow::bail!("Unknown host id source '{}'", name),
+        })
+        .collect()
+}
+
+fn dmi() -> Result<String> {
+    smbios::read_live()?
+        .system
+        .and_then(|system| system.uuid)
+        .ok_or_else(|| anyhow::anyhow!("No usable system UUID in the DMI table"))
+}
+
+fn machine_id() -> Result<String> {
+    let id = read_trimmed("/etc/machine-id")
+        .ok_or_else(|| anyhow::anyhow!("Failed to read /etc/machine-id"))?;
+    // systemd writes "uninitialized" until the first boot completes
+    if id.len() != 32 || !id.bytes().all(|b| b.is_ascii_hexdigit()) || id.bytes().all(|b| b == b'0')
+    {
+        anyhow::bail!("/etc/machine-id holds no usable id");
+    }
+    Ok(id.to_lowercase())
+}
+
+/// The interface of the default route, or else the first one backed by a device.
+fn primary_interface() -> Option<String> {
+    let routes = std::fs::read_to_string("/proc/net/route").unwrap_or_default();
+    let default = routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        (fields.get(1) == Some(&"00000000")).then(|| fields[0].to_string())
+    });
+    default.or_else(|| {
+        sorted_entries("/sys/class/net").into_iter().find(|name| {
+            std::fs::exists(format!("/sys/class/net/{}/device", name)).unwrap_or(false)
+        })
+    })
+}
+
+fn mac() -> Result<String> {
+    let name = primary_interface().ok_or_else(|| anyhow::anyhow!("No network interface"))?;
+    // Random and stolen addresses change between boots
+    let dir = format!("/sys/class/net/{}", name);
+    let assign = read_trimmed(format!("{}/addr_assign_type", dir));
+    if assign.is_some_and(|t| t != "0") {
+        anyhow::bail!("{} has no permanent address", name);
+    }
+    let mac = read_trimmed(format!("{}/address", dir))
+        .filter(|mac| mac.len() == 17 && mac != "00:00:00:00:00:00")
+        .ok_or_else(|| anyhow::anyhow!("{} has no usable address", name))?;
+    Ok(mac.to_lowercase())
+}
+
+/// A random UUID kept in the state directory, generated on first use.
+fn generated() -> Result<String> {
+    let path = state_dir().join(GENERATED_FILE);
+    if let Some(id) = read_trimmed(&path).filter(|id| !id.is_empty()) {
+        return Ok(id);
+    }
+    let mut bytes = [0u8; 16];
+    rand::rng().fill_bytes(&mut bytes);
+    // Version 4, variant 1
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let id = smbios::format_uuid(&bytes);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    write_atomic(&path, format!("{}\n", id).as_bytes(), 0o644)?;
+    info!("Generated host id {} in {}", id, path.display());
+    Ok(id)
+}
+
+static RESOLVED: OnceLock<HostId> = OnceLock::new();
+
+/// The host id found by `resolve`, once it has run.
+pub(crate) fn current() -> Option<&'static HostId> {
+    RESOLVED.get()
+}
+
+/// Take the host id from the first source that has a usable one.
+pub(crate) fn resolve() -> Result<HostId> {
+    for source in sources()? {
+        let result = match source {
+            HostIdSource::Dmi => dmi(),
+            HostIdSource::MachineId => machine_id(),
+            HostIdSource::Mac => mac(),
+            HostIdSource::Generated => generated(),
+        };
+        match result {
+            Ok(id) => {
+                info!("Using host id {} from {:?}", id, source);
+                return Ok(RESOLVED.get_or_init(|| HostId { id, source }).clone());
+            }
+            Err(err) => warn!("No host id from {:?}: {}", source, err),
+        }
+    }
+    anyhow::bail!("No host id source gave a usable id")
+}
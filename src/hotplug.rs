@@ -0,0 +1,185 @@
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use log::{info, warn};
+
+use crate::{
+    events, hostfs,
+    messages::{AgentEventPayload, Hotplug},
+};
+
+/// Subsystems of the devices the inventory lists.
+const SUBSYSTEMS: &[&str] = &["block", "net", "pci"];
+/// Multicast group of uevents sent by the kernel; udev sends its own on 2.
+const KERNEL_GROUP: u32 = 1;
+const BUFFER_SIZE: usize = 16 * 1024;
+
+fn open() -> std::io::Result<OwnedFd> {
+    // SAFETY: socket takes no pointers
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor was just opened and nothing else owns it
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    // SAFETY: an all-zero sockaddr_nl is valid
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    addr.nl_groups = KERNEL_GROUP;
+    // SAFETY: addr is a sockaddr_nl of the given size
+    let bound = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            (&addr as *const libc::sockaddr_nl).cast(),
+            std::mem::size_of::<libc::sockaddr_nl>() as u32,
+        )
+    };
+    if bound < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+/// Parse a kernel uevent: `action@devpath`, then NUL separated `KEY=VALUE` pairs.
+fn parse(msg: &[u8]) -> Option<Hotplug> {
+    let mut fields = msg.split(|&b| b == 0).map(String::from_utf8_lossy);
+    let header = fields.next()?;
+    let (action, devpath) = header.split_once('@')?;
+    let (mut subsystem, mut name) = (None, None);
+    for field in fields {
+        match field.split_once('=') {
+            Some(("SUBSYSTEM", value)) => subsystem = Some(value.to_string()),
+            Some(("DEVNAME" | "INTERFACE", value)) => name = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    Some(Hotplug {
+        action: action.to_string(),
+        subsystem: subsystem?,
+        devpath: devpath.to_string(),
+        name,
+    })
+}
+
+fn listen(socket: OwnedFd) {
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        // SAFETY: buf is valid for its length
+        let len = unsafe { libc::recv(socket.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+        if len < 0 {
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => {}
+                Some(libc::ENOBUFS) => warn!("Missed hotplug events, the kernel sent too many"),
+                _ => {
+                    warn!("Stopped watching for hotplug events: {}", err);
+                    return;
+                }
+            }
+            continue;
+        }
+        let Some(hotplug) = parse(&buf[..len as usize]) else {
+            continue;
+        };
+        if SUBSYSTEMS.contains(&hotplug.subsystem.as_str()) {
+            info!(
+                "Hotplug: {} {} {}",
+                hotplug.action, hotplug.subsystem, hotplug.devpath
+            );
+            events::emit(AgentEventPayload::Hotplug(hotplug));
+        }
+    }
+}
+
+/// Watch for devices coming and going on the running host, in a thread of
+/// its own.
+pub(crate) fn watch() {
+    if !hostfs::is_live() {
+        return;
+    }
+    match open() {
+        Ok(socket) => {
+            std::thread::spawn(move || listen(socket));
+        }
+        Err(err) => warn!("Not watching for hotplug events: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uevent(fields: &[&str]) -> Vec<u8> {
+        fields
+            .iter()
+            .flat_map(|f| [f.as_bytes(), b"\0"])
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn block_device() {
+        let msg = uevent(&[
+            "add@/devices/pci0000:00/0000:00:05.0/virtio2/block/vdb",
+            "ACTION=add",
+            "DEVPATH=/devices/pci0000:00/0000:00:05.0/virtio2/block/vdb",
+            "SUBSYSTEM=block",
+            "MAJOR=253",
+            "MINOR=16",
+            "DEVNAME=vdb",
+            "DEVTYPE=disk",
+            "SEQNUM=2451",
+        ]);
+        let hotplug = parse(&msg).unwrap();
+        assert_eq!(hotplug.action, "add");
+        assert_eq!(hotplug.subsystem, "block");
+        assert_eq!(
+            hotplug.devpath,
+            "/devices/pci0000:00/0000:00:05.0/virtio2/block/vdb"
+        );
+        assert_eq!(hotplug.name.as_deref(), Some("vdb"));
+    }
+
+    #[test]
+    fn network_interface() {
+        let msg = uevent(&[
+            "remove@/devices/virtual/net/veth0",
+            "ACTION=remove",
+            "SUBSYSTEM=net",
+            "INTERFACE=veth0",
+            "IFINDEX=12",
+        ]);
+        let hotplug = parse(&msg).unwrap();
+        assert_eq!(
+            (hotplug.action.as_str(), hotplug.subsystem.as_str()),
+            ("remove", "net")
+        );
+        assert_eq!(hotplug.name.as_deref(), Some("veth0"));
+    }
+
+    #[test]
+    fn incomplete_messages() {
+        // No subsystem
+        assert!(
+            parse(&uevent(&[
+                "change@/devices/system/cpu/cpu0",
+                "ACTION=change"
+            ]))
+            .is_none()
+        );
+        // udev's binary messages and other noise have no action@devpath header
+        assert!(parse(b"libudev\0\xfe\xed\xca\xfe").is_none());
+        assert!(parse(b"").is_none());
+        let pci = parse(&uevent(&[
+            "bind@/devices/pci0000:00/0000:00:04.0",
+            "SUBSYSTEM=pci",
+        ]));
+        assert!(pci.unwrap().name.is_none());
+    }
+}
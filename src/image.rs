@@ -0,0 +1,604 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileTypeExt, OpenOptionsExt},
+    },
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use futures_util::TryStreamExt;
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+use crate::{
+    messages::{
+        AgentResponsePayload, Compression, ImageFormat, ImageWriteRequest, ImageWriteResponse,
+    },
+    net::Context,
+    qcow2, sparse,
+    throttle::Throttle,
+    utils::{http_get, verify_digest},
+};
+
+/// Alignment of buffers, offsets and lengths for `O_DIRECT` writes.
+const ALIGN: usize = 4096;
+pub(crate) const DEFAULT_BLOCK_SIZE: u32 = 4 * 1024 * 1024;
+/// Largest block size a request may ask for, since each one is a buffer.
+const MAX_BLOCK_SIZE: u32 = 64 * 1024 * 1024;
+pub(crate) const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// `_IO(0x12, 119)`, not exported by libc.
+const BLKDISCARD: libc::c_ulong = 0x1277;
+
+/// Guess the compression of a stream from its magic bytes.
+fn detect_compression<R: BufRead>(reader: &mut R) -> Result<Compression> {
+    let head = reader.fill_buf()?;
+    Ok(if head.starts_with(&[0x1f, 0x8b]) {
+        Compression::Gzip
+    } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Compression::Xz
+    } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    })
+}
+
+/// Guess the format of a decompressed image from its magic bytes.
+fn detect_format<R: BufRead>(reader: &mut R) -> Result<ImageFormat> {
+    let head = reader.fill_buf()?;
+    Ok(if head.starts_with(qcow2::MAGIC) {
+        ImageFormat::Qcow2
+    } else if head.starts_with(&sparse::MAGIC.to_le_bytes()) {
+        ImageFormat::AndroidSparse
+    } else {
+        ImageFormat::Raw
+    })
+}
+
+pub(crate) fn decompress<'a, R: BufRead + 'a>(
+    compression: Compression,
+    reader: R,
+) -> Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Compression::Xz => Box::new(liblzma::bufread::XzDecoder::new_multi_decoder(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+    })
+}
+
+/// A heap buffer whose start is aligned for direct I/O.
+pub(crate) struct AlignedBuf {
+    buf: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    pub(crate) fn new(len: usize) -> Self {
+        let buf = vec![0u8; len + ALIGN];
+        let start = (ALIGN - buf.as_ptr() as usize % ALIGN) % ALIGN;
+        AlignedBuf { buf, start, len }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..self.start + self.len]
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        &self.buf[self.start..self.start + self.len]
+    }
+}
+
+/// Destination of an image: a block device or a regular file.
+pub(crate) struct Target {
+    pub file: File,
+    pub path: String,
+    pub direct: bool,
+    /// Size of a block device; regular files grow as needed.
+    pub capacity: Option<u64>,
+}
+
+impl Target {
+    /// Open an existing target, or with `create` a regular file that may not
+    /// exist yet.
+    pub(crate) fn open(path: &str, direct: bool, create: bool) -> Result<Self> {
+        let mut options = OpenOptions::new();
+        options
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false);
+        let (mut file, direct) = if direct {
+            match options.clone().custom_flags(libc::O_DIRECT).open(path) {
+                Ok(file) => (file, true),
+                Err(err) => {
+                    warn!(
+                        "Opening {} with O_DIRECT failed, using buffered I/O: {}",
+                        path, err
+                    );
+                    (options.open(path)?, false)
+                }
+            }
+        } else {
+            (options.open(path)?, false)
+        };
+        let capacity = if file.metadata()?.file_type().is_block_device() {
+            Some(file.seek(SeekFrom::End(0))?)
+        } else {
+            None
+        };
+        Ok(Target {
+            file,
+            path: path.to_string(),
+            direct,
+            capacity,
+        })
+    }
+
+    /// Stop using `O_DIRECT`, for unaligned writes or when the filesystem rejects it.
+    pub(crate) fn disable_direct(&mut self) -> Result<()> {
+        if !self.direct {
+            return Ok(());
+        }
+        let fd = self.file.as_raw_fd();
+        // SAFETY: fcntl on a file descriptor owned by `self.file`
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        self.direct = false;
+        Ok(())
+    }
+
+    fn check_capacity(&self, end: u64) -> Result<()> {
+        if let Some(capacity) = self.capacity
+            && end > capacity
+        {
+            anyhow::bail!(
+                "Image does not fit on {}: {} bytes needed, {} available",
+                self.path,
+                end,
+                capacity
+            );
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `offset`, directly when both are aligned.
+    pub(crate) fn write_at(&mut self, data: &[u8], offset: u64) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+        self.check_capacity(offset + data.len() as u64)?;
+        let aligned = data.len().is_multiple_of(ALIGN)
+            && offset.is_multiple_of(ALIGN as u64)
+            && (data.as_ptr() as usize).is_multiple_of(ALIGN);
+        if self.direct && !aligned {
+            self.disable_direct()?;
+        }
+        match self.file.write_all_at(data, offset) {
+            Err(err) if self.direct && err.raw_os_error() == Some(libc::EINVAL) => {
+                warn!(
+                    "O_DIRECT write rejected on {}, using buffered I/O",
+                    self.path
+                );
+                self.disable_direct()?;
+                self.file.write_all_at(data, offset)?;
+            }
+            result => result?,
+        }
+        Ok(())
+    }
+
+    /// Release a range: punch a hole in a regular file, discard blocks on a device.
+    pub(crate) fn discard(&mut self, offset: u64, len: u64) -> Result<()> {
+        self.check_capacity(offset + len)?;
+        let fd = self.file.as_raw_fd();
+        if self.capacity.is_some() {
+            let range = [offset, len];
+            // SAFETY: BLKDISCARD reads two u64 from the pointer
+            if unsafe { libc::ioctl(fd, BLKDISCARD as _, range.as_ptr()) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        } else {
+            let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+            // SAFETY: fallocate on a file descriptor owned by `self.file`
+            if unsafe { libc::fallocate(fd, mode, offset as i64, len as i64) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush to stable storage and trim a regular file to the image size.
+    pub(crate) fn finish(&mut self, size: u64) -> Result<()> {
+        if self.capacity.is_none() {
+            self.file.set_len(size)?;
+        }
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Read until `buf` is full or the stream ends, returning the number of bytes read.
+pub(crate) fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+/// Hashes and counts the bytes passing through.
+struct HashReader<R> {
+    inner: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// Receives an expanded image as data at offsets plus holes, and writes it to a target.
+///
+/// Contiguous writes are gathered into aligned blocks. Holes are punched in regular
+/// files; on block devices they are left untouched unless discarding is asked for.
+pub(crate) struct Sink<'a> {
+    target: &'a mut Target,
+    buf: AlignedBuf,
+    start: u64,
+    len: usize,
+    hasher: Sha256,
+    extents: Vec<(u64, u64)>,
+    holes: Vec<(u64, u64)>,
+    discard: bool,
+    written: &'a AtomicU64,
+    cancel: &'a AtomicBool,
+}
+
+impl<'a> Sink<'a> {
+    fn new(
+        target: &'a mut Target,
+        block_size: usize,
+        discard: bool,
+        written: &'a AtomicU64,
+        cancel: &'a AtomicBool,
+    ) -> Self {
+        Sink {
+            target,
+            buf: AlignedBuf::new(block_size),
+            start: 0,
+            len: 0,
+            hasher: Sha256::new(),
+            extents: Vec::new(),
+            holes: Vec::new(),
+            discard,
+            written,
+            cancel,
+        }
+    }
+
+    pub(crate) fn write(&mut self, offset: u64, mut data: &[u8]) -> Result<()> {
+        let mut offset = offset;
+        while !data.is_empty() {
+            if self.len > 0 && offset != self.start + self.len as u64 {
+                self.flush()?;
+            }
+            if self.len == 0 {
+                self.start = offset;
+            }
+            let block = self.buf.as_mut_slice();
+            let n = data.len().min(block.len() - self.len);
+            block[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            offset += n as u64;
+            data = &data[n..];
+            if self.len == block.len() {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark a range that reads as zeros in the image.
+    pub(crate) fn hole(&mut self, offset: u64, len: u64) {
+        match self.holes.last_mut() {
+            Some((start, hole_len)) if *start + *hole_len == offset => *hole_len += len,
+            _ => self.holes.push((offset, len)),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.cancel.load(Ordering::Relaxed) {
+            anyhow::bail!("Image write cancelled");
+        }
+        if self.len == 0 {
+            return Ok(());
+        }
+        let data = &self.buf.as_slice()[..self.len];
+        self.target.write_at(data, self.start)?;
+        self.hasher.update(data);
+        match self.extents.last_mut() {
+            Some((start, len)) if *start + *len == self.start => *len += self.len as u64,
+            _ => self.extents.push((self.start, self.len as u64)),
+        }
+        self.written.fetch_add(self.len as u64, Ordering::Relaxed);
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Discard a hole; devices only take whole blocks, so zero the unaligned edges.
+    fn release(&mut self, offset: u64, len: u64) -> Result<()> {
+        if self.target.capacity.is_none() {
+            return self.target.discard(offset, len);
+        }
+        let align = ALIGN as u64;
+        let start = offset.next_multiple_of(align);
+        let end = (offset + len) / align * align;
+        if start >= end {
+            return self.target.write_at(&vec![0u8; len as usize], offset);
+        }
+        let zeros = vec![0u8; ALIGN];
+        self.target
+            .write_at(&zeros[..(start - offset) as usize], offset)?;
+        self.target.discard(start, end - start)?;
+        self.target
+            .write_at(&zeros[..(offset + len - end) as usize], end)
+    }
+
+    /// Write out what is left, release holes and size the target, returning the
+    /// digest of the data written and the extents it was written to.
+    fn finish(mut self, size: u64) -> Result<(String, Vec<(u64, u64)>)> {
+        self.flush()?;
+        if self.target.capacity.is_none() || self.discard {
+            for (offset, len) in std::mem::take(&mut self.holes) {
+                let len = len.min(size.saturating_sub(offset));
+                if len == 0 {
+                    continue;
+                }
+                if let Err(err) = self.release(offset, len) {
+                    warn!(
+                        "Failed to discard {}+{} on {}: {}",
+                        offset, len, self.target.path, err
+                    );
+                    break;
+                }
+            }
+        }
+        self.target.finish(size)?;
+        Ok((hex::encode(self.hasher.finalize()), self.extents))
+    }
+}
+
+/// Copy a raw image onto the sink, returning its size.
+fn write_raw(reader: &mut impl Read, sink: &mut Sink, block_size: usize) -> Result<u64> {
+    let mut buf = vec![0u8; block_size];
+    let mut offset = 0u64;
+    loop {
+        let len = read_full(reader, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+        sink.write(offset, &buf[..len])?;
+        offset += len as u64;
+    }
+    Ok(offset)
+}
+
+/// Outcome of writing an image stream.
+struct Written {
+    /// SHA-256 of the decompressed stream.
+    source_digest: String,
+    /// Size of the disk the image describes.
+    size: u64,
+    data_digest: String,
+    extents: Vec<(u64, u64)>,
+}
+
+/// Expand a decompressed image stream onto the target.
+fn write_stream(
+    reader: impl Read,
+    format: Option<ImageFormat>,
+    mut sink: Sink,
+    block_size: usize,
+) -> Result<Written> {
+    let mut reader = BufReader::with_capacity(
+        1024 * 1024,
+        HashReader {
+            inner: reader,
+            hasher: Sha256::new(),
+            len: 0,
+        },
+    );
+    let format = match format {
+        Some(format) => format,
+        None => detect_format(&mut reader)?,
+    };
+    info!("Image format: {:?}", format);
+    let size = match format {
+        ImageFormat::Raw => write_raw(&mut reader, &mut sink, block_size)?,
+        ImageFormat::Qcow2 => qcow2::expand(&mut reader, &mut sink)?,
+        ImageFormat::AndroidSparse => sparse::expand(&mut reader, &mut sink)?,
+    };
+    // Trailing bytes still count towards the digest of the download
+    io::copy(&mut reader, &mut io::sink())?;
+    let (data_digest, extents) = sink.finish(size)?;
+    let source = reader.into_inner();
+    debug!("Read {} bytes of image data", source.len);
+    Ok(Written {
+        source_digest: hex::encode(source.hasher.finalize()),
+        size,
+        data_digest,
+        extents,
+    })
+}
+
+/// Read back the given extents of the target, bypassing the page cache where possible.
+pub(crate) fn read_back_digest(path: &str, extents: &[(u64, u64)]) -> Result<String> {
+    use std::os::unix::fs::FileExt;
+    let file = File::open(path)?;
+    // SAFETY: plain advice on a file descriptor owned by `file`
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 4 * 1024 * 1024];
+    for &(mut offset, len) in extents {
+        let end = offset + len;
+        while offset < end {
+            let want = (end - offset).min(buf.len() as u64) as usize;
+            file.read_exact_at(&mut buf[..want], offset)?;
+            hasher.update(&buf[..want]);
+            offset += want as u64;
+        }
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Stream an image from a URL onto a block device or file, decompressing on the fly.
+///
+/// qcow2 and Android sparse images are expanded while streaming, without writing
+/// their holes. Progress is reported on the request id while writing; the final
+/// response carries the SHA-256 of the downloaded image, and with `verify` the
+/// written data is read back and compared.
+pub(crate) async fn write_image(
+    ctx: &Context,
+    req: &ImageWriteRequest,
+) -> Result<ImageWriteResponse> {
+    let (target, compression, format) = (req.target.as_str(), req.compression, req.format);
+    let discard = req.discard;
+    info!("Writing image from {} to {}", req.url, target);
+    let block_size = req.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    if block_size > MAX_BLOCK_SIZE {
+        anyhow::bail!(
+            "Block size {} exceeds the maximum of {}",
+            block_size,
+            MAX_BLOCK_SIZE
+        );
+    }
+    let block_size = block_size as usize;
+    let block_size = block_size.max(ALIGN) / ALIGN * ALIGN;
+    let throttle = Throttle::new(req.rate_limit);
+    let response = http_get(&req.url).await?;
+    let total = response.content_length();
+    let stream = throttle.clone().wrap(response.bytes_stream());
+    let reader = SyncIoBridge::new(StreamReader::new(stream.map_err(io::Error::other)));
+
+    // A missing device node must not turn into a file on devtmpfs
+    let create = !Path::new(target).starts_with("/dev");
+    let mut dest = Target::open(target, req.direct, create)
+        .map_err(|err| anyhow::anyhow!("Failed to open {}: {}", target, err))?;
+    debug!("Target {} opened, O_DIRECT={}", target, dest.direct);
+    let written = Arc::new(AtomicU64::new(0));
+    let cancel = Arc::new(AtomicBool::new(false));
+    let mut writer = {
+        let (written, cancel) = (written.clone(), cancel.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut reader = BufReader::with_capacity(1024 * 1024, reader);
+            let compression = match compression {
+                Some(c) => c,
+                None => detect_compression(&mut reader)?,
+            };
+            info!("Image compression: {:?}", compression);
+            let reader = decompress(compression, reader)?;
+            let sink = Sink::new(&mut dest, block_size, discard, &written, &cancel);
+            write_stream(reader, format, sink, block_size)
+        })
+    };
+
+    let mut progress = tokio::time::interval(PROGRESS_INTERVAL);
+    let result = loop {
+        tokio::select! {
+            result = &mut writer => break result??,
+            _ = ctx.cancelled(), if !cancel.load(Ordering::Relaxed) => {
+                cancel.store(true, Ordering::Relaxed);
+            }
+            _ = progress.tick() => {
+                ctx.respond2(
+                    true,
+                    AgentResponsePayload::ImageWriteResponse(ImageWriteResponse {
+                        written: written.load(Ordering::Relaxed),
+                        source_size: total,
+                        ..Default::default()
+                    }),
+                )
+                .await;
+            }
+        }
+    };
+    let data_len: u64 = result.extents.iter().map(|(_, len)| len).sum();
+    info!(
+        "Wrote {} bytes of a {} byte image to {}",
+        data_len, result.size, target
+    );
+    verify_digest(req.digest.as_deref(), &result.source_digest)?;
+    if req.verify {
+        let (path, extents) = (target.to_string(), result.extents.clone());
+        let read_back =
+            tokio::task::spawn_blocking(move || read_back_digest(&path, &extents)).await??;
+        if read_back != result.data_digest {
+            anyhow::bail!(
+                "Read-back verification failed: wrote {}, read {}",
+                result.data_digest,
+                read_back
+            );
+        }
+    }
+    Ok(ImageWriteResponse {
+        written: data_len,
+        source_size: total,
+        image_size: Some(result.size),
+        digest: Some(result.source_digest),
+        verified: req.verify,
+        done: true,
+        error: None,
+    })
+}
+
+/// Expand an image through a sink onto a scratch file and return its content.
+#[cfg(test)]
+pub(crate) fn expand_to_vec(expand: impl FnOnce(&mut Sink) -> Result<u64>) -> Result<Vec<u8>> {
+    let file = tempfile::NamedTempFile::new()?;
+    let mut target = Target::open(file.path().to_str().unwrap(), false, false)?;
+    let (written, cancel) = (AtomicU64::new(0), AtomicBool::new(false));
+    let mut sink = Sink::new(&mut target, 64 * 1024, false, &written, &cancel);
+    let size = expand(&mut sink)?;
+    sink.finish(size)?;
+    Ok(std::fs::read(file.path())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_targets_are_only_created_on_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.img");
+        let path = path.to_str().unwrap();
+        assert!(Target::open(path, false, false).is_err());
+        assert!(!Path::new(path).exists());
+        let target = Target::open(path, false, true).unwrap();
+        assert_eq!(target.capacity, None);
+        assert!(Path::new(path).is_file());
+    }
+}
@@ -0,0 +1,20 @@
+use log::warn;
+
+use crate::{blockdev, messages::InventoryResponse, netif, pci, smbios};
+
+/// Collect what the agent can find out about the hardware. Parts that cannot
+/// be read are reported as warnings instead of failing the whole inventory.
+pub(crate) fn collect() -> InventoryResponse {
+    let mut resp = InventoryResponse::default();
+    match smbios::read() {
+        Ok(dmi) => resp.dmi = Some(Box::new(dmi)),
+        Err(err) => {
+            warn!("No DMI inventory: {}", err);
+            resp.warnings.push(err.to_string());
+        }
+    }
+    resp.disks = blockdev::disks();
+    resp.interfaces = netif::interfaces();
+    resp.pci = pci::devices();
+    resp
+}
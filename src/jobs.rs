@@ -0,0 +1,80 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use log::{debug, info};
+use tokio_util::sync::CancellationToken;
+
+/// Requests in progress on a connection, so they can be cancelled by id.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Jobs {
+    running: Arc<Mutex<HashMap<u64, (u64, CancellationToken)>>>,
+    serial: Arc<AtomicU64>,
+}
+
+impl Jobs {
+    pub(crate) fn start(&self, id: u64) -> Job {
+        let serial = self.serial.fetch_add(1, Ordering::Relaxed);
+        let token = CancellationToken::new();
+        self.running
+            .lock()
+            .unwrap()
+            .insert(id, (serial, token.clone()));
+        Job {
+            id,
+            serial,
+            token,
+            jobs: self.clone(),
+        }
+    }
+
+    /// Cancel the request with the given id, returning whether it was running.
+    pub(crate) fn cancel(&self, id: u64) -> bool {
+        match self.running.lock().unwrap().get(&id) {
+            Some((_, token)) => {
+                info!("Cancelling request[id={}]", id);
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn cancel_all(&self) {
+        for (id, (_, token)) in self.running.lock().unwrap().iter() {
+            debug!("Cancelling request[id={}]", id);
+            token.cancel();
+        }
+    }
+}
+
+/// Registration of one running request, removed on drop.
+#[derive(Debug)]
+pub(crate) struct Job {
+    id: u64,
+    serial: u64,
+    token: CancellationToken,
+    jobs: Jobs,
+}
+
+impl Job {
+    pub(crate) fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        let mut running = self.jobs.running.lock().unwrap();
+        if running
+            .get(&self.id)
+            .is_some_and(|(s, _)| *s == self.serial)
+        {
+            running.remove(&self.id);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{ffi::CString, fs::File, os::fd::AsRawFd};
+
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::{
+    cache,
+    messages::{KexecRequest, KexecResponse},
+    throttle::Throttle,
+    utils::{download_file, sha256_file, verify_digest},
+};
+
+/// Download a file, or take it from the cache when its digest is known, and
+/// return its SHA-256.
+async fn fetch(url: &str, digest: Option<&str>, path: &str, throttle: &Throttle) -> Result<String> {
+    if let Some(digest) = digest
+        && cache::global().fetch(digest, path, false).await?
+    {
+        return cache::normalize_digest(digest);
+    }
+    download_file(url, path, throttle).await?;
+    let actual = sha256_file(path).await?;
+    verify_digest(digest, &actual)?;
+    if digest.is_some()
+        && let Err(err) = cache::global().insert(&actual, path).await
+    {
+        warn!("Failed to cache '{}': {}", path, err);
+    }
+    Ok(actual)
+}
+
+fn kexec_file_load(kernel: &File, initrd: Option<&File>, cmdline: &str) -> Result<()> {
+    let cmdline = CString::new(cmdline)?;
+    let (initrd, flags) = match initrd {
+        Some(initrd) => (initrd.as_raw_fd(), 0),
+        None => (-1, libc::KEXEC_FILE_NO_INITRAMFS),
+    };
+    // SAFETY: the descriptors are open and the command line is NUL terminated,
+    // its length includes the terminator as the syscall expects
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_kexec_file_load,
+            kernel.as_raw_fd(),
+            initrd,
+            cmdline.as_bytes_with_nul().len(),
+            cmdline.as_ptr(),
+            flags,
+        )
+    };
+    if ret < 0 {
+        let err = std::io::Error::last_os_error();
+        anyhow::bail!("kexec_file_load failed: {}", err);
+    }
+    Ok(())
+}
+
+/// Download the kernel and initrd, check their digests and load them for kexec.
+pub(crate) async fn load(req: &KexecRequest) -> Result<KexecResponse> {
+    let dir = tempfile::tempdir()?;
+    let throttle = Throttle::new(req.rate_limit);
+    let kernel_path = dir.path().join("kernel").to_string_lossy().to_string();
+    let kernel_digest = fetch(
+        &req.kernel,
+        req.kernel_digest.as_deref(),
+        &kernel_path,
+        &throttle,
+    )
+    .await?;
+    let (initrd_path, initrd_digest) = match &req.initrd {
+        Some(url) => {
+            let path = dir.path().join("initrd").to_string_lossy().to_string();
+            let digest = fetch(url, req.initrd_digest.as_deref(), &path, &throttle).await?;
+            (Some(path), Some(digest))
+        }
+        None => (None, None),
+    };
+    info!(
+        "Loading kernel from {} with cmdline '{}'",
+        req.kernel, req.cmdline
+    );
+    let cmdline = req.cmdline.clone();
+    tokio::task::spawn_blocking(move || {
+        let kernel = File::open(&kernel_path)?;
+        let initrd = initrd_path.map(File::open).transpose()?;
+        kexec_file_load(&kernel, initrd.as_ref(), &cmdline)
+    })
+    .await??;
+    Ok(KexecResponse {
+        kernel_digest: Some(kernel_digest),
+        initrd_digest,
+        loaded: true,
+        ..Default::default()
+    })
+}
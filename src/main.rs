@@ -1,11 +1,42 @@
 use discovery::discover_controller;
 use log::{error, info, warn};
 
+mod archive;
+mod blockdev;
+mod cache;
+mod chroot;
 mod discovery;
+mod events;
 mod executor;
+mod facts;
+mod filesystem;
+mod follow;
+mod hostfs;
+mod hostid;
+mod hotplug;
+mod image;
+mod inventory;
+mod jobs;
+mod kexec;
 mod messages;
+mod mounts;
 mod net;
+mod netif;
+mod partition;
+mod pci;
+mod personalize;
+mod power;
+mod qcow2;
+mod render;
+mod segmented;
+mod signing;
+mod smbios;
+mod sparse;
+mod telemetry;
+mod throttle;
+mod transfer;
 mod utils;
+mod wipe;
 
 #[tokio::main]
 async fn main() {
@@ -15,21 +46,29 @@ async fn main() {
         .init()
         .unwrap();
 
+    // `snapshot <dir>` captures this host's sysfs and procfs files for MXA_HOST_ROOT
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("snapshot") {
+        let Some(dest) = args.get(2) else {
+            error!("Usage: {} snapshot <dir>", args[0]);
+            std::process::exit(2);
+        };
+        if let Err(err) = hostfs::snapshot(std::path::Path::new(dest)) {
+            error!("Failed to snapshot the host: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     info!("MetalX Agent - Launching");
-    let host_id = match utils::get_machine_id() {
+    let host_id = match hostid::resolve() {
         Ok(id) => id,
         Err(err) => {
-            error!("Failed to get machine id: {}", err);
-            if cfg!(debug_assertions) {
-                eprintln!(
-                    "[Debug] Failed to get machine id, use 'cafecafecafecafecafecafecafecafe' instead",
-                );
-                "cafecafecafecafecafecafecafecafe".to_string()
-            } else {
-                std::process::exit(1);
-            }
+            error!("Failed to get host id: {}", err);
+            std::process::exit(1);
         }
     };
+    hotplug::watch();
     let ws_url = match std::env::var("WS_URL") {
         Ok(url) => url,
         Err(_) => {
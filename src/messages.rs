@@ -1,10 +1,16 @@
-use std::str::FromStr;
+use std::{fmt::Display, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct CommandExecutionRequest {
     pub command: String,
+    /// Run chrooted into the target root, with `/dev`, `/proc`, `/sys` and `/run` bound inside.
+    #[serde(default)]
+    pub target_root: bool,
+    /// Kill the command after this many seconds.
+    #[serde(default)]
+    pub timeout: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -21,24 +27,1020 @@ pub enum FileOperation {
     Upload,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub enum FileTransport {
+    /// Fetch from or send to `url` over HTTP.
+    #[default]
+    Http,
+    /// Stream the content as binary frames over the controller websocket.
+    WebSocket,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+    /// Extraction only.
+    Zip,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct FileOperationRequest {
+    #[serde(default)]
     pub url: String,
     pub path: String,
     pub operation: FileOperation,
+    #[serde(default)]
+    pub transport: FileTransport,
+    /// Resume offset in bytes, websocket transport only.
+    #[serde(default)]
+    pub offset: u64,
+    /// Expected SHA-256 of the whole file, checked after a download.
+    #[serde(default)]
+    pub digest: Option<String>,
+    /// Chunk size in bytes for websocket uploads, at most 4 MiB.
+    #[serde(default)]
+    pub chunk_size: Option<u32>,
+    /// Maximum number of unacknowledged chunks, websocket uploads only, at most 64.
+    #[serde(default)]
+    pub window: Option<u32>,
+    /// Extract the download into `path`, or archive the directory at `path` for upload.
+    #[serde(default)]
+    pub archive: Option<ArchiveFormat>,
+    /// Globs relative to the uploaded directory; everything is included when empty.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Per-transfer limit in bytes per second, on top of the agent-wide limit.
+    #[serde(default)]
+    pub rate_limit: Option<u64>,
+    /// Fallback URLs tried in order after `url`, HTTP downloads only.
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    /// Number of concurrent range requests, HTTP downloads only.
+    #[serde(default)]
+    pub parallelism: Option<u32>,
+    /// Hard link a cached copy instead of copying it; the file must not be modified in place.
+    #[serde(default)]
+    pub cache_link: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct FileOperationResponse {
     pub success: bool,
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// SHA-256 of the whole file.
+    #[serde(default)]
+    pub digest: Option<String>,
+    /// The download was served from the local cache.
+    #[serde(default)]
+    pub cache_hit: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Change the agent-wide transfer rate limit; `None` removes it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RateLimitRequest {
+    pub bytes_per_second: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RateLimitResponse {
+    pub bytes_per_second: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum CacheRequest {
+    /// Download a file into the cache without placing it anywhere.
+    Prewarm {
+        url: String,
+        digest: String,
+        #[serde(default)]
+        mirrors: Vec<String>,
+    },
+    /// Remove one entry, or the whole cache when no digest is given.
+    Purge {
+        digest: Option<String>,
+    },
+    List,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CacheEntry {
+    pub digest: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub last_used: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CacheResponse {
+    pub entries: Vec<CacheEntry>,
+}
+
+/// Stream content appended to a file until the request is cancelled.
+///
+/// Starts at the last `lines` lines if given, else at `offset`, else at the end of the file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileFollowRequest {
+    pub path: String,
+    #[serde(default)]
+    pub offset: Option<u64>,
+    #[serde(default)]
+    pub lines: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FileFollowResponse {
+    /// File offset of the first byte of `data`.
+    pub offset: u64,
+    pub data: String,
+    /// The file shrank and is followed again from the start.
+    pub truncated: bool,
+    /// The file was replaced and the new one is followed from the start.
+    pub rotated: bool,
+    /// Last message of the stream.
+    pub done: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Render a minijinja template with `variables` and host facts into `path`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RenderFileRequest {
+    pub template: String,
+    #[serde(default)]
+    pub variables: serde_json::Map<String, serde_json::Value>,
+    pub path: String,
+    /// Mode of a newly created file, 0o644 by default.
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Only compute the diff.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RenderFileResponse {
+    pub changed: bool,
+    /// Unified diff against the previous content.
+    pub diff: String,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+/// Layout of a disk image after decompression.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum ImageFormat {
+    Raw,
+    Qcow2,
+    AndroidSparse,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Stream an image from `url` onto a block device or file, decompressing on the fly.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImageWriteRequest {
+    pub url: String,
+    /// A block device, or a regular file that is created when missing unless
+    /// it is under `/dev`.
+    pub target: String,
+    /// Detected from the stream when not given.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Detected from the decompressed stream when not given.
+    #[serde(default)]
+    pub format: Option<ImageFormat>,
+    /// Write size in bytes, rounded down to 4 KiB, at most 64 MiB.
+    #[serde(default)]
+    pub block_size: Option<u32>,
+    /// Bypass the page cache with `O_DIRECT` where the target supports it.
+    #[serde(default = "default_true")]
+    pub direct: bool,
+    /// Read the target back and compare digests after writing.
+    #[serde(default = "default_true")]
+    pub verify: bool,
+    /// Discard holes of qcow2 and sparse images on block devices instead of skipping them.
+    #[serde(default)]
+    pub discard: bool,
+    /// Expected SHA-256 of the decompressed image, before expanding qcow2 or sparse images.
+    #[serde(default)]
+    pub digest: Option<String>,
+    #[serde(default)]
+    pub rate_limit: Option<u64>,
+}
+
+/// Sent periodically while writing, and once more with `done` set.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ImageWriteResponse {
+    /// Bytes written to the target so far, not counting holes.
+    pub written: u64,
+    /// Size of the (compressed) download, when announced by the server.
+    pub source_size: Option<u64>,
+    /// Size of the disk described by the image.
+    #[serde(default)]
+    pub image_size: Option<u64>,
+    /// SHA-256 of the decompressed image.
+    pub digest: Option<String>,
+    pub verified: bool,
+    pub done: bool,
+    pub error: Option<String>,
+}
+
+/// Read the partition table and filesystem signatures of a block device or image file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiskInspectRequest {
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionTableKind {
+    Gpt,
+    Mbr,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilesystemKind {
+    Ext2,
+    Ext3,
+    Ext4,
+    Xfs,
+    Vfat,
+    Luks,
+    LvmPv,
+    Swap,
+}
+
+/// A filesystem or other known signature found at the start of a partition or disk.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FilesystemInfo {
+    pub kind: FilesystemKind,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PartitionInfo {
+    pub number: u32,
+    /// Offset and size in bytes.
+    pub start: u64,
+    pub size: u64,
+    /// GPT partition type GUID.
+    pub type_guid: Option<String>,
+    /// GPT unique partition GUID.
+    pub guid: Option<String>,
+    /// MBR partition type byte.
+    pub mbr_type: Option<u8>,
+    pub name: Option<String>,
+    /// GPT attribute bits.
+    pub attributes: u64,
+    pub bootable: bool,
+    pub filesystem: Option<FilesystemInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DiskInspectResponse {
+    pub size: u64,
+    pub sector_size: u32,
+    pub table: Option<PartitionTableKind>,
+    /// GPT disk GUID or MBR disk signature.
+    pub disk_id: Option<String>,
+    pub partitions: Vec<PartitionInfo>,
+    /// Signature on the whole disk, e.g. a filesystem without a partition table.
+    pub filesystem: Option<FilesystemInfo>,
+    /// Problems that did not prevent reading the table, such as a damaged backup GPT.
+    pub warnings: Vec<String>,
+    pub error: Option<String>,
+}
+
+/// Chooses a whole disk; every given criterion must match, and exactly one disk may.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DiskSelector {
+    /// Device path or image file, used as is when given.
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub serial: Option<String>,
+    /// Substring of the model name.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// World wide name, as the disk reports it.
+    #[serde(default)]
+    pub wwn: Option<String>,
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    #[serde(default)]
+    pub max_size: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum PartitionSize {
+    Bytes(u64),
+    /// Share of the usable space on the disk.
+    Percent(u32),
+    /// Everything left; only valid for the last partition.
+    Rest,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PartitionSpec {
+    /// Type GUID, or one of `efi`, `bios-boot`, `linux`, `root-x86-64`, `root-aarch64`,
+    /// `home`, `swap`, `lvm` and `raid`.
+    pub type_guid: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub size: PartitionSize,
+    /// Unique partition GUID, random when not given.
+    #[serde(default)]
+    pub guid: Option<String>,
+    #[serde(default)]
+    pub attributes: u64,
+}
+
+/// Replace the partition table of a disk with a new GPT.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PartitionRequest {
+    pub disk: DiskSelector,
+    pub partitions: Vec<PartitionSpec>,
+    /// Alignment of partition starts in bytes, 1 MiB by default.
+    #[serde(default)]
+    pub alignment: Option<u64>,
+    #[serde(default)]
+    pub disk_guid: Option<String>,
+    /// Only compute the layout.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Ask the kernel to re-read the partition table of a block device afterwards.
+    #[serde(default = "default_true")]
+    pub reread: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PartitionResponse {
+    /// The selected disk.
+    pub disk: String,
+    pub disk_guid: Option<String>,
+    /// The computed layout, as written unless this was a dry run.
+    pub partitions: Vec<PartitionInfo>,
+    pub written: bool,
+    /// The kernel picked up the new table.
+    pub reread: bool,
+    pub warnings: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MountSpec {
+    pub device: String,
+    /// Absolute path inside the target root; ignored for swap.
+    #[serde(default)]
+    pub mount_point: String,
+    /// Mount options, also used for the fstab entry.
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// Create filesystems and manage mounts under the target root.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum FilesystemRequest {
+    /// Create a filesystem or swap area with the `mkfs` tools.
+    Create {
+        device: String,
+        kind: FilesystemKind,
+        #[serde(default)]
+        label: Option<String>,
+        /// A UUID, or for vfat a volume id like `1234-ABCD`.
+        #[serde(default)]
+        uuid: Option<String>,
+        /// Extra arguments for the `mkfs` tool.
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// Mount devices in order, undoing all of them if one fails; swap is only recorded.
+    Mount {
+        mounts: Vec<MountSpec>,
+    },
+    /// Unmount one mount point, or everything mounted by the agent, innermost first.
+    Unmount {
+        #[serde(default)]
+        mount_point: Option<String>,
+        /// Detach busy mounts with `MNT_DETACH` instead of failing; they are
+        /// only released once no longer in use.
+        #[serde(default)]
+        lazy: bool,
+    },
+    /// Write `/etc/fstab` in the target root for the recorded mounts.
+    WriteFstab,
+    List,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MountEntry {
+    pub device: String,
+    /// Path inside the target root, or `none` for swap.
+    pub mount_point: String,
+    pub fstype: String,
+    pub options: Vec<String>,
+    pub uuid: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FilesystemResponse {
+    /// The filesystem found on the device after `Create`.
+    pub filesystem: Option<FilesystemInfo>,
+    /// Mounts currently recorded by the agent.
+    pub mounts: Vec<MountEntry>,
+    /// Content written by `WriteFstab`.
+    pub fstab: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WipeMode {
+    /// Discard (TRIM) every block, or punch out a regular file.
+    Discard,
+    Zero,
+    /// Overwrite with pseudo-random data, `passes` times.
+    Random,
+    /// Zero only where partition tables, filesystem and RAID superblocks live:
+    /// the first and last MiB of the disk and of each partition.
+    Signatures,
+}
+
+/// Wipe a disk, partition or image file; progress is reported on the request id.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WipeRequest {
+    pub target: String,
+    pub mode: WipeMode,
+    /// Number of passes for `Random`, 1 by default.
+    #[serde(default)]
+    pub passes: Option<u32>,
+    /// Number of 4 KiB blocks read back to verify the wipe, 64 by default.
+    #[serde(default)]
+    pub samples: Option<u32>,
+}
+
+/// What was wiped and how it checked out, as signed by the agent.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WipeSummary {
+    pub target: String,
+    pub serial: Option<String>,
+    pub model: Option<String>,
+    pub size: u64,
+    pub mode: WipeMode,
+    pub passes: u32,
+    /// Bytes overwritten or discarded in each pass.
+    pub wiped: u64,
+    /// Start and end as seconds since the Unix epoch.
+    pub started: u64,
+    pub finished: u64,
+    pub samples: u32,
+    /// Sampled blocks that did not read back as written.
+    pub mismatches: u32,
+    pub verified: bool,
+    /// No read-back was done because the device does not report that discarded
+    /// blocks read as zeros.
+    pub verification_skipped: bool,
+}
+
+/// A JSON document with an ed25519 signature over its exact bytes.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignedSummary {
+    pub summary: String,
+    /// Hex encoded signature; `None` when the agent has no signing key.
+    pub signature: Option<String>,
+    /// Hex encoded public key of the agent.
+    pub public_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WipeResponse {
+    /// Current pass, counting from 1.
+    pub pass: u32,
+    pub passes: u32,
+    /// Bytes done in the current pass.
+    pub written: u64,
+    pub total: u64,
+    /// The signed [`WipeSummary`], in the final response.
+    pub summary: Option<SignedSummary>,
+    pub done: bool,
+    pub error: Option<String>,
+}
+
+/// Boot into another kernel without going through firmware.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KexecRequest {
+    /// URL of the kernel image.
+    pub kernel: String,
+    #[serde(default)]
+    pub initrd: Option<String>,
+    #[serde(default)]
+    pub cmdline: String,
+    /// Expected SHA-256 of the kernel; also lets it come from the download cache.
+    #[serde(default)]
+    pub kernel_digest: Option<String>,
+    #[serde(default)]
+    pub initrd_digest: Option<String>,
+    /// Load the kernel but do not execute it.
+    #[serde(default)]
+    pub load_only: bool,
+    #[serde(default)]
+    pub rate_limit: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct KexecResponse {
+    pub kernel_digest: Option<String>,
+    pub initrd_digest: Option<String>,
+    pub loaded: bool,
+    /// Sent right before the agent executes the loaded kernel.
+    pub executing: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerAction {
+    Reboot,
+    Poweroff,
+    Halt,
+    /// Only recorded for a [`KexecRequest`], not accepted in a [`PowerRequest`].
+    Kexec,
+}
+
+/// Reboot or power off the host. The agent acknowledges and closes the
+/// connection before acting.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PowerRequest {
+    pub action: PowerAction,
+    /// Seconds to wait after closing the connection.
+    #[serde(default)]
+    pub delay: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PowerResponse {
+    pub action: Option<PowerAction>,
+    pub delay: u64,
+    pub error: Option<String>,
+}
+
+/// A power action the agent carried out on purpose.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlannedPower {
+    pub action: PowerAction,
+    /// Id of the request that asked for it.
+    pub request_id: u64,
+    /// Seconds since the Unix epoch.
+    pub requested_at: u64,
+}
+
+/// Where the agent took its host id from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostIdSource {
+    /// The SMBIOS system UUID.
+    Dmi,
+    /// `/etc/machine-id`.
+    MachineId,
+    /// The permanent address of the primary network interface.
+    Mac,
+    /// A random id generated once and kept in the state directory.
+    Generated,
+}
+
+/// The first event the agent sends on every connection.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hello {
+    pub version: String,
+    pub host_id: String,
+    pub host_id_source: HostIdSource,
+    pub boot_id: Option<String>,
+    /// Set when the previous boot ended with a power action from the controller.
+    pub planned: Option<PlannedPower>,
+}
+
+/// A user to create, or to update when it exists.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserSpec {
+    pub name: String,
+    /// Picked from 1000 up for new users when not given.
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// A crypt(3) hash; new users without one cannot log in with a password.
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    /// Supplementary groups, which must exist in the target.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub shell: Option<String>,
+    #[serde(default)]
+    pub gecos: Option<String>,
+    /// Replaces `~/.ssh/authorized_keys` when not empty.
+    #[serde(default)]
+    pub authorized_keys: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetworkBackend {
+    Netplan,
+    NetworkManager,
+    Networkd,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InterfaceConfig {
+    pub name: String,
+    /// Match the interface by MAC address and give it `name`.
+    #[serde(default)]
+    pub mac: Option<String>,
+    /// Configure IPv4 with DHCP.
+    #[serde(default)]
+    pub dhcp: bool,
+    /// Static addresses in CIDR notation.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    #[serde(default)]
+    pub gateway: Option<String>,
+    #[serde(default)]
+    pub dns: Vec<String>,
+    #[serde(default)]
+    pub mtu: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NetworkConfig {
+    /// Detected from the target when not given.
+    #[serde(default)]
+    pub backend: Option<NetworkBackend>,
+    pub interfaces: Vec<InterfaceConfig>,
+}
+
+/// Personalize an installed root. Everything is validated before anything is written.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PersonalizeRequest {
+    /// Host path of the root, the target root by default.
+    #[serde(default)]
+    pub root: Option<String>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub users: Vec<UserSpec>,
+    #[serde(default)]
+    pub network: Option<NetworkConfig>,
+    /// 32 hex digits; empty to have systemd generate one on first boot.
+    #[serde(default)]
+    pub machine_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PersonalizeResponse {
+    /// What was changed, one line per change.
+    pub changes: Vec<String>,
+    /// Files written, as paths inside the root.
+    pub files: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BiosInfo {
+    pub vendor: Option<String>,
+    pub version: Option<String>,
+    pub release_date: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SystemInfo {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub serial: Option<String>,
+    pub uuid: Option<String>,
+    pub sku: Option<String>,
+    pub family: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BaseboardInfo {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub serial: Option<String>,
+    pub asset_tag: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ChassisInfo {
+    pub manufacturer: Option<String>,
+    /// Chassis type, such as "Rack Mount Chassis".
+    pub kind: Option<String>,
+    pub version: Option<String>,
+    pub serial: Option<String>,
+    pub asset_tag: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ProcessorInfo {
+    pub socket: Option<String>,
+    pub manufacturer: Option<String>,
+    pub version: Option<String>,
+    pub max_speed_mhz: Option<u32>,
+    pub current_speed_mhz: Option<u32>,
+    pub cores: Option<u32>,
+    pub threads: Option<u32>,
+    pub populated: bool,
+    pub serial: Option<String>,
+    pub part_number: Option<String>,
+}
+
+/// A memory slot; `size` is 0 when it is empty.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MemoryDevice {
+    pub locator: Option<String>,
+    pub bank: Option<String>,
+    /// Size in bytes.
+    pub size: u64,
+    /// Memory type, such as "DDR4".
+    pub kind: Option<String>,
+    /// Maximum speed in MT/s.
+    pub speed: Option<u32>,
+    pub configured_speed: Option<u32>,
+    pub manufacturer: Option<String>,
+    pub serial: Option<String>,
+    pub asset_tag: Option<String>,
+    pub part_number: Option<String>,
+}
+
+/// Hardware described by the SMBIOS (DMI) tables.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DmiInfo {
+    /// SMBIOS version, such as "3.2".
+    pub version: Option<String>,
+    pub bios: Option<BiosInfo>,
+    pub system: Option<SystemInfo>,
+    pub baseboard: Option<BaseboardInfo>,
+    pub chassis: Option<ChassisInfo>,
+    pub processors: Vec<ProcessorInfo>,
+    pub memory: Vec<MemoryDevice>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Sata,
+    Sas,
+    Scsi,
+    Nvme,
+    Virtio,
+    Usb,
+    Mmc,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlockPartition {
+    pub name: String,
+    pub path: String,
+    pub number: Option<u32>,
+    /// Offset on the disk in bytes.
+    pub start: u64,
+    pub size: u64,
+    /// Devices built on top of the partition, such as `dm-0` or `md127`.
+    pub holders: Vec<String>,
+}
+
+/// A disk found in `/sys/block`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlockDevice {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    pub logical_sector_size: u64,
+    pub physical_sector_size: u64,
+    pub rotational: bool,
+    pub removable: bool,
+    pub read_only: bool,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub wwn: Option<String>,
+    pub transport: Option<Transport>,
+    pub partitions: Vec<BlockPartition>,
+    /// Devices built on top of the whole disk, such as `dm-0` or `md127`.
+    pub holders: Vec<String>,
+    /// Symlinks in `/dev/disk/by-id` and `/dev/disk/by-path`.
+    pub by_id: Vec<String>,
+    pub by_path: Vec<String>,
+}
+
+/// A network interface found in `/sys/class/net`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub index: Option<u32>,
+    /// Kind of virtual device, such as "bond", "bridge" or "vlan".
+    pub kind: Option<String>,
+    pub mac: Option<String>,
+    /// Address burnt into the hardware, which bonding may hide from `mac`.
+    pub permanent_mac: Option<String>,
+    pub driver: Option<String>,
+    pub pci_address: Option<String>,
+    pub mtu: Option<u32>,
+    pub operstate: Option<String>,
+    pub carrier: Option<bool>,
+    /// Link speed in Mb/s.
+    pub speed: Option<u32>,
+    pub duplex: Option<String>,
+    /// Addresses with their prefix length, such as "192.0.2.10/24".
+    pub ipv4: Vec<String>,
+    pub ipv6: Vec<String>,
+    /// Bond or bridge the interface is a member of.
+    pub master: Option<String>,
+    /// Interfaces below this one: bond or bridge members, or the parent of a VLAN.
+    pub lower: Vec<String>,
+    pub vlan_id: Option<u16>,
+}
+
+/// A device found in `/sys/bus/pci/devices`. Ids are lowercase hex, as `lspci`
+/// shows them; names are only set when a `pci.ids` database is available.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PciDevice {
+    /// Domain, bus, device and function, such as "0000:3b:00.0".
+    pub address: String,
+    pub vendor_id: String,
+    pub device_id: String,
+    pub subsystem_vendor_id: Option<String>,
+    pub subsystem_device_id: Option<String>,
+    /// Class, subclass and programming interface, such as "010700".
+    pub class: String,
+    pub revision: Option<String>,
+    pub vendor: Option<String>,
+    pub device: Option<String>,
+    pub subsystem: Option<String>,
+    /// Name of the subclass, or of the class when the subclass is unknown.
+    pub class_name: Option<String>,
+    pub driver: Option<String>,
+    pub numa_node: Option<u32>,
+    pub iommu_group: Option<u32>,
+    /// Negotiated PCIe link, such as "8.0 GT/s PCIe" and 8 lanes.
+    pub link_speed: Option<String>,
+    pub link_width: Option<u32>,
+    pub max_link_speed: Option<String>,
+    pub max_link_width: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InventoryRequest {}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct InventoryResponse {
+    pub dmi: Option<Box<DmiInfo>>,
+    pub disks: Vec<BlockDevice>,
+    pub interfaces: Vec<NetworkInterface>,
+    pub pci: Vec<PciDevice>,
+    /// Parts of the inventory that could not be collected.
+    pub warnings: Vec<String>,
+    pub error: Option<String>,
+}
+
+/// Set how often the agent pushes telemetry samples.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TelemetryRequest {
+    /// Seconds between samples; telemetry stops when absent or 0.
+    #[serde(default)]
+    pub interval: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TelemetryResponse {
+    pub interval: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CpuSample {
+    /// Busy share of all CPUs since the previous sample, in percent.
+    pub usage: Option<f64>,
+    pub load: [f64; 3],
+}
+
+/// Memory counters in bytes, from `/proc/meminfo`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MemorySample {
+    pub total: u64,
+    pub free: u64,
+    pub available: u64,
+    pub buffers: u64,
+    pub cached: u64,
+    pub swap_total: u64,
+    pub swap_free: u64,
+}
+
+/// Cumulative counters of a disk, from `/proc/diskstats`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiskSample {
+    pub name: String,
+    pub reads: u64,
+    pub read_bytes: u64,
+    pub writes: u64,
+    pub written_bytes: u64,
+    /// Milliseconds spent doing I/O.
+    pub io_time: u64,
+}
+
+/// Cumulative counters of an interface, from `/proc/net/dev`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InterfaceSample {
+    pub name: String,
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_errors: u64,
+    pub tx_dropped: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FilesystemSample {
+    pub device: String,
+    pub mountpoint: String,
+    pub kind: String,
+    pub size: u64,
+    pub free: u64,
+    /// Free space usable without privileges.
+    pub available: u64,
+}
+
+/// A sensor from `/sys/class/hwmon`, in degrees Celsius.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TemperatureSample {
+    pub chip: String,
+    pub label: String,
+    pub current: f64,
+    pub max: Option<f64>,
+    pub critical: Option<f64>,
+}
+
+/// Pushed by the agent at the interval the controller set.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Telemetry {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub cpu: CpuSample,
+    pub memory: MemorySample,
+    pub disks: Vec<DiskSample>,
+    pub interfaces: Vec<InterfaceSample>,
+    pub filesystems: Vec<FilesystemSample>,
+    pub temperatures: Vec<TemperatureSample>,
+}
+
+/// Cancel a running request with the given id.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CancelRequest {
+    pub id: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CancelResponse {
+    /// The request was still running.
+    pub found: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 // #[serde(tag = "type")]
+#[allow(clippy::enum_variant_names)]
 pub enum ControllerRequestPayload {
     // None,
     CommandExecutionRequest(CommandExecutionRequest),
     FileOperationRequest(FileOperationRequest),
+    RateLimitRequest(RateLimitRequest),
+    CacheRequest(CacheRequest),
+    FileFollowRequest(FileFollowRequest),
+    CancelRequest(CancelRequest),
+    RenderFileRequest(RenderFileRequest),
+    ImageWriteRequest(ImageWriteRequest),
+    DiskInspectRequest(DiskInspectRequest),
+    PartitionRequest(PartitionRequest),
+    FilesystemRequest(FilesystemRequest),
+    WipeRequest(WipeRequest),
+    KexecRequest(KexecRequest),
+    PowerRequest(PowerRequest),
+    PersonalizeRequest(PersonalizeRequest),
+    InventoryRequest(InventoryRequest),
+    TelemetryRequest(TelemetryRequest),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -54,6 +1056,21 @@ pub enum AgentResponsePayload {
     None,
     CommandExecutionResponse(CommandExecutionResponse),
     FileOperationResponse(FileOperationResponse),
+    RateLimitResponse(RateLimitResponse),
+    CacheResponse(CacheResponse),
+    FileFollowResponse(FileFollowResponse),
+    CancelResponse(CancelResponse),
+    RenderFileResponse(RenderFileResponse),
+    ImageWriteResponse(ImageWriteResponse),
+    DiskInspectResponse(DiskInspectResponse),
+    PartitionResponse(PartitionResponse),
+    FilesystemResponse(FilesystemResponse),
+    WipeResponse(WipeResponse),
+    KexecResponse(KexecResponse),
+    PowerResponse(PowerResponse),
+    PersonalizeResponse(PersonalizeResponse),
+    InventoryResponse(InventoryResponse),
+    TelemetryResponse(TelemetryResponse),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -63,6 +1080,65 @@ pub struct AgentResponse {
     pub payload: AgentResponsePayload,
 }
 
+/// A message from the controller that could not be parsed as a request.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MalformedRequest {
+    /// The request id, when the message carried one.
+    pub id: Option<u64>,
+    pub error: String,
+}
+
+/// A request is done and nothing more is sent for it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobFinished {
+    pub id: u64,
+    /// Set when the agent could not handle the request; the outcome of the
+    /// work itself is in its response.
+    pub error: Option<String>,
+}
+
+/// A kernel uevent for a block, network or PCI device.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hotplug {
+    /// `add`, `remove`, `change`, `bind` and so on.
+    pub action: String,
+    pub subsystem: String,
+    /// Path of the device below `/sys`.
+    pub devpath: String,
+    /// Interface name or `/dev` node name.
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum AgentState {
+    /// Accepting requests.
+    Ready,
+    /// About to carry out a power action; the connection is closed next.
+    PowerPending(PowerAction),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum AgentEventPayload {
+    Hello(Hello),
+    Telemetry(Telemetry),
+    MalformedRequest(MalformedRequest),
+    JobFinished(JobFinished),
+    Hotplug(Hotplug),
+    StateChanged(AgentState),
+}
+
+/// Sent by the agent on its own, not in reply to a request. Responses carry an
+/// `id`, events a `seq` and an `event` instead.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AgentEvent {
+    /// Counts up from 0 across all events since the agent started; a gap means
+    /// events were dropped while disconnected or behind.
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub event: AgentEventPayload,
+}
+
 impl FromStr for ControllerRequest {
     type Err = serde_json::Error;
 
@@ -71,8 +1147,14 @@ impl FromStr for ControllerRequest {
     }
 }
 
-impl ToString for AgentResponse {
-    fn to_string(&self) -> String {
-        serde_json::to_string(self).unwrap()
+impl Display for AgentResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
+    }
+}
+
+impl Display for AgentEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
     }
 }
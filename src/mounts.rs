@@ -0,0 +1,251 @@
+use std::{
+    ffi::CString,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    sync::{LazyLock, Mutex},
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    filesystem::{fstype, probe_device},
+    messages::{FilesystemKind, MountEntry, MountSpec},
+    render::write_atomic,
+};
+
+const DEFAULT_TARGET_ROOT: &str = "/mnt/target";
+
+/// Mounts made by the agent, under the root from `MXA_TARGET_ROOT`.
+static MOUNTS: LazyLock<Mounts> = LazyLock::new(|| {
+    let root = std::env::var("MXA_TARGET_ROOT").unwrap_or_else(|_| DEFAULT_TARGET_ROOT.to_string());
+    Mounts {
+        root: PathBuf::from(root),
+        mounted: Mutex::new(Vec::new()),
+    }
+});
+
+pub(crate) fn global() -> &'static Mounts {
+    &MOUNTS
+}
+
+fn cstring(path: &Path) -> Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Split mount options into flags and the filesystem specific rest.
+fn mount_flags(options: &[String]) -> (libc::c_ulong, String) {
+    let mut flags = 0;
+    let mut data = Vec::new();
+    for option in options {
+        match option.as_str() {
+            "defaults" | "rw" => {}
+            "ro" => flags |= libc::MS_RDONLY,
+            "nosuid" => flags |= libc::MS_NOSUID,
+            "nodev" => flags |= libc::MS_NODEV,
+            "noexec" => flags |= libc::MS_NOEXEC,
+            "noatime" => flags |= libc::MS_NOATIME,
+            "nodiratime" => flags |= libc::MS_NODIRATIME,
+            "relatime" => flags |= libc::MS_RELATIME,
+            "sync" => flags |= libc::MS_SYNCHRONOUS,
+            other => data.push(other),
+        }
+    }
+    (flags, data.join(","))
+}
+
+/// Unmount `path`; when it is busy, fail unless `lazy` allows detaching it.
+fn umount(path: &Path, lazy: bool) -> Result<()> {
+    let target = cstring(path)?;
+    // SAFETY: umount2 with a valid C string
+    if unsafe { libc::umount2(target.as_ptr(), 0) } == 0 {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EBUSY) {
+        return Err(err.into());
+    }
+    if !lazy {
+        anyhow::bail!("{} is busy", path.display());
+    }
+    warn!("{} is busy, detaching it lazily", path.display());
+    // SAFETY: as above
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+struct Mounted {
+    entry: MountEntry,
+    /// Where it is mounted on the host; `None` for swap.
+    path: Option<PathBuf>,
+}
+
+/// Tracks what the agent mounted under the target root, so it can be unmounted
+/// and described in the installed system's fstab.
+pub(crate) struct Mounts {
+    root: PathBuf,
+    mounted: Mutex<Vec<Mounted>>,
+}
+
+impl Mounts {
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Host path of an absolute path inside the target root.
+    pub(crate) fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let relative = Path::new(path)
+            .strip_prefix("/")
+            .map_err(|_| anyhow::anyhow!("Path must be absolute: {}", path))?;
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            anyhow::bail!("Invalid path: {}", path);
+        }
+        Ok(self.root.join(relative))
+    }
+
+    fn mount(&self, spec: &MountSpec) -> Result<MountEntry> {
+        let info = probe_device(&spec.device)?
+            .ok_or_else(|| anyhow::anyhow!("No filesystem on {}", spec.device))?;
+        let fstype = fstype(info.kind)
+            .ok_or_else(|| anyhow::anyhow!("Cannot mount {:?} on {}", info.kind, spec.device))?;
+        let mut entry = MountEntry {
+            device: spec.device.clone(),
+            mount_point: spec.mount_point.clone(),
+            fstype: fstype.to_string(),
+            options: spec.options.clone(),
+            uuid: info.uuid,
+        };
+        if info.kind == FilesystemKind::Swap {
+            entry.mount_point = "none".to_string();
+            self.mounted.lock().unwrap().push(Mounted {
+                entry: entry.clone(),
+                path: None,
+            });
+            return Ok(entry);
+        }
+        let path = self.resolve(&spec.mount_point)?;
+        let mut mounted = self.mounted.lock().unwrap();
+        if mounted.iter().any(|m| m.path.as_ref() == Some(&path)) {
+            anyhow::bail!("Something is already mounted at {}", spec.mount_point);
+        }
+        fs::create_dir_all(&path)?;
+        let (flags, data) = mount_flags(&spec.options);
+        let (source, target) = (cstring(Path::new(&spec.device))?, cstring(&path)?);
+        let (fstype, data) = (CString::new(fstype)?, CString::new(data)?);
+        info!("Mounting {} at {}", spec.device, path.display());
+        // SAFETY: mount with valid C strings
+        let ret = unsafe {
+            libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                fstype.as_ptr(),
+                flags,
+                data.as_ptr().cast(),
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        mounted.push(Mounted {
+            entry: entry.clone(),
+            path: Some(path),
+        });
+        Ok(entry)
+    }
+
+    /// Mount devices, parents before children; on failure or cancellation the
+    /// mounts made so far are undone.
+    pub(crate) fn mount_all(&self, specs: &[MountSpec], cancel: &CancellationToken) -> Result<()> {
+        let mut specs: Vec<&MountSpec> = specs.iter().collect();
+        specs.sort_by_key(|s| Path::new(&s.mount_point).components().count());
+        let mut done = Vec::new();
+        for spec in specs {
+            let result = if cancel.is_cancelled() {
+                Err(anyhow::anyhow!("Mounting cancelled"))
+            } else {
+                self.mount(spec)
+            };
+            match result {
+                Ok(entry) => done.push(entry.mount_point),
+                Err(err) => {
+                    for mount_point in done.iter().rev() {
+                        if let Err(err) = self.unmount(Some(mount_point), false) {
+                            warn!("Failed to undo mount at {}: {}", mount_point, err);
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmount one mount point, or everything the agent mounted, innermost first.
+    pub(crate) fn unmount(&self, mount_point: Option<&str>, lazy: bool) -> Result<()> {
+        let mut mounted = self.mounted.lock().unwrap();
+        if let Some(mount_point) = mount_point
+            && !mounted.iter().any(|m| m.entry.mount_point == mount_point)
+        {
+            anyhow::bail!("{} was not mounted by the agent", mount_point);
+        }
+        while let Some(index) = mounted
+            .iter()
+            .rposition(|m| mount_point.is_none_or(|p| m.entry.mount_point == p))
+        {
+            if let Some(path) = &mounted[index].path {
+                info!("Unmounting {}", path.display());
+                umount(path, lazy)?;
+            }
+            mounted.remove(index);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn list(&self) -> Vec<MountEntry> {
+        let mounted = self.mounted.lock().unwrap();
+        mounted.iter().map(|m| m.entry.clone()).collect()
+    }
+
+    /// An fstab for the installed system, referring to filesystems by UUID.
+    pub(crate) fn fstab(&self) -> String {
+        let mut fstab = String::new();
+        for entry in self.list() {
+            let source = match &entry.uuid {
+                Some(uuid) => format!("UUID={}", uuid),
+                None => entry.device.clone(),
+            };
+            let options = match entry.options.is_empty() {
+                true if entry.fstype == "swap" => "sw".to_string(),
+                true => "defaults".to_string(),
+                false => entry.options.join(","),
+            };
+            // xfs is never checked at boot, swap has nothing to check
+            let pass = match (entry.fstype.as_str(), entry.mount_point.as_str()) {
+                ("swap" | "xfs", _) => 0,
+                (_, "/") => 1,
+                _ => 2,
+            };
+            fstab.push_str(&format!(
+                "{} {} {} {} 0 {}\n",
+                source, entry.mount_point, entry.fstype, options, pass
+            ));
+        }
+        fstab
+    }
+
+    pub(crate) fn write_fstab(&self) -> Result<String> {
+        let fstab = self.fstab();
+        let path = self.resolve("/etc/fstab")?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        write_atomic(&path, fstab.as_bytes(), 0o644)?;
+        info!("Wrote {}", path.display());
+        Ok(fstab)
+    }
+}
@@ -1,13 +1,32 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
 use crate::{
+    events,
     executor::handle_event,
-    messages::{AgentResponse, AgentResponsePayload, ControllerRequest},
+    hostid::HostId,
+    jobs::{Job, Jobs},
+    messages::{
+        AgentEvent, AgentEventPayload, AgentResponse, AgentResponsePayload, ControllerRequest,
+        Hello, JobFinished, MalformedRequest,
+    },
+    power, telemetry,
+    transfer::{Frame, FrameRouter, FrameSubscription},
 };
 use anyhow::Result;
 use futures_util::{SinkExt, StreamExt};
 use log::{debug, error, info, trace, warn};
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    watch,
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::protocol::{CloseFrame, Message, frame::coding::CloseCode},
+};
+use tokio_util::sync::CancellationToken;
+
+/// How long [`Context::close`] waits for the controller to finish the close handshake.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
 
 type WebSocketTx = futures_util::stream::SplitSink<
     tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
@@ -37,12 +56,18 @@ pub(crate) struct Context {
     pub id: u64,
     pub request: Request,
     responder: AsyncResponder,
+    frames: FrameRouter,
+    jobs: Jobs,
+    job: Job,
+    /// Number of the last event sent to the controller.
+    events_sent: watch::Receiver<u64>,
 }
 
 impl Context {
     pub(crate) async fn respond(&self, response: Response) -> Result<()> {
         let msg = match response {
             Response::Text(r) => Message::Text(r.to_string()),
+            Response::Binary(f) => Message::Binary(f.encode()),
         };
         self.responder.clone().respond(msg).await
     }
@@ -59,6 +84,58 @@ impl Context {
             warn!("Failed to respond request[id={}]: {}", self.id, e);
         }
     }
+
+    pub(crate) async fn send_frame(&self, frame: Frame) -> Result<()> {
+        self.respond(Response::Binary(frame)).await
+    }
+
+    /// Receive binary frames addressed to this request until the subscription is dropped.
+    pub(crate) fn subscribe_frames(&self) -> FrameSubscription {
+        self.frames.subscribe(self.id)
+    }
+
+    /// Resolves once the controller cancels this request or the connection is lost.
+    pub(crate) async fn cancelled(&self) {
+        self.job.token().cancelled().await
+    }
+
+    /// Token cancelled along with this request, for work outside async code.
+    pub(crate) fn token(&self) -> &CancellationToken {
+        self.job.token()
+    }
+
+    /// Close the connection cleanly once the events emitted so far are sent,
+    /// waiting briefly for the controller to answer the close frame.
+    pub(crate) async fn close(&self) {
+        let last = events::last_seq();
+        let mut sent = self.events_sent.clone();
+        if tokio::time::timeout(CLOSE_TIMEOUT, sent.wait_for(|seq| *seq >= last))
+            .await
+            .is_err()
+        {
+            warn!("Closing the connection before event {} was sent", last);
+        }
+        let frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "".into(),
+        };
+        if let Err(e) = self
+            .responder
+            .clone()
+            .respond(Message::Close(Some(frame)))
+            .await
+        {
+            warn!("Failed to close the connection: {}", e);
+            return;
+        }
+        // The job is cancelled once the connection is gone
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, self.cancelled()).await;
+    }
+
+    /// Cancel another request running on the same connection.
+    pub(crate) fn cancel(&self, id: u64) -> bool {
+        self.jobs.cancel(id)
+    }
 }
 
 #[derive(Debug)]
@@ -69,9 +146,16 @@ pub(crate) enum Request {
 #[derive(Debug)]
 pub(crate) enum Response {
     Text(AgentResponse),
+    Binary(Frame),
 }
 
-async fn handle_msg(ws_msg: Message, responder: AsyncResponder) -> Result<bool> {
+async fn handle_msg(
+    ws_msg: Message,
+    responder: AsyncResponder,
+    frames: FrameRouter,
+    jobs: Jobs,
+    events_sent: watch::Receiver<u64>,
+) -> Result<bool> {
     debug!("Received message: {:?}", ws_msg);
     match ws_msg {
         Message::Text(msg) => {
@@ -79,37 +163,43 @@ async fn handle_msg(ws_msg: Message, responder: AsyncResponder) -> Result<bool>
             match ControllerRequest::from_str(msg.as_str()) {
                 Ok(event_msg) => {
                     info!("Received event: {:?}", event_msg);
+                    let id = event_msg.id;
                     let ctx = Context {
                         id: event_msg.id,
+                        job: jobs.start(event_msg.id),
                         request: Request::Text(event_msg),
                         responder,
+                        frames,
+                        jobs,
+                        events_sent,
                     };
                     tokio::spawn(async move {
-                        if let Err(e) = handle_event(ctx).await {
+                        let result = handle_event(ctx).await;
+                        if let Err(e) = &result {
                             error!("Failed to handle event: {}", e);
                         }
+                        events::emit(AgentEventPayload::JobFinished(JobFinished {
+                            id,
+                            error: result.err().map(|e| e.to_string()),
+                        }));
                     });
                 }
                 Err(err) => {
                     error!("Failed to parse message: {}", err);
-                    if let Err(e) = responder
-                        .respond(Message::Text(
-                            AgentResponse {
-                                id: u64::MAX,
-                                ok: false,
-                                payload: AgentResponsePayload::None,
-                            }
-                            .to_string(),
-                        ))
-                        .await
-                    {
-                        error!("Failed to respond to malformed message: {}", e);
-                    }
+                    // Pick out the id so the controller can tell which request failed
+                    let id = serde_json::from_str::<serde_json::Value>(msg.as_str())
+                        .ok()
+                        .and_then(|value| value.get("id")?.as_u64());
+                    events::emit(AgentEventPayload::MalformedRequest(MalformedRequest {
+                        id,
+                        error: err.to_string(),
+                    }));
                 }
             }
         }
-        Message::Binary(_) => {
-            debug!("Received binary message from controller"); // TODO: Handle binary message
+        Message::Binary(msg) => {
+            trace!("Received binary message from controller");
+            frames.dispatch(msg)?;
         }
         Message::Ping(msg) => {
             responder.respond(Message::Pong(msg)).await?;
@@ -131,44 +221,151 @@ async fn handle_msg(ws_msg: Message, responder: AsyncResponder) -> Result<bool>
     Ok(true)
 }
 
+fn hello(host_id: &HostId) -> AgentEventPayload {
+    AgentEventPayload::Hello(Hello {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        host_id: host_id.id.clone(),
+        host_id_source: host_id.source,
+        boot_id: power::boot_id(),
+        planned: power::previous(),
+    })
+}
+
+/// Send queued events to the controller until the connection ends, recording
+/// the number of the last one sent.
+async fn forward_events(
+    responder: AsyncResponder,
+    mut events: broadcast::Receiver<AgentEvent>,
+    sent: watch::Sender<u64>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Dropped {} event(s), the connection fell behind", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if let Err(e) = responder
+            .clone()
+            .respond(Message::Text(event.to_string()))
+            .await
+        {
+            warn!("Failed to send event {}: {}", event.seq, e);
+            return;
+        }
+        sent.send_replace(event.seq);
+    }
+}
+
+/// Push telemetry at the interval the controller set, until the connection ends.
+async fn push_telemetry() {
+    let mut config = telemetry::subscribe();
+    let mut previous = None;
+    loop {
+        let Some(interval) = *config.borrow_and_update() else {
+            previous = None;
+            if config.changed().await.is_err() {
+                return;
+            }
+            continue;
+        };
+        if previous.is_none() {
+            // A baseline so the first sample has a CPU usage
+            previous = telemetry::cpu_times();
+        }
+        tokio::select! {
+            changed = config.changed() => if changed.is_err() {
+                return;
+            },
+            _ = tokio::time::sleep(interval) => {
+                let mut times = previous;
+                let Ok(sample) = tokio::task::spawn_blocking(move || {
+                    let sample = telemetry::sample(&mut times);
+                    (sample, times)
+                })
+                .await
+                else {
+                    return;
+                };
+                previous = sample.1;
+                events::emit(AgentEventPayload::Telemetry(sample.0));
+            }
+        }
+    }
+}
+
 async fn handle_conn(
     ws: tokio_tungstenite::WebSocketStream<
         tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
     >,
+    host_id: &HostId,
 ) -> Result<()> {
     let (tx, mut rx) = ws.split();
     let responder = AsyncResponder::new(tx);
+    // Everything queued after the hello is numbered after it
+    let (hello, queued) = events::subscribe(hello(host_id));
+    let (sent, events_sent) = watch::channel(hello.seq);
+    responder
+        .clone()
+        .respond(Message::Text(hello.to_string()))
+        .await?;
+    let forwarder = tokio::spawn(forward_events(responder.clone(), queued, sent));
+    let pusher = tokio::spawn(push_telemetry());
+    let frames = FrameRouter::default();
+    let jobs = Jobs::default();
     trace!("Websocket connected to controller. Begin to handle message loop");
     while let Some(event) = rx.next().await {
         match event {
-            Ok(ws_msg) => match handle_msg(ws_msg, responder.clone()).await {
-                Ok(c) => {
-                    if !c {
-                        break;
+            Ok(ws_msg) => {
+                let sent = events_sent.clone();
+                match handle_msg(
+                    ws_msg,
+                    responder.clone(),
+                    frames.clone(),
+                    jobs.clone(),
+                    sent,
+                )
+                .await
+                {
+                    Ok(c) => {
+                        if !c {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to handle message: {}", e);
                     }
                 }
-                Err(e) => {
-                    error!("Failed to handle message: {}", e);
-                }
-            },
+            }
             Err(err) => {
                 error!("Failed to receive message: {}", err);
             }
         }
     }
+    jobs.cancel_all();
+    pusher.abort();
+    forwarder.abort();
     Ok(())
 }
 
-pub(crate) async fn agent_main(ws_url: String, host_id: String) -> Result<()> {
+pub(crate) async fn agent_main(ws_url: String, host_id: HostId) -> Result<()> {
     info!("Use Controller URL: {}", ws_url);
-    let ws_url = format!("{}?host_id={}", ws_url, host_id);
+    let ws_url = format!("{}?host_id={}", ws_url, host_id.id);
     loop {
+        if power::pending() {
+            info!("Power action pending, not reconnecting");
+            while power::pending() {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
         let ws_url = ws_url.clone();
         info!("Connecting to controller websocket: {}", ws_url);
         for retry in 0..5 {
             match connect_async(ws_url.clone()).await {
                 Ok((ws, _)) => {
-                    handle_conn(ws).await?;
+                    handle_conn(ws, &host_id).await?;
                     break;
                 }
                 Err(err) => {
@@ -0,0 +1,302 @@
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    net::{Ipv4Addr, Ipv6Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+};
+
+use log::warn;
+
+use crate::{
+    facts::{link_name, read_trimmed, sorted_entries},
+    hostfs,
+    messages::NetworkInterface,
+};
+
+const ETHTOOL_GPERMADDR: u32 = 0x20;
+const MAX_ADDR_LEN: usize = 32;
+
+/// `struct ethtool_perm_addr` with room for the longest hardware address.
+#[repr(C)]
+struct PermAddr {
+    cmd: u32,
+    size: u32,
+    data: [u8; MAX_ADDR_LEN],
+}
+
+fn format_mac(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The address the hardware came with, asked from the driver through ethtool.
+fn permanent_mac(name: &str) -> Option<String> {
+    if name.len() >= libc::IFNAMSIZ {
+        return None;
+    }
+    // SAFETY: socket takes no pointers
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return None;
+    }
+    // SAFETY: the descriptor was just opened and nothing else owns it
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut addr = PermAddr {
+        cmd: ETHTOOL_GPERMADDR,
+        size: MAX_ADDR_LEN as u32,
+        data: [0; MAX_ADDR_LEN],
+    };
+    // SAFETY: an all-zero ifreq is valid
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    ifr.ifr_ifru.ifru_data = (&mut addr as *mut PermAddr).cast();
+    // SAFETY: ifr points at `addr`, which outlives the call
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCETHTOOL as _, &mut ifr) } < 0 {
+        return None;
+    }
+    let bytes = addr.data.get(..addr.size as usize)?;
+    bytes.iter().any(|&b| b != 0).then(|| format_mac(bytes))
+}
+
+/// IPv4 and IPv6 addresses of every interface, as the kernel reports them
+/// over netlink to getifaddrs.
+fn addresses() -> HashMap<String, (Vec<String>, Vec<String>)> {
+    let mut addresses: HashMap<String, (Vec<String>, Vec<String>)> = HashMap::new();
+    let mut list = std::ptr::null_mut();
+    // SAFETY: getifaddrs fills in a list we free below
+    if unsafe { libc::getifaddrs(&mut list) } < 0 {
+        warn!(
+            "Failed to list interface addresses: {}",
+            std::io::Error::last_os_error()
+        );
+        return addresses;
+    }
+    let mut next = list;
+    while !next.is_null() {
+        // SAFETY: entries of the list stay valid until freeifaddrs
+        let ifa = unsafe { &*next };
+        next = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() {
+            continue;
+        }
+        // SAFETY: the name is NUL terminated and the address starts with its family
+        let (name, family) = unsafe {
+            (
+                CStr::from_ptr(ifa.ifa_name).to_string_lossy().to_string(),
+                (*ifa.ifa_addr).sa_family as i32,
+            )
+        };
+        let entry = addresses.entry(name).or_default();
+        match family {
+            libc::AF_INET => {
+                // SAFETY: AF_INET addresses and netmasks are sockaddr_in
+                let (addr, mask) = unsafe {
+                    let addr = &*(ifa.ifa_addr as *const libc::sockaddr_in);
+                    let mask = (!ifa.ifa_netmask.is_null())
+                        .then(|| (*(ifa.ifa_netmask as *const libc::sockaddr_in)).sin_addr);
+                    (addr.sin_addr, mask)
+                };
+                let prefix = mask.map_or(32, |m| m.s_addr.count_ones());
+                let addr = Ipv4Addr::from(u32::from_be(addr.s_addr));
+                entry.0.push(format!("{}/{}", addr, prefix));
+            }
+            libc::AF_INET6 => {
+                // SAFETY: AF_INET6 addresses and netmasks are sockaddr_in6
+                let (addr, mask) = unsafe {
+                    let addr = &*(ifa.ifa_addr as *const libc::sockaddr_in6);
+                    let mask = (!ifa.ifa_netmask.is_null())
+                        .then(|| (*(ifa.ifa_netmask as *const libc::sockaddr_in6)).sin6_addr);
+                    (addr.sin6_addr, mask)
+                };
+                let prefix = mask.map_or(128, |m| {
+                    m.s6_addr.iter().map(|b| b.count_ones()).sum::<u32>()
+                });
+                let addr = Ipv6Addr::from(addr.s6_addr);
+                entry.1.push(format!("{}/{}", addr, prefix));
+            }
+            _ => {}
+        }
+    }
+    // SAFETY: the list came from getifaddrs and is not used past here
+    unsafe { libc::freeifaddrs(list) };
+    addresses
+}
+
+/// VLAN ids by interface name, from `/proc/net/vlan/config`.
+fn vlan_ids(root: &Path) -> HashMap<String, u16> {
+    let config = std::fs::read_to_string(root.join("proc/net/vlan/config")).unwrap_or_default();
+    config
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let mut fields = line.split('|').map(str::trim);
+            let name = fields.next()?.to_string();
+            let id = fields.next()?.parse().ok()?;
+            Some((name, id))
+        })
+        .collect()
+}
+
+/// Bus address of the PCI device behind an interface; virtio devices sit one
+/// level below theirs.
+fn pci_address(root: &Path, device: &Path) -> Option<String> {
+    let path = std::fs::canonicalize(device).ok()?;
+    let devices = std::fs::canonicalize(root.join("sys/devices")).ok()?;
+    path.ancestors()
+        .take_while(|dir| dir.starts_with(&devices))
+        .find(|dir| link_name(dir.join("subsystem")).as_deref() == Some("pci"))
+        .and_then(|dir| Some(dir.file_name()?.to_string_lossy().to_string()))
+}
+
+/// Every interface in `/sys/class/net`, with its link state, addresses and
+/// place among bonds, bridges and VLANs.
+pub(crate) fn interfaces() -> Vec<NetworkInterface> {
+    interfaces_in(&hostfs::path("/"), hostfs::is_live())
+}
+
+/// The interfaces described by the files under `root`. Addresses and permanent
+/// MACs come from the kernel, not from files, so only `live` roots have them.
+fn interfaces_in(root: &Path, live: bool) -> Vec<NetworkInterface> {
+    let mut addresses = if live { addresses() } else { HashMap::new() };
+    let vlans = vlan_ids(root);
+    let net = root.join("sys/class/net");
+    sorted_entries(&net)
+        .into_iter()
+        .map(|name| {
+            let dir = net.join(&name);
+            let attr = |attr: &str| read_trimmed(dir.join(attr));
+            let kind = attr("uevent").and_then(|uevent| {
+                uevent
+                    .lines()
+                    .find_map(|line| line.strip_prefix("DEVTYPE=").map(str::to_string))
+            });
+            let device = dir.join("device");
+            let lower = sorted_entries(&dir)
+                .into_iter()
+                .filter_map(|entry| entry.strip_prefix("lower_").map(str::to_string))
+                .collect();
+            let (ipv4, ipv6) = addresses.remove(&name).unwrap_or_default();
+            NetworkInterface {
+                index: attr("ifindex").and_then(|i| i.parse().ok()),
+                kind,
+                mac: attr("address").filter(|mac| !mac.is_empty()),
+                permanent_mac: live.then(|| permanent_mac(&name)).flatten(),
+                driver: link_name(device.join("driver")),
+                pci_address: pci_address(root, &device),
+                mtu: attr("mtu").and_then(|mtu| mtu.parse().ok()),
+                operstate: attr("operstate"),
+                // Reading carrier, speed and duplex fails while the link is down
+                carrier: attr("carrier").map(|carrier| carrier == "1"),
+                speed: attr("speed")
+                    .and_then(|speed| speed.parse::<u32>().ok())
+                    .filter(|speed| *speed > 0 && *speed != u32::MAX),
+                duplex: attr("duplex").filter(|duplex| duplex != "unknown"),
+                ipv4,
+                ipv6,
+                master: link_name(dir.join("master")),
+                lower,
+                vlan_id: vlans.get(&name).copied(),
+                name,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interfaces_from_fixture() {
+        let interfaces = interfaces();
+        let names: Vec<&str> = interfaces.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["eth0", "lo"]);
+        let eth0 = &interfaces[0];
+        assert_eq!(eth0.index, Some(4));
+        assert_eq!(eth0.mac.as_deref(), Some("02:fc:00:00:00:01"));
+        assert_eq!(eth0.driver.as_deref(), Some("virtio_net"));
+        assert_eq!(eth0.pci_address.as_deref(), Some("0000:00:04.0"));
+        assert_eq!(eth0.mtu, Some(1400));
+        assert_eq!(eth0.operstate.as_deref(), Some("up"));
+        // Only known on the running host
+        assert!(eth0.permanent_mac.is_none());
+        assert!(eth0.ipv4.is_empty());
+        let lo = &interfaces[1];
+        assert!(lo.driver.is_none());
+        assert!(lo.pci_address.is_none());
+    }
+
+    /// Set when the test runs inside the namespaces it set up for itself.
+    const INSIDE: &str = "MXA_NETIF_NETNS_TEST";
+
+    fn ip(args: &str) {
+        let status = std::process::Command::new("ip")
+            .args(args.split_whitespace())
+            .status()
+            .unwrap();
+        assert!(status.success(), "ip {}", args);
+    }
+
+    /// Needs root and the dummy, bridge and 8021q modules. Runs itself again in
+    /// fresh network and mount namespaces, with a sysfs of their own, so the
+    /// host's interfaces are left alone.
+    #[test]
+    #[ignore]
+    fn interfaces_in_namespace() {
+        if std::env::var_os(INSIDE).is_none() {
+            let status = std::process::Command::new("unshare")
+                .args(["--net", "--mount", "--propagation", "private", "sh", "-c"])
+                .arg(r#"mount -t sysfs sysfs /sys && exec "$0" "$@""#)
+                .arg(std::env::current_exe().unwrap())
+                .args(["--exact", "netif::tests::interfaces_in_namespace"])
+                .args(["--ignored", "--nocapture"])
+                .env(INSIDE, "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+        ip("link set lo up");
+        ip("link add br0 type bridge");
+        ip("link add d0 type dummy");
+        ip("link set d0 master br0");
+        ip("link add link d0 name d0.42 type vlan id 42");
+        ip("addr add 192.0.2.1/24 dev br0");
+        ip("addr add 2001:db8::1/64 dev br0 nodad");
+        ip("link set d0 up");
+        ip("link set br0 up");
+
+        let interfaces = interfaces_in(Path::new("/"), true);
+        let find = |name: &str| interfaces.iter().find(|i| i.name == name).unwrap();
+        let names: Vec<&str> = interfaces.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["br0", "d0", "d0.42", "lo"]);
+
+        let bridge = find("br0");
+        assert_eq!(bridge.kind.as_deref(), Some("bridge"));
+        assert_eq!(bridge.ipv4, ["192.0.2.1/24"]);
+        assert!(bridge.ipv6.iter().any(|a| a == "2001:db8::1/64"));
+        assert!(bridge.master.is_none());
+
+        let dummy = find("d0");
+        assert_eq!(dummy.master.as_deref(), Some("br0"));
+        assert_eq!(dummy.operstate.as_deref(), Some("unknown"));
+        assert!(dummy.driver.is_none() && dummy.pci_address.is_none());
+
+        let vlan = find("d0.42");
+        assert_eq!(vlan.kind.as_deref(), Some("vlan"));
+        assert_eq!(vlan.vlan_id, Some(42));
+        assert_eq!(vlan.lower, ["d0"]);
+        assert_eq!(vlan.operstate.as_deref(), Some("down"));
+        assert_eq!(vlan.mac, dummy.mac);
+
+        let lo = find("lo");
+        assert_eq!(lo.ipv4, ["127.0.0.1/8"]);
+        assert_eq!(lo.operstate.as_deref(), Some("unknown"));
+    }
+}
@@ -0,0 +1,1040 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Seek, SeekFrom},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, FileTypeExt},
+    },
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+
+use crate::{
+    blockdev,
+    facts::sorted_entries,
+    filesystem::{self, PROBE_SIZE},
+    hostfs,
+    messages::{
+        DiskInspectResponse, DiskSelector, PartitionInfo, PartitionRequest, PartitionResponse,
+        PartitionSize, PartitionTableKind,
+    },
+    telemetry,
+};
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_ENTRY_SIZE: usize = 128;
+const GPT_ENTRIES: u32 = 128;
+const GPT_REVISION: u32 = 0x0001_0000;
+/// Partition names are limited to 36 UTF-16 code units.
+const GPT_NAME_LEN: usize = 36;
+const DEFAULT_ALIGNMENT: u64 = 1024 * 1024;
+/// `_IO(0x12, 95)`, not exported by libc.
+const BLKRRPART: libc::c_ulong = 0x125f;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PROTECTIVE: u8 = 0xee;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Upper bound on logical partitions, in case the EBR chain loops.
+const MAX_LOGICAL: u32 = 128;
+
+/// A block device or disk image opened for partition table access.
+pub(crate) struct Disk {
+    pub file: File,
+    pub size: u64,
+    pub sector_size: u64,
+    pub block_device: bool,
+}
+
+impl Disk {
+    pub(crate) fn open(path: &str, write: bool) -> Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(write).open(path)?;
+        let block_device = file.metadata()?.file_type().is_block_device();
+        let size = file.seek(SeekFrom::End(0))?;
+        let sector_size = if block_device {
+            let mut sector_size: libc::c_int = 0;
+            // SAFETY: BLKSSZGET writes an int to the pointer
+            if unsafe { libc::ioctl(file.as_raw_fd(), libc::BLKSSZGET, &mut sector_size) } < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            sector_size as u64
+        } else {
+            // Images carry no sector size; a GPT header tells where LBA 1 is
+            let mut sig = [0u8; 8];
+            match file.read_exact_at(&mut sig, 4096) {
+                Ok(()) if sig == GPT_SIGNATURE => 4096,
+                _ => 512,
+            }
+        };
+        Ok(Disk {
+            file,
+            size,
+            sector_size,
+            block_device,
+        })
+    }
+
+    pub(crate) fn read(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        if offset
+            .checked_add(len as u64)
+            .is_none_or(|end| end > self.size)
+        {
+            anyhow::bail!("Read of {}+{} past the end of the disk", offset, len);
+        }
+        let mut buf = vec![0u8; len];
+        self.file.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    }
+
+    pub(crate) fn read_sectors(&self, lba: u64, count: u64) -> Result<Vec<u8>> {
+        let offset = lba
+            .checked_mul(self.sector_size)
+            .ok_or_else(|| anyhow::anyhow!("LBA {} is past the end of the disk", lba))?;
+        self.read(offset, (count * self.sector_size) as usize)
+    }
+
+    pub(crate) fn last_lba(&self) -> Result<u64> {
+        (self.size / self.sector_size)
+            .checked_sub(1)
+            .ok_or_else(|| anyhow::anyhow!("Disk of {} bytes holds no whole sector", self.size))
+    }
+
+    /// Ask the kernel to re-read the partition table of a block device.
+    pub(crate) fn reread(&self) -> std::io::Result<()> {
+        // SAFETY: BLKRRPART takes no argument
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), BLKRRPART as _) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Identify the filesystem at `offset`, looking at no more than `len` bytes.
+    fn probe(&self, offset: u64, len: u64) -> Option<crate::messages::FilesystemInfo> {
+        let len = len
+            .min(PROBE_SIZE as u64)
+            .min(self.size.saturating_sub(offset));
+        self.read(offset, len as usize)
+            .ok()
+            .and_then(|buf| filesystem::probe(&buf))
+    }
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap())
+}
+
+/// Format a GUID stored in the mixed-endian GPT layout.
+pub(crate) fn guid_to_string(bytes: &[u8]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{}-{}",
+        u32_at(bytes, 0),
+        u16::from_le_bytes([bytes[4], bytes[5]]),
+        u16::from_le_bytes([bytes[6], bytes[7]]),
+        hex::encode(&bytes[8..10]),
+        hex::encode(&bytes[10..16])
+    )
+}
+
+/// Parse a GUID string into the mixed-endian GPT layout.
+pub(crate) fn parse_guid(guid: &str) -> Result<[u8; 16]> {
+    let hex: String = guid.chars().filter(|c| *c != '-').collect();
+    let bytes = match hex::decode(&hex) {
+        Ok(bytes) if bytes.len() == 16 && guid.len() == 36 => bytes,
+        _ => anyhow::bail!("Invalid GUID: {}", guid),
+    };
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&bytes);
+    out[0..4].reverse();
+    out[4..6].reverse();
+    out[6..8].reverse();
+    Ok(out)
+}
+
+/// A random version 4 GUID in the GPT layout.
+fn random_guid() -> Result<[u8; 16]> {
+    let mut guid = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact_at(&mut guid, 0)?;
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    Ok(guid)
+}
+
+/// Partition type GUIDs that may be given by name.
+const TYPE_ALIASES: &[(&str, &str)] = &[
+    ("efi", "c12a7328-f81f-11d2-ba4b-00a0c93ec93b"),
+    ("bios-boot", "21686148-6449-6e6f-744e-656564454649"),
+    ("linux", "0fc63daf-8483-4772-8e79-3d47149db4e4"),
+    ("root-x86-64", "4f68bce3-e8cd-4db1-96e7-fbcaf984b709"),
+    ("root-aarch64", "b921b045-1df0-41c3-af44-4c6f280d3fae"),
+    ("home", "933ac7e1-2eb4-4f13-b844-0e14e2aef915"),
+    ("swap", "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f"),
+    ("lvm", "e6d6d379-f507-44c2-a23c-238f2a3df928"),
+    ("raid", "a19d880f-05fc-4d3b-a006-743f0f84911e"),
+];
+
+fn parse_type(kind: &str) -> Result<[u8; 16]> {
+    match TYPE_ALIASES.iter().find(|(alias, _)| *alias == kind) {
+        Some((_, guid)) => parse_guid(guid),
+        None => parse_guid(kind),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct GptHeader {
+    pub my_lba: u64,
+    pub alternate_lba: u64,
+    pub first_usable: u64,
+    pub last_usable: u64,
+    pub disk_guid: [u8; 16],
+    pub entries_lba: u64,
+    pub num_entries: u32,
+    pub entry_size: u32,
+    pub entries_crc: u32,
+}
+
+impl GptHeader {
+    fn parse(buf: &[u8]) -> Result<Self> {
+        if !buf.starts_with(GPT_SIGNATURE) {
+            anyhow::bail!("No GPT signature");
+        }
+        let size = u32_at(buf, 12) as usize;
+        if !(GPT_HEADER_SIZE..=buf.len()).contains(&size) {
+            anyhow::bail!("Invalid GPT header size {}", size);
+        }
+        let mut header = buf[..size].to_vec();
+        header[16..20].fill(0);
+        if crc32fast::hash(&header) != u32_at(buf, 16) {
+            anyhow::bail!("GPT header checksum mismatch");
+        }
+        let entry_size = u32_at(buf, 84);
+        if entry_size < GPT_ENTRY_SIZE as u32 || !entry_size.is_multiple_of(8) {
+            anyhow::bail!("Invalid GPT entry size {}", entry_size);
+        }
+        Ok(GptHeader {
+            my_lba: u64_at(buf, 24),
+            alternate_lba: u64_at(buf, 32),
+            first_usable: u64_at(buf, 40),
+            last_usable: u64_at(buf, 48),
+            disk_guid: buf[56..72].try_into().unwrap(),
+            entries_lba: u64_at(buf, 72),
+            num_entries: u32_at(buf, 80),
+            entry_size,
+            entries_crc: u32_at(buf, 88),
+        })
+    }
+
+    fn entries_len(&self) -> usize {
+        self.num_entries as usize * self.entry_size as usize
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(GPT_HEADER_SIZE);
+        buf.extend_from_slice(GPT_SIGNATURE);
+        buf.extend_from_slice(&GPT_REVISION.to_le_bytes());
+        buf.extend_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        buf.extend_from_slice(&[0; 8]);
+        for lba in [
+            self.my_lba,
+            self.alternate_lba,
+            self.first_usable,
+            self.last_usable,
+        ] {
+            buf.extend_from_slice(&lba.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.disk_guid);
+        buf.extend_from_slice(&self.entries_lba.to_le_bytes());
+        buf.extend_from_slice(&self.num_entries.to_le_bytes());
+        buf.extend_from_slice(&self.entry_size.to_le_bytes());
+        buf.extend_from_slice(&self.entries_crc.to_le_bytes());
+        let crc = crc32fast::hash(&buf);
+        buf[16..20].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct GptEntry {
+    pub number: u32,
+    pub type_guid: [u8; 16],
+    pub guid: [u8; 16],
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl GptEntry {
+    fn to_bytes(&self) -> [u8; GPT_ENTRY_SIZE] {
+        let mut buf = [0u8; GPT_ENTRY_SIZE];
+        buf[0..16].copy_from_slice(&self.type_guid);
+        buf[16..32].copy_from_slice(&self.guid);
+        buf[32..40].copy_from_slice(&self.first_lba.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.last_lba.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+        for (i, unit) in self.name.encode_utf16().take(GPT_NAME_LEN).enumerate() {
+            buf[56 + i * 2..58 + i * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        buf
+    }
+
+    fn info(&self, sector_size: u64) -> PartitionInfo {
+        PartitionInfo {
+            number: self.number,
+            start: self.first_lba.saturating_mul(sector_size),
+            // parse_entries drops entries that end before they start
+            size: (self.last_lba - self.first_lba + 1).saturating_mul(sector_size),
+            type_guid: Some(guid_to_string(&self.type_guid)),
+            guid: Some(guid_to_string(&self.guid)),
+            mbr_type: None,
+            name: Some(self.name.clone()).filter(|n| !n.is_empty()),
+            attributes: self.attributes,
+            // Legacy BIOS bootable attribute
+            bootable: self.attributes & 0x4 != 0,
+            filesystem: None,
+        }
+    }
+}
+
+/// Read a GPT header at `lba` along with its partition entry array.
+fn read_gpt_at(disk: &Disk, lba: u64) -> Result<(GptHeader, Vec<u8>)> {
+    let header = GptHeader::parse(&disk.read_sectors(lba, 1)?)?;
+    if header.my_lba != lba {
+        anyhow::bail!(
+            "GPT header at LBA {} claims to be at {}",
+            lba,
+            header.my_lba
+        );
+    }
+    let entries = disk.read(
+        header.entries_lba.saturating_mul(disk.sector_size),
+        header.entries_len(),
+    )?;
+    if crc32fast::hash(&entries) != header.entries_crc {
+        anyhow::bail!("GPT partition entries checksum mismatch");
+    }
+    Ok((header, entries))
+}
+
+/// Used entries of a GPT; those whose range is reversed or outside the usable
+/// area are dropped with a warning.
+fn parse_entries(header: &GptHeader, entries: &[u8], warnings: &mut Vec<String>) -> Vec<GptEntry> {
+    entries
+        .chunks_exact(header.entry_size as usize)
+        .enumerate()
+        .filter(|(_, e)| e[0..16] != [0; 16])
+        .map(|(i, e)| {
+            let name: Vec<u16> = e[56..128]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|c| *c != 0)
+                .collect();
+            GptEntry {
+                number: i as u32 + 1,
+                type_guid: e[0..16].try_into().unwrap(),
+                guid: e[16..32].try_into().unwrap(),
+                first_lba: u64_at(e, 32),
+                last_lba: u64_at(e, 40),
+                attributes: u64_at(e, 48),
+                name: String::from_utf16_lossy(&name),
+            }
+        })
+        .filter(|entry| {
+            let valid = entry.first_lba <= entry.last_lba
+                && entry.first_lba >= header.first_usable
+                && entry.last_lba <= header.last_usable;
+            if !valid {
+                warnings.push(format!(
+                    "Ignoring partition {} at LBA {}-{}, outside the usable LBA {}-{}",
+                    entry.number,
+                    entry.first_lba,
+                    entry.last_lba,
+                    header.first_usable,
+                    header.last_usable
+                ));
+            }
+            valid
+        })
+        .collect()
+}
+
+/// Read the GPT, preferring the primary header and checking it against the backup.
+///
+/// Returns `None` when neither header carries a GPT signature.
+pub(crate) fn read_gpt(
+    disk: &Disk,
+    warnings: &mut Vec<String>,
+) -> Result<Option<(GptHeader, Vec<GptEntry>)>> {
+    // Too small to hold a protective MBR and a header
+    let Some(last_lba) = disk.last_lba().ok().filter(|lba| *lba >= 1) else {
+        return Ok(None);
+    };
+    let has_signature = |lba: u64| {
+        disk.read_sectors(lba, 1)
+            .is_ok_and(|s| s.starts_with(GPT_SIGNATURE))
+    };
+    let primary = read_gpt_at(disk, 1);
+    let backup_lba = match &primary {
+        Ok((header, _)) => header.alternate_lba,
+        Err(_) => last_lba,
+    };
+    let backup = read_gpt_at(disk, backup_lba);
+    match (primary, backup) {
+        (Ok((header, entries)), backup) => {
+            match backup {
+                Ok((backup, backup_entries)) => {
+                    if backup.alternate_lba != 1
+                        || backup.disk_guid != header.disk_guid
+                        || backup_entries != entries
+                    {
+                        warnings.push("Backup GPT does not match the primary".to_string());
+                    }
+                }
+                Err(err) => warnings.push(format!(
+                    "Backup GPT at LBA {} is invalid: {}",
+                    backup_lba, err
+                )),
+            }
+            if header.alternate_lba != last_lba {
+                warnings.push(format!(
+                    "Backup GPT is at LBA {}, not at the end of the disk (LBA {})",
+                    header.alternate_lba, last_lba
+                ));
+            }
+            let parsed = parse_entries(&header, &entries, warnings);
+            Ok(Some((header, parsed)))
+        }
+        (Err(err), Ok((header, entries))) => {
+            warnings.push(format!("Primary GPT is invalid, using the backup: {}", err));
+            let parsed = parse_entries(&header, &entries, warnings);
+            Ok(Some((header, parsed)))
+        }
+        (Err(primary), Err(backup)) => {
+            if has_signature(1) || has_signature(last_lba) {
+                warnings.push(format!(
+                    "GPT signature found but no valid header: {}; backup: {}",
+                    primary, backup
+                ));
+            }
+            Ok(None)
+        }
+    }
+}
+
+struct MbrEntry {
+    bootable: bool,
+    kind: u8,
+    start: u64,
+    sectors: u64,
+}
+
+fn mbr_entries(sector: &[u8]) -> Option<[MbrEntry; 4]> {
+    if sector[510..512] != MBR_SIGNATURE {
+        return None;
+    }
+    let entries = [0, 1, 2, 3].map(|i| {
+        let e = &sector[446 + i * 16..462 + i * 16];
+        MbrEntry {
+            bootable: e[0] == 0x80,
+            kind: e[4],
+            start: u32_at(e, 8) as u64,
+            sectors: u32_at(e, 12) as u64,
+        }
+    });
+    // Boot flags other than 0x00 and 0x80 mean this is not a partition table
+    let valid = (0..4).all(|i| matches!(sector[446 + i * 16], 0x00 | 0x80));
+    valid.then_some(entries)
+}
+
+/// Read an MBR partition table, following the EBR chain of an extended partition.
+fn read_mbr(disk: &Disk, warnings: &mut Vec<String>) -> Result<Option<(u32, Vec<PartitionInfo>)>> {
+    if disk.size < disk.sector_size {
+        return Ok(None);
+    }
+    let mbr = disk.read_sectors(0, 1)?;
+    let Some(entries) = mbr_entries(&mbr) else {
+        return Ok(None);
+    };
+    let ss = disk.sector_size;
+    let mut partitions = Vec::new();
+    let info = |number: u32, entry: &MbrEntry, start: u64| PartitionInfo {
+        number,
+        start: start * ss,
+        size: entry.sectors * ss,
+        type_guid: None,
+        guid: None,
+        mbr_type: Some(entry.kind),
+        name: None,
+        attributes: 0,
+        bootable: entry.bootable,
+        filesystem: disk.probe(start * ss, entry.sectors * ss),
+    };
+    let mut extended = None;
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.kind == 0 || entry.sectors == 0 {
+            continue;
+        }
+        if entry.kind == MBR_PROTECTIVE {
+            warnings.push("Protective MBR without a valid GPT".to_string());
+        }
+        if MBR_EXTENDED.contains(&entry.kind) {
+            extended = Some(entry.start);
+        }
+        partitions.push(info(i as u32 + 1, entry, entry.start));
+    }
+    if let Some(base) = extended {
+        let mut ebr = base;
+        for number in 5..5 + MAX_LOGICAL {
+            let Some([logical, next, ..]) = mbr_entries(&disk.read_sectors(ebr, 1)?) else {
+                warnings.push(format!("Invalid EBR at LBA {}", ebr));
+                break;
+            };
+            if logical.sectors > 0 {
+                partitions.push(info(number, &logical, ebr + logical.start));
+            }
+            if next.sectors == 0 || !MBR_EXTENDED.contains(&next.kind) {
+                break;
+            }
+            ebr = base + next.start;
+        }
+    }
+    Ok(Some((u32_at(&mbr, 440), partitions)))
+}
+
+/// Describe the partition table of a disk and the signatures on its partitions.
+pub(crate) fn inspect(path: &str) -> Result<DiskInspectResponse> {
+    let disk = Disk::open(path, false)?;
+    info!(
+        "Inspecting {} ({} bytes, {} byte sectors)",
+        path, disk.size, disk.sector_size
+    );
+    let mut resp = DiskInspectResponse {
+        size: disk.size,
+        sector_size: disk.sector_size as u32,
+        filesystem: disk.probe(0, disk.size),
+        ..Default::default()
+    };
+    if let Some((header, entries)) = read_gpt(&disk, &mut resp.warnings)? {
+        resp.table = Some(PartitionTableKind::Gpt);
+        resp.disk_id = Some(guid_to_string(&header.disk_guid));
+        resp.partitions = entries
+            .iter()
+            .map(|e| {
+                let mut info = e.info(disk.sector_size);
+                info.filesystem = disk.probe(info.start, info.size);
+                info
+            })
+            .collect();
+    } else if resp.filesystem.is_none()
+        && let Some((id, partitions)) = read_mbr(&disk, &mut resp.warnings)?
+    {
+        // A FAT boot sector also ends in 0x55aa, so only look for an MBR without a filesystem
+        resp.table = Some(PartitionTableKind::Mbr);
+        resp.disk_id = Some(format!("{:08x}", id));
+        resp.partitions = partitions;
+    }
+    debug!(
+        "{}: {:?} with {} partition(s)",
+        path,
+        resp.table,
+        resp.partitions.len()
+    );
+    Ok(resp)
+}
+
+/// Resolve a selector to the path of exactly one disk.
+pub(crate) fn select_disk(selector: &DiskSelector) -> Result<String> {
+    hostfs::ensure_live()?;
+    if let Some(path) = &selector.path {
+        return Ok(path.clone());
+    }
+    let mut matches = Vec::new();
+    for disk in blockdev::disks() {
+        let matched = selector.min_size.is_none_or(|min| disk.size >= min)
+            && selector.max_size.is_none_or(|max| disk.size <= max)
+            && selector
+                .serial
+                .as_ref()
+                .is_none_or(|s| disk.serial.as_ref() == Some(s))
+            && selector
+                .model
+                .as_ref()
+                .is_none_or(|m| disk.model.as_ref().is_some_and(|model| model.contains(m)))
+            && selector.wwn.as_ref().is_none_or(|w| {
+                disk.wwn
+                    .as_ref()
+                    .is_some_and(|wwn| wwn.eq_ignore_ascii_case(w))
+            });
+        if matched {
+            matches.push(disk.path);
+        }
+    }
+    match matches.len() {
+        1 => Ok(matches.remove(0)),
+        0 => anyhow::bail!("No disk matches {:?}", selector),
+        _ => anyhow::bail!("Several disks match {:?}: {}", selector, matches.join(", ")),
+    }
+}
+
+/// Whether the device, or for a disk one of its partitions, is mounted, used
+/// as swap, or held by another device such as a device-mapper target or an md array.
+pub(crate) fn mounted(path: &str) -> bool {
+    let read = |table: &str| std::fs::read_to_string(hostfs::path(table)).unwrap_or_default();
+    in_use(path, &read("/proc/self/mounts"), &read("/proc/swaps"))
+}
+
+fn in_use(path: &str, mounts: &str, swaps: &str) -> bool {
+    let device = hostfs::resolve(path);
+    let mut devices = vec![device.clone()];
+    if let Some(name) = device.strip_prefix("/dev").ok().and_then(|n| n.to_str()) {
+        let block = hostfs::path("/sys/block");
+        let disk = block.join(name);
+        if disk.is_dir() {
+            let partitions = blockdev::partitions(name, &disk);
+            if !blockdev::holders(&disk).is_empty()
+                || partitions.iter().any(|p| !p.holders.is_empty())
+            {
+                return true;
+            }
+            devices.extend(partitions.into_iter().map(|p| PathBuf::from(p.path)));
+        } else if sorted_entries(&block)
+            .into_iter()
+            // A partition lives in the directory of its disk
+            .any(|disk| !blockdev::holders(&block.join(disk).join(name)).is_empty())
+        {
+            return true;
+        }
+    }
+    let mount_sources = mounts.lines().filter_map(|l| l.split(' ').next());
+    // Swap areas are listed below a header line
+    let swap_areas = swaps
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next());
+    mount_sources
+        .chain(swap_areas)
+        .filter(|source| source.starts_with("/dev/"))
+        .any(|source| {
+            let source = String::from_utf8_lossy(&telemetry::unescape(source)).to_string();
+            devices.contains(&hostfs::resolve(source))
+        })
+}
+
+/// Lay out partitions in order, each starting on an alignment boundary.
+fn compute_layout(disk: &Disk, req: &PartitionRequest) -> Result<(GptHeader, Vec<GptEntry>)> {
+    let ss = disk.sector_size;
+    let alignment = req.alignment.unwrap_or(DEFAULT_ALIGNMENT);
+    if alignment == 0 || !alignment.is_multiple_of(ss) {
+        anyhow::bail!(
+            "Alignment {} is not a multiple of the {} byte sector",
+            alignment,
+            ss
+        );
+    }
+    if req.partitions.len() > GPT_ENTRIES as usize {
+        anyhow::bail!("At most {} partitions are supported", GPT_ENTRIES);
+    }
+    let align = alignment / ss;
+    let entries_sectors = (GPT_ENTRIES as u64 * GPT_ENTRY_SIZE as u64).div_ceil(ss);
+    let last_lba = disk.last_lba()?;
+    let first_usable = 2 + entries_sectors;
+    let last_usable = last_lba
+        .checked_sub(1 + entries_sectors)
+        .filter(|l| *l > first_usable)
+        .ok_or_else(|| anyhow::anyhow!("Disk is too small for a GPT"))?;
+    let usable = (last_usable + 1 - first_usable.next_multiple_of(align)) * ss;
+
+    let mut entries = Vec::new();
+    let mut next = first_usable;
+    for (i, spec) in req.partitions.iter().enumerate() {
+        let start = next.next_multiple_of(align);
+        let sectors = match spec.size {
+            PartitionSize::Bytes(bytes) => bytes.div_ceil(ss),
+            PartitionSize::Percent(percent) => {
+                if !(1..=100).contains(&percent) {
+                    anyhow::bail!("Partition {}: invalid percentage {}", i + 1, percent);
+                }
+                usable * percent as u64 / 100 / alignment * align
+            }
+            PartitionSize::Rest => {
+                if i + 1 != req.partitions.len() {
+                    anyhow::bail!(
+                        "Partition {}: only the last partition can take the rest",
+                        i + 1
+                    );
+                }
+                (last_usable + 1).saturating_sub(start)
+            }
+        };
+        if sectors == 0 || start + sectors - 1 > last_usable {
+            anyhow::bail!(
+                "Partition {} of {} sectors at LBA {} does not fit before LBA {}",
+                i + 1,
+                sectors,
+                start,
+                last_usable
+            );
+        }
+        let name = spec.name.clone().unwrap_or_default();
+        if name.encode_utf16().count() > GPT_NAME_LEN {
+            anyhow::bail!(
+                "Partition {}: name longer than {} characters",
+                i + 1,
+                GPT_NAME_LEN
+            );
+        }
+        entries.push(GptEntry {
+            number: i as u32 + 1,
+            type_guid: parse_type(&spec.type_guid)?,
+            guid: match &spec.guid {
+                Some(guid) => parse_guid(guid)?,
+                None => random_guid()?,
+            },
+            first_lba: start,
+            last_lba: start + sectors - 1,
+            attributes: spec.attributes,
+            name,
+        });
+        next = start + sectors;
+    }
+    let header = GptHeader {
+        my_lba: 1,
+        alternate_lba: last_lba,
+        first_usable,
+        last_usable,
+        disk_guid: match &req.disk_guid {
+            Some(guid) => parse_guid(guid)?,
+            None => random_guid()?,
+        },
+        entries_lba: 2,
+        num_entries: GPT_ENTRIES,
+        entry_size: GPT_ENTRY_SIZE as u32,
+        entries_crc: 0,
+    };
+    Ok((header, entries))
+}
+
+/// A protective MBR covering the whole disk, as GPT requires.
+fn protective_mbr(last_lba: u64) -> Vec<u8> {
+    let mut mbr = vec![0u8; 512];
+    let entry = &mut mbr[446..462];
+    entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    entry[4] = MBR_PROTECTIVE;
+    entry[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+    entry[12..16].copy_from_slice(&(last_lba.min(u32::MAX as u64) as u32).to_le_bytes());
+    mbr[510..512].copy_from_slice(&MBR_SIGNATURE);
+    mbr
+}
+
+/// Write a protective MBR, the primary GPT and its backup at the end of the disk.
+fn write_gpt(disk: &Disk, mut header: GptHeader, entries: &[GptEntry]) -> Result<()> {
+    let ss = disk.sector_size;
+    let mut array = vec![0u8; header.entries_len()];
+    for entry in entries {
+        let at = (entry.number as usize - 1) * GPT_ENTRY_SIZE;
+        array[at..at + GPT_ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+    }
+    header.entries_crc = crc32fast::hash(&array);
+    let backup = GptHeader {
+        my_lba: header.alternate_lba,
+        alternate_lba: header.my_lba,
+        entries_lba: header.last_usable + 1,
+        ..header.clone()
+    };
+    let sector = |data: Vec<u8>| {
+        let mut data = data;
+        data.resize(ss as usize, 0);
+        data
+    };
+    // Clear the area before the first partition of any old table or boot code
+    let mut head = vec![0u8; (header.first_usable * ss) as usize];
+    let last_lba = disk.last_lba()?;
+    head[..512].copy_from_slice(&protective_mbr(last_lba));
+    head[ss as usize..2 * ss as usize].copy_from_slice(&sector(header.to_bytes()));
+    head[(2 * ss) as usize..(2 * ss) as usize + array.len()].copy_from_slice(&array);
+    let mut tail = array;
+    tail.resize(((last_lba - backup.entries_lba) * ss) as usize, 0);
+    tail.extend_from_slice(&sector(backup.to_bytes()));
+    disk.file.write_all_at(&tail, backup.entries_lba * ss)?;
+    disk.file.write_all_at(&head, 0)?;
+    disk.file.sync_all()?;
+    Ok(())
+}
+
+/// Write a new GPT from a declarative layout, or only compute it on a dry run.
+pub(crate) fn partition(req: &PartitionRequest) -> Result<PartitionResponse> {
+    let path = select_disk(&req.disk)?;
+    let disk = Disk::open(&path, !req.dry_run)?;
+    let (header, entries) = compute_layout(&disk, req)?;
+    let mut resp = PartitionResponse {
+        disk: path.clone(),
+        disk_guid: Some(guid_to_string(&header.disk_guid)),
+        partitions: entries.iter().map(|e| e.info(disk.sector_size)).collect(),
+        ..Default::default()
+    };
+    if req.dry_run {
+        return Ok(resp);
+    }
+    if mounted(&path) {
+        anyhow::bail!(
+            "{} or one of its partitions is mounted, used as swap or held by another device",
+            path
+        );
+    }
+    info!(
+        "Writing GPT with {} partition(s) to {}",
+        entries.len(),
+        path
+    );
+    write_gpt(&disk, header, &entries)?;
+    resp.written = true;
+    if req.reread && disk.block_device {
+        if let Err(err) = disk.reread() {
+            warn!("Failed to re-read the partition table of {}: {}", path, err);
+            resp.warnings.push(format!(
+                "Kernel did not re-read the partition table: {}",
+                err
+            ));
+        } else {
+            resp.reread = true;
+        }
+    }
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::messages::{FilesystemKind, PartitionSpec};
+
+    const MIB: u64 = 1024 * 1024;
+    const DISK_GUID: &str = "5e3b2f1a-8c4d-4e6f-9a0b-1c2d3e4f5a6b";
+
+    fn image(size: u64) -> NamedTempFile {
+        let file = NamedTempFile::new().unwrap();
+        file.as_file().set_len(size).unwrap();
+        file
+    }
+
+    fn path(file: &NamedTempFile) -> &str {
+        file.path().to_str().unwrap()
+    }
+
+    fn request(partitions: Vec<PartitionSpec>) -> PartitionRequest {
+        PartitionRequest {
+            disk: DiskSelector::default(),
+            partitions,
+            alignment: None,
+            disk_guid: Some(DISK_GUID.to_string()),
+            dry_run: false,
+            reread: false,
+        }
+    }
+
+    fn spec(type_guid: &str, name: Option<&str>, size: PartitionSize) -> PartitionSpec {
+        PartitionSpec {
+            type_guid: type_guid.to_string(),
+            name: name.map(str::to_string),
+            size,
+            guid: None,
+            attributes: 0,
+        }
+    }
+
+    /// An 8 MiB image with an EFI partition and a Linux one taking the rest.
+    fn gpt_image() -> NamedTempFile {
+        let file = image(8 * MIB);
+        let disk = Disk::open(path(&file), true).unwrap();
+        let req = request(vec![
+            spec("efi", Some("EFI"), PartitionSize::Bytes(MIB)),
+            spec("linux", None, PartitionSize::Rest),
+        ]);
+        let (header, entries) = compute_layout(&disk, &req).unwrap();
+        write_gpt(&disk, header, &entries).unwrap();
+        file
+    }
+
+    #[test]
+    fn gpt_roundtrip() {
+        let file = gpt_image();
+        let resp = inspect(path(&file)).unwrap();
+        assert_eq!(resp.table, Some(PartitionTableKind::Gpt));
+        assert_eq!(resp.disk_id.as_deref(), Some(DISK_GUID));
+        assert!(resp.warnings.is_empty(), "{:?}", resp.warnings);
+        assert_eq!(resp.partitions.len(), 2);
+        let (efi, linux) = (&resp.partitions[0], &resp.partitions[1]);
+        assert_eq!((efi.number, efi.start, efi.size), (1, MIB, MIB));
+        assert_eq!(efi.name.as_deref(), Some("EFI"));
+        assert_eq!(
+            efi.type_guid.as_deref(),
+            Some("c12a7328-f81f-11d2-ba4b-00a0c93ec93b")
+        );
+        assert_eq!((linux.number, linux.start), (2, 2 * MIB));
+        // Up to the 33 sectors of the backup entries and header
+        assert_eq!(linux.start + linux.size, 8 * MIB - 33 * 512);
+    }
+
+    #[test]
+    fn gpt_partition_signature() {
+        let file = gpt_image();
+        // An ext2 superblock magic at the start of the second partition
+        let at = 2 * MIB + 1024 + 56;
+        file.as_file()
+            .write_all_at(&0xef53u16.to_le_bytes(), at)
+            .unwrap();
+        let resp = inspect(path(&file)).unwrap();
+        let fs = resp.partitions[1].filesystem.as_ref().unwrap();
+        assert_eq!(fs.kind, FilesystemKind::Ext2);
+        assert!(resp.partitions[0].filesystem.is_none());
+    }
+
+    #[test]
+    fn gpt_falls_back_to_backup() {
+        let file = gpt_image();
+        // Break the primary header checksum
+        file.as_file().write_all_at(b"X", 512 + 40).unwrap();
+        let resp = inspect(path(&file)).unwrap();
+        assert_eq!(resp.table, Some(PartitionTableKind::Gpt));
+        assert_eq!(resp.partitions.len(), 2);
+        assert!(resp.warnings[0].contains("using the backup"));
+    }
+
+    #[test]
+    fn gpt_header_crc() {
+        let header = GptHeader {
+            my_lba: 1,
+            alternate_lba: 16383,
+            first_usable: 34,
+            last_usable: 16350,
+            disk_guid: parse_guid(DISK_GUID).unwrap(),
+            entries_lba: 2,
+            num_entries: GPT_ENTRIES,
+            entry_size: GPT_ENTRY_SIZE as u32,
+            entries_crc: 0,
+        };
+        let mut bytes = header.to_bytes();
+        let parsed = GptHeader::parse(&bytes).unwrap();
+        assert_eq!(parsed.last_usable, 16350);
+        assert_eq!(parsed.disk_guid, header.disk_guid);
+        bytes[48] ^= 1;
+        let err = GptHeader::parse(&bytes).unwrap_err();
+        assert!(err.to_string().contains("checksum"));
+    }
+
+    #[test]
+    fn gpt_ignores_reversed_entry() {
+        let file = image(8 * MIB);
+        let disk = Disk::open(path(&file), true).unwrap();
+        let (header, mut entries) = compute_layout(
+            &disk,
+            &request(vec![spec("linux", None, PartitionSize::Rest)]),
+        )
+        .unwrap();
+        entries[0].last_lba = entries[0].first_lba - 1;
+        write_gpt(&disk, header, &entries).unwrap();
+        let resp = inspect(path(&file)).unwrap();
+        assert_eq!(resp.table, Some(PartitionTableKind::Gpt));
+        assert!(resp.partitions.is_empty());
+        assert!(resp.warnings[0].starts_with("Ignoring partition 1"));
+    }
+
+    #[test]
+    fn images_smaller_than_a_sector() {
+        for size in [0, 100, 511] {
+            let file = image(size);
+            let resp = inspect(path(&file)).unwrap();
+            assert!(resp.table.is_none());
+            assert!(resp.partitions.is_empty());
+            let disk = Disk::open(path(&file), false).unwrap();
+            assert!(disk.last_lba().is_err());
+        }
+    }
+
+    fn mbr_entry(sector: &mut [u8], slot: usize, kind: u8, start: u32, sectors: u32) {
+        let e = &mut sector[446 + slot * 16..462 + slot * 16];
+        e[4] = kind;
+        e[8..12].copy_from_slice(&start.to_le_bytes());
+        e[12..16].copy_from_slice(&sectors.to_le_bytes());
+    }
+
+    #[test]
+    fn mbr_with_logical_partitions() {
+        let file = image(8 * MIB);
+        let mut mbr = [0u8; 512];
+        mbr[440..444].copy_from_slice(&0xdeadbeefu32.to_le_bytes());
+        mbr_entry(&mut mbr, 0, 0x83, 2048, 2048);
+        mbr[446] = 0x80;
+        mbr_entry(&mut mbr, 1, 0x05, 4096, 8192);
+        mbr[510..512].copy_from_slice(&MBR_SIGNATURE);
+        // Two logical partitions; EBR links are relative to the extended partition
+        let mut ebr = [0u8; 512];
+        mbr_entry(&mut ebr, 0, 0x83, 63, 1000);
+        mbr_entry(&mut ebr, 1, 0x05, 2048, 1000);
+        ebr[510..512].copy_from_slice(&MBR_SIGNATURE);
+        let mut last = [0u8; 512];
+        mbr_entry(&mut last, 0, 0x82, 63, 500);
+        last[510..512].copy_from_slice(&MBR_SIGNATURE);
+        let f = file.as_file();
+        f.write_all_at(&mbr, 0).unwrap();
+        f.write_all_at(&ebr, 4096 * 512).unwrap();
+        f.write_all_at(&last, (4096 + 2048) * 512).unwrap();
+
+        let resp = inspect(path(&file)).unwrap();
+        assert_eq!(resp.table, Some(PartitionTableKind::Mbr));
+        assert_eq!(resp.disk_id.as_deref(), Some("deadbeef"));
+        let found: Vec<(u32, u64, u64, Option<u8>)> = resp
+            .partitions
+            .iter()
+            .map(|p| (p.number, p.start / 512, p.size / 512, p.mbr_type))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (1, 2048, 2048, Some(0x83)),
+                (2, 4096, 8192, Some(0x05)),
+                (5, 4096 + 63, 1000, Some(0x83)),
+                (6, 4096 + 2048 + 63, 500, Some(0x82)),
+            ]
+        );
+        assert!(resp.partitions[0].bootable);
+    }
+
+    #[test]
+    fn select_disk_refuses_snapshot_root() {
+        // Tests read a fixture tree, which must never pick a disk to act on
+        let selector = DiskSelector {
+            serial: Some("overlayblk".to_string()),
+            ..Default::default()
+        };
+        assert!(select_disk(&selector).is_err());
+    }
+
+    #[test]
+    fn devices_in_use() {
+        const SWAPS: &str = "Filename\tType\tSize\tUsed\tPriority\n";
+        let mounts = "/dev/disk/by-id/virtio-overlayblk-part1 / ext4 rw 0 0\n";
+        // Links are resolved on both sides, and a disk is in use through its partitions
+        assert!(in_use("/dev/vda1", mounts, SWAPS));
+        assert!(in_use("/dev/disk/by-id/virtio-overlayblk", mounts, SWAPS));
+        let swaps = format!("{}/dev/vda1 partition\t1048572\t0\t-2\n", SWAPS);
+        assert!(in_use("/dev/vda", "", &swaps));
+
+        let unrelated = "proc /proc proc rw 0 0\n/dev/vdb1 /mnt ext4 rw 0 0\n";
+        let swap_file = format!("{}/swapfile file\t1048572\t0\t-2\n", SWAPS);
+        assert!(!in_use("/dev/vda", unrelated, &swap_file));
+        // Only device paths are compared, not a file with the same name
+        assert!(!in_use("/tmp/vda1", mounts, SWAPS));
+
+        // Holders of a partition or of the disk itself
+        assert!(in_use("/dev/loop0", "", SWAPS));
+        assert!(in_use("/dev/loop0p1", "", SWAPS));
+        assert!(in_use("/dev/loop1", "", SWAPS));
+    }
+}
@@ -0,0 +1,306 @@
+use std::{collections::HashMap, path::Path, sync::LazyLock};
+
+use log::{debug, info, warn};
+
+use crate::{
+    facts::{link_name, read_trimmed, sorted_entries},
+    hostfs,
+    messages::PciDevice,
+};
+
+const DEVICES: &str = "/sys/bus/pci/devices";
+/// Where distributions install the database, tried after `MXA_PCI_IDS`.
+const IDS_PATHS: &[&str] = &[
+    "/usr/share/hwdata/pci.ids",
+    "/usr/share/misc/pci.ids",
+    "/usr/share/pci.ids",
+];
+
+/// Class names, so devices are described even without a database.
+const CLASSES: &[(u8, &str)] = &[
+    (0x00, "Unclassified device"),
+    (0x01, "Mass storage controller"),
+    (0x02, "Network controller"),
+    (0x03, "Display controller"),
+    (0x04, "Multimedia controller"),
+    (0x05, "Memory controller"),
+    (0x06, "Bridge"),
+    (0x07, "Communication controller"),
+    (0x08, "Generic system peripheral"),
+    (0x09, "Input device controller"),
+    (0x0a, "Docking station"),
+    (0x0b, "Processor"),
+    (0x0c, "Serial bus controller"),
+    (0x0d, "Wireless controller"),
+    (0x0e, "Intelligent controller"),
+    (0x0f, "Satellite communications controller"),
+    (0x10, "Encryption controller"),
+    (0x11, "Signal processing controller"),
+    (0x12, "Processing accelerators"),
+    (0x13, "Non-Essential Instrumentation"),
+    (0x40, "Coprocessor"),
+    (0xff, "Unassigned class"),
+];
+
+const SUBCLASSES: &[(u8, u8, &str)] = &[
+    (0x01, 0x00, "SCSI storage controller"),
+    (0x01, 0x01, "IDE interface"),
+    (0x01, 0x04, "RAID bus controller"),
+    (0x01, 0x06, "SATA controller"),
+    (0x01, 0x07, "Serial Attached SCSI controller"),
+    (0x01, 0x08, "Non-Volatile memory controller"),
+    (0x01, 0x80, "Mass storage controller"),
+    (0x02, 0x00, "Ethernet controller"),
+    (0x02, 0x07, "Infiniband controller"),
+    (0x02, 0x08, "Fabric controller"),
+    (0x02, 0x80, "Network controller"),
+    (0x03, 0x00, "VGA compatible controller"),
+    (0x03, 0x02, "3D controller"),
+    (0x06, 0x00, "Host bridge"),
+    (0x06, 0x01, "ISA bridge"),
+    (0x06, 0x04, "PCI bridge"),
+    (0x06, 0x80, "Bridge"),
+    (0x08, 0x06, "IOMMU"),
+    (0x0c, 0x03, "USB controller"),
+    (0x0c, 0x04, "Fibre Channel"),
+    (0x0c, 0x05, "SMBus"),
+    (0x0c, 0x06, "InfiniBand"),
+    (0x12, 0x00, "Processing accelerators"),
+];
+
+/// Names from a `pci.ids` database, loaded once on first use.
+static IDS: LazyLock<Option<PciIds>> = LazyLock::new(|| {
+    let configured = std::env::var("MXA_PCI_IDS").ok();
+    let path = configured
+        .iter()
+        .map(String::as_str)
+        .chain(IDS_PATHS.iter().copied())
+        .find(|path| std::path::Path::new(path).is_file())?;
+    match std::fs::read(path) {
+        Ok(content) => {
+            let ids = PciIds::parse(&String::from_utf8_lossy(&content));
+            info!("Loaded {} PCI vendor(s) from {}", ids.vendors.len(), path);
+            Some(ids)
+        }
+        Err(err) => {
+            warn!("Failed to read {}: {}", path, err);
+            None
+        }
+    }
+});
+
+#[derive(Default)]
+struct PciIds {
+    vendors: HashMap<u16, String>,
+    devices: HashMap<(u16, u16), String>,
+    /// Keyed by vendor, device, subsystem vendor and subsystem device.
+    subsystems: HashMap<(u16, u16, u16, u16), String>,
+    classes: HashMap<u8, String>,
+    subclasses: HashMap<(u8, u8), String>,
+}
+
+impl PciIds {
+    /// Parse the tab-indented `pci.ids` format: vendors with their devices and
+    /// subsystems, then `C` lines with subclasses.
+    fn parse(content: &str) -> Self {
+        let mut ids = PciIds::default();
+        let hex16 = |s: &str| u16::from_str_radix(s, 16).ok();
+        let hex8 = |s: &str| u8::from_str_radix(s, 16).ok();
+        let (mut vendor, mut device, mut class) = (None, None, None);
+        for line in content.lines() {
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let depth = line.bytes().take_while(|&b| b == b'\t').count();
+            let line = line.trim_start_matches('\t');
+            let (id, name) = match line.split_once("  ") {
+                Some((id, name)) => (id.trim(), name.trim().to_string()),
+                None => continue,
+            };
+            match depth {
+                0 if let Some(code) = id.strip_prefix("C ") => {
+                    vendor = None;
+                    class = hex8(code);
+                    if let Some(class) = class {
+                        ids.classes.insert(class, name);
+                    }
+                }
+                0 => {
+                    (class, device) = (None, None);
+                    vendor = hex16(id);
+                    if let Some(vendor) = vendor {
+                        ids.vendors.insert(vendor, name);
+                    }
+                }
+                1 => {
+                    if let Some(vendor) = vendor {
+                        device = hex16(id);
+                        if let Some(device) = device {
+                            ids.devices.insert((vendor, device), name);
+                        }
+                    } else if let (Some(class), Some(sub)) = (class, hex8(id)) {
+                        ids.subclasses.insert((class, sub), name);
+                    }
+                }
+                2 => {
+                    let mut parts = id.split_whitespace().map(hex16);
+                    if let (Some(vendor), Some(device), Some(Some(sv)), Some(Some(sd))) =
+                        (vendor, device, parts.next(), parts.next())
+                    {
+                        ids.subsystems.insert((vendor, device, sv, sd), name);
+                    }
+                }
+                _ => {}
+            }
+        }
+        ids
+    }
+}
+
+/// Name of a class code, from the database when it has one.
+fn class_name(ids: Option<&PciIds>, class: u8, subclass: u8) -> Option<String> {
+    let from_ids = ids.and_then(|ids| {
+        ids.subclasses
+            .get(&(class, subclass))
+            .or_else(|| ids.classes.get(&class))
+    });
+    let builtin = || {
+        SUBCLASSES
+            .iter()
+            .find(|(c, s, _)| *c == class && *s == subclass)
+            .map(|(_, _, name)| *name)
+            .or_else(|| CLASSES.iter().find(|(c, _)| *c == class).map(|(_, n)| *n))
+    };
+    from_ids.cloned().or_else(|| builtin().map(str::to_string))
+}
+
+fn read_hex(path: &Path) -> Option<u32> {
+    let value = read_trimmed(path)?;
+    u32::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+}
+
+/// Link speed as sysfs reports it, unless the device has no PCIe link.
+fn link_speed(path: &Path) -> Option<String> {
+    read_trimmed(path).filter(|speed| !speed.starts_with("Unknown"))
+}
+
+/// Every device in `/sys/bus/pci/devices`, with names where a database has them.
+pub(crate) fn devices() -> Vec<PciDevice> {
+    devices_named(IDS.as_ref())
+}
+
+fn devices_named(ids: Option<&PciIds>) -> Vec<PciDevice> {
+    if ids.is_none() {
+        debug!("No pci.ids database, PCI devices are listed without names");
+    }
+    let devices = hostfs::path(DEVICES);
+    sorted_entries(&devices)
+        .into_iter()
+        .filter_map(|address| {
+            let dir = devices.join(&address);
+            let attr = |attr: &str| dir.join(attr);
+            let vendor = read_hex(&attr("vendor"))? as u16;
+            let device = read_hex(&attr("device"))? as u16;
+            let class = read_hex(&attr("class")).unwrap_or(0);
+            let subsystem = read_hex(&attr("subsystem_vendor"))
+                .zip(read_hex(&attr("subsystem_device")))
+                .map(|(sv, sd)| (sv as u16, sd as u16))
+                .filter(|(sv, _)| *sv != 0);
+            let (base, sub) = ((class >> 16) as u8, (class >> 8) as u8);
+            Some(PciDevice {
+                vendor_id: format!("{:04x}", vendor),
+                device_id: format!("{:04x}", device),
+                subsystem_vendor_id: subsystem.map(|(sv, _)| format!("{:04x}", sv)),
+                subsystem_device_id: subsystem.map(|(_, sd)| format!("{:04x}", sd)),
+                class: format!("{:06x}", class),
+                revision: read_hex(&attr("revision")).map(|rev| format!("{:02x}", rev)),
+                vendor: ids.and_then(|ids| ids.vendors.get(&vendor).cloned()),
+                device: ids.and_then(|ids| ids.devices.get(&(vendor, device)).cloned()),
+                subsystem: ids.zip(subsystem).and_then(|(ids, (sv, sd))| {
+                    ids.subsystems.get(&(vendor, device, sv, sd)).cloned()
+                }),
+                class_name: class_name(ids, base, sub),
+                driver: link_name(attr("driver")),
+                // -1 on machines without NUMA
+                numa_node: read_trimmed(attr("numa_node")).and_then(|node| node.parse().ok()),
+                iommu_group: link_name(attr("iommu_group")).and_then(|group| group.parse().ok()),
+                link_speed: link_speed(&attr("current_link_speed")),
+                link_width: read_trimmed(attr("current_link_width"))
+                    .and_then(|width| width.parse().ok())
+                    .filter(|width| *width > 0),
+                max_link_speed: link_speed(&attr("max_link_speed")),
+                max_link_width: read_trimmed(attr("max_link_width"))
+                    .and_then(|width| width.parse().ok())
+                    .filter(|width| *width > 0),
+                address,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDS: &str = "\
+# comment
+1af4  Red Hat, Inc.
+\t1041  Virtio 1.0 network device
+\t\t1af4 1041  QEMU Virtio 1.0 network device
+\t1042  Virtio 1.0 block device
+8086  Intel Corporation
+C 02  Network controller
+\t00  Ethernet controller
+";
+
+    #[test]
+    fn parse_ids() {
+        let ids = PciIds::parse(IDS);
+        assert_eq!(ids.vendors[&0x1af4], "Red Hat, Inc.");
+        assert_eq!(ids.devices[&(0x1af4, 0x1042)], "Virtio 1.0 block device");
+        assert_eq!(
+            ids.subsystems[&(0x1af4, 0x1041, 0x1af4, 0x1041)],
+            "QEMU Virtio 1.0 network device"
+        );
+        assert_eq!(ids.classes[&0x02], "Network controller");
+        assert_eq!(ids.subclasses[&(0x02, 0x00)], "Ethernet controller");
+        // Class lines end the vendor before them
+        assert!(!ids.devices.contains_key(&(0x8086, 0x00)));
+    }
+
+    #[test]
+    fn devices_from_fixture() {
+        let ids = PciIds::parse(IDS);
+        let devices = devices_named(Some(&ids));
+        let addresses: Vec<&str> = devices.iter().map(|d| d.address.as_str()).collect();
+        assert_eq!(addresses, ["0000:00:00.0", "0000:00:02.0", "0000:00:04.0"]);
+        let bridge = &devices[0];
+        assert_eq!(bridge.vendor.as_deref(), Some("Intel Corporation"));
+        assert!(bridge.device.is_none());
+        assert!(bridge.subsystem_vendor_id.is_none());
+        assert_eq!(bridge.class_name.as_deref(), Some("Host bridge"));
+        let net = &devices[2];
+        assert_eq!(net.vendor_id, "1af4");
+        assert_eq!(net.device_id, "1041");
+        assert_eq!(net.class, "020000");
+        assert_eq!(net.revision.as_deref(), Some("01"));
+        assert_eq!(net.device.as_deref(), Some("Virtio 1.0 network device"));
+        assert_eq!(
+            net.subsystem.as_deref(),
+            Some("QEMU Virtio 1.0 network device")
+        );
+        assert_eq!(net.class_name.as_deref(), Some("Ethernet controller"));
+        assert_eq!(net.driver.as_deref(), Some("virtio-pci"));
+    }
+
+    #[test]
+    fn devices_without_database() {
+        let devices = devices_named(None);
+        assert!(devices.iter().all(|d| d.vendor.is_none()));
+        // The built-in class table still names them
+        assert_eq!(
+            devices[1].class_name.as_deref(),
+            Some("Mass storage controller")
+        );
+    }
+}
@@ -0,0 +1,966 @@
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    fs,
+    io::ErrorKind,
+    net::IpAddr,
+    os::unix::fs::{MetadataExt, PermissionsExt, lchown},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::info;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    messages::{
+        InterfaceConfig, NetworkBackend, NetworkConfig, PersonalizeRequest, PersonalizeResponse,
+        UserSpec,
+    },
+    mounts,
+    render::write_atomic,
+};
+
+/// Range new user and group ids are picked from.
+const FIRST_ID: u32 = 1000;
+const LAST_ID: u32 = 59999;
+/// Prefix of the network configuration files written by the agent.
+const NETWORK_PREFIX: &str = "90-mxa";
+/// Symlinks followed while resolving one path, as the kernel allows.
+const MAX_LINKS: usize = 40;
+
+/// An installed root, addressed with absolute paths inside it.
+struct Root {
+    path: PathBuf,
+}
+
+impl Root {
+    fn open(path: PathBuf) -> Result<Self> {
+        if !path.is_dir() {
+            anyhow::bail!("{} is not a directory", path.display());
+        }
+        let root = Root { path };
+        if !root.host("/etc/passwd")?.is_file() {
+            anyhow::bail!(
+                "{} does not look like an installed system: no /etc/passwd",
+                root.path.display()
+            );
+        }
+        Ok(root)
+    }
+
+    /// The host path of `path`, resolved as if the root were `/`: symlinks,
+    /// absolute or relative, and `..` cannot lead outside of it.
+    fn host(&self, path: &str) -> Result<PathBuf> {
+        self.resolve(path, true)
+    }
+
+    /// Like `host`, but a symlink in the last component is left alone, to be
+    /// replaced rather than written through.
+    fn host_entry(&self, path: &str) -> Result<PathBuf> {
+        self.resolve(path, false)
+    }
+
+    fn resolve(&self, path: &str, follow_last: bool) -> Result<PathBuf> {
+        let mut resolved = PathBuf::new();
+        let mut pending: Vec<PathBuf> = vec![PathBuf::from(path)];
+        let mut links = 0;
+        while let Some(next) = pending.pop() {
+            let mut components = next.components();
+            let Some(component) = components.next() else {
+                continue;
+            };
+            let rest = components.as_path();
+            if !rest.as_os_str().is_empty() {
+                pending.push(rest.to_path_buf());
+            }
+            let name = match component {
+                Component::RootDir => {
+                    resolved.clear();
+                    continue;
+                }
+                Component::ParentDir => {
+                    resolved.pop();
+                    continue;
+                }
+                Component::CurDir | Component::Prefix(_) => continue,
+                Component::Normal(name) => name,
+            };
+            let candidate = resolved.join(name);
+            let last = pending.is_empty();
+            let link = fs::symlink_metadata(self.path.join(&candidate))
+                .is_ok_and(|m| m.file_type().is_symlink());
+            if !link || (last && !follow_last) {
+                resolved = candidate;
+                continue;
+            }
+            links += 1;
+            if links > MAX_LINKS {
+                anyhow::bail!("Too many levels of symbolic links in {}", path);
+            }
+            pending.push(fs::read_link(self.path.join(&candidate))?);
+        }
+        Ok(self.path.join(resolved))
+    }
+
+    fn read(&self, path: &str) -> Result<Option<String>> {
+        match fs::read_to_string(self.host(path)?) {
+            Ok(content) => Ok(Some(content)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// A colon separated database such as `/etc/passwd`, kept line by line.
+struct Table {
+    path: &'static str,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    /// Read a table whose rows all have `fields` fields.
+    fn read(root: &Root, path: &'static str, fields: usize) -> Result<Option<Self>> {
+        let Some(content) = root.read(path)? else {
+            return Ok(None);
+        };
+        let mut rows = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let row: Vec<String> = line.split(':').map(String::from).collect();
+            if row.len() != fields {
+                anyhow::bail!(
+                    "Line {} of {} has {} fields instead of {}",
+                    number + 1,
+                    path,
+                    row.len(),
+                    fields
+                );
+            }
+            rows.push(row);
+        }
+        Ok(Some(Table { path, rows }))
+    }
+
+    fn require(root: &Root, path: &'static str, fields: usize) -> Result<Self> {
+        Table::read(root, path, fields)?
+            .ok_or_else(|| anyhow::anyhow!("The target has no {}", path))
+    }
+
+    fn find(&mut self, name: &str) -> Option<&mut Vec<String>> {
+        self.rows.iter_mut().find(|r| r[0] == name)
+    }
+
+    fn ids(&self) -> HashSet<u32> {
+        self.rows
+            .iter()
+            .filter_map(|r| r.get(2).and_then(|id| id.parse().ok()))
+            .collect()
+    }
+
+    fn content(&self) -> String {
+        self.rows.iter().map(|r| r.join(":") + "\n").collect()
+    }
+}
+
+fn free_id(used: &HashSet<u32>) -> Result<u32> {
+    (FIRST_ID..=LAST_ID)
+        .find(|id| !used.contains(id))
+        .ok_or_else(|| anyhow::anyhow!("No free id left"))
+}
+
+/// Append `name` to a comma separated member list.
+fn add_member(members: &mut String, name: &str) -> bool {
+    if members.split(',').any(|m| m == name) {
+        return false;
+    }
+    if !members.is_empty() {
+        members.push(',');
+    }
+    members.push_str(name);
+    true
+}
+
+struct PlannedFile {
+    path: String,
+    content: String,
+    mode: u32,
+    owner: Option<(u32, u32)>,
+}
+
+/// Everything to write, worked out before anything is touched.
+#[derive(Default)]
+struct Plan {
+    /// Directories to create when missing: path, mode and owner.
+    dirs: Vec<(String, u32, (u32, u32))>,
+    /// New home directories to populate from `/etc/skel`.
+    homes: Vec<(String, u32, u32)>,
+    files: Vec<PlannedFile>,
+    changes: Vec<String>,
+}
+
+impl Plan {
+    /// Queue a file unless it already has this content, returning whether it changes.
+    fn file(
+        &mut self,
+        root: &Root,
+        path: &str,
+        content: String,
+        mode: u32,
+        owner: Option<(u32, u32)>,
+    ) -> Result<bool> {
+        if root.read(path)?.as_ref() == Some(&content) {
+            return Ok(false);
+        }
+        self.files.push(PlannedFile {
+            path: path.to_string(),
+            content,
+            mode,
+            owner,
+        });
+        Ok(true)
+    }
+
+    fn apply(self, root: &Root) -> Result<PersonalizeResponse> {
+        for (path, uid, gid) in &self.homes {
+            let home = root.host(path)?;
+            if home.exists() {
+                continue;
+            }
+            fs::create_dir_all(&home)?;
+            fs::set_permissions(&home, fs::Permissions::from_mode(0o750))?;
+            lchown(&home, Some(*uid), Some(*gid))?;
+            copy_tree(&root.host("/etc/skel")?, &home, *uid, *gid)?;
+        }
+        for (path, mode, (uid, gid)) in &self.dirs {
+            let dir = root.host(path)?;
+            if !dir.exists() {
+                fs::create_dir_all(&dir)?;
+                fs::set_permissions(&dir, fs::Permissions::from_mode(*mode))?;
+                lchown(&dir, Some(*uid), Some(*gid))?;
+            }
+        }
+        let mut files = Vec::new();
+        for file in self.files {
+            let host = root.host_entry(&file.path)?;
+            // Existing files keep their mode and owner, symlinks are replaced
+            let existing = fs::symlink_metadata(&host).ok().filter(|m| m.is_file());
+            let mode = existing.as_ref().map_or(file.mode, |m| m.mode() & 0o7777);
+            let owner = file.owner.or(existing.as_ref().map(|m| (m.uid(), m.gid())));
+            fs::create_dir_all(host.parent().unwrap())?;
+            write_atomic(&host, file.content.as_bytes(), mode)?;
+            if let Some((uid, gid)) = owner {
+                lchown(&host, Some(uid), Some(gid))?;
+            }
+            files.push(file.path);
+        }
+        Ok(PersonalizeResponse {
+            changes: self.changes,
+            files,
+            error: None,
+        })
+    }
+}
+
+/// Copy a directory tree, handing everything to `uid` and `gid`.
+fn copy_tree(src: &Path, dst: &Path, uid: u32, gid: u32) -> Result<()> {
+    let entries = match fs::read_dir(src) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let (from, to) = (entry.path(), dst.join(entry.file_name()));
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            fs::create_dir(&to)?;
+            fs::set_permissions(&to, entry.metadata()?.permissions())?;
+            copy_tree(&from, &to, uid, gid)?;
+        } else if kind.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&from)?, &to)?;
+        } else if kind.is_file() {
+            fs::copy(&from, &to)?;
+        } else {
+            continue;
+        }
+        lchown(&to, Some(uid), Some(gid))?;
+    }
+    Ok(())
+}
+
+fn valid_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+fn plan_hostname(root: &Root, plan: &mut Plan, hostname: &str) -> Result<()> {
+    if !valid_hostname(hostname) {
+        anyhow::bail!("Invalid hostname: {}", hostname);
+    }
+    if plan.file(
+        root,
+        "/etc/hostname",
+        format!("{}\n", hostname),
+        0o644,
+        None,
+    )? {
+        plan.changes.push(format!("Set hostname to {}", hostname));
+    }
+    // Debian style mapping of the hostname to 127.0.1.1
+    let short = hostname.split('.').next().unwrap();
+    let entry = if short == hostname {
+        format!("127.0.1.1\t{}", hostname)
+    } else {
+        format!("127.0.1.1\t{} {}", hostname, short)
+    };
+    let hosts = root.read("/etc/hosts")?.unwrap_or_default();
+    let mut found = false;
+    let mut content = String::new();
+    for line in hosts.lines() {
+        if line.split_whitespace().next() == Some("127.0.1.1") {
+            if !found {
+                content.push_str(&entry);
+                content.push('\n');
+            }
+            found = true;
+        } else {
+            content.push_str(line);
+            content.push('\n');
+        }
+    }
+    if !found {
+        content.push_str(&entry);
+        content.push('\n');
+    }
+    if plan.file(root, "/etc/hosts", content, 0o644, None)? {
+        plan.changes
+            .push(format!("Mapped {} to 127.0.1.1 in /etc/hosts", hostname));
+    }
+    Ok(())
+}
+
+fn plan_machine_id(root: &Root, plan: &mut Plan, id: &str) -> Result<()> {
+    let content = if id.is_empty() {
+        String::new()
+    } else if id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit()) {
+        format!("{}\n", id.to_ascii_lowercase())
+    } else {
+        anyhow::bail!("Invalid machine id: {}", id);
+    };
+    if plan.file(root, "/etc/machine-id", content, 0o444, None)? {
+        plan.changes.push(match id {
+            "" => "Cleared the machine id for generation on first boot".to_string(),
+            id => format!("Set machine id to {}", id.to_ascii_lowercase()),
+        });
+    }
+    Ok(())
+}
+
+fn valid_user_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    name.len() <= 32
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+/// Check a value going into a colon separated database.
+fn field(value: &str, what: &str) -> Result<String> {
+    if value.contains([':', '\n']) {
+        anyhow::bail!("Invalid {}: {}", what, value);
+    }
+    Ok(value.to_string())
+}
+
+fn plan_users(root: &Root, plan: &mut Plan, users: &[UserSpec]) -> Result<()> {
+    let mut passwd = Table::require(root, "/etc/passwd", 7)?;
+    let mut shadow = Table::require(root, "/etc/shadow", 9)?;
+    let mut group = Table::require(root, "/etc/group", 4)?;
+    let mut gshadow = Table::read(root, "/etc/gshadow", 4)?;
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86400)
+        .unwrap_or(0)
+        .to_string();
+
+    for user in users {
+        let name = user.name.as_str();
+        if !valid_user_name(name) {
+            anyhow::bail!("Invalid user name: {}", name);
+        }
+        let hash = user
+            .password_hash
+            .as_deref()
+            .map(|h| field(h, "password hash"))
+            .transpose()?;
+        let (home, uid, gid, created) = match passwd.find(name) {
+            Some(row) => {
+                if let Some(uid) = user.uid
+                    && row[2] != uid.to_string()
+                {
+                    anyhow::bail!("User {} exists with uid {}", name, row[2]);
+                }
+                let mut updated = false;
+                for (index, value, what) in [(6, &user.shell, "shell"), (4, &user.gecos, "gecos")] {
+                    if let Some(value) = value
+                        && row[index] != *value
+                    {
+                        row[index] = field(value, what)?;
+                        updated = true;
+                    }
+                }
+                if updated {
+                    plan.changes.push(format!("Updated user {}", name));
+                }
+                (row[5].clone(), row[2].parse()?, row[3].parse()?, false)
+            }
+            None => {
+                let used = passwd.ids();
+                let uid = match user.uid {
+                    Some(uid) if used.contains(&uid) => anyhow::bail!("uid {} is taken", uid),
+                    Some(uid) => uid,
+                    None => free_id(&used)?,
+                };
+                let gid = match group.find(name) {
+                    Some(row) => row[2].parse()?,
+                    None => {
+                        let used = group.ids();
+                        let gid = if used.contains(&uid) {
+                            free_id(&used)?
+                        } else {
+                            uid
+                        };
+                        group.rows.push(vec![
+                            name.into(),
+                            "x".into(),
+                            gid.to_string(),
+                            String::new(),
+                        ]);
+                        if let Some(gshadow) = &mut gshadow {
+                            gshadow.rows.push(vec![
+                                name.into(),
+                                "!".into(),
+                                String::new(),
+                                String::new(),
+                            ]);
+                        }
+                        gid
+                    }
+                };
+                let home = format!("/home/{}", name);
+                passwd.rows.push(vec![
+                    name.into(),
+                    "x".into(),
+                    uid.to_string(),
+                    gid.to_string(),
+                    field(user.gecos.as_deref().unwrap_or(""), "gecos")?,
+                    home.clone(),
+                    field(user.shell.as_deref().unwrap_or("/bin/bash"), "shell")?,
+                ]);
+                plan.homes.push((home.clone(), uid, gid));
+                plan.changes
+                    .push(format!("Created user {} (uid {})", name, uid));
+                (home, uid, gid, true)
+            }
+        };
+
+        match shadow.find(name) {
+            Some(row) => {
+                if let Some(hash) = &hash
+                    && &row[1] != hash
+                {
+                    row[1] = hash.clone();
+                    row[2] = days.clone();
+                    plan.changes.push(format!("Set password of {}", name));
+                }
+            }
+            None => {
+                if !created {
+                    plan.changes.push(format!("Added {} to /etc/shadow", name));
+                }
+                if hash.is_some() {
+                    plan.changes.push(format!("Set password of {}", name));
+                }
+                let hash = hash.clone().unwrap_or_else(|| "!".to_string());
+                let row = [name, &hash, &days, "0", "99999", "7", "", "", ""];
+                shadow.rows.push(row.map(String::from).to_vec());
+            }
+        }
+
+        for member_of in &user.groups {
+            let row = group
+                .find(member_of)
+                .ok_or_else(|| anyhow::anyhow!("Group {} does not exist", member_of))?;
+            if add_member(&mut row[3], name) {
+                plan.changes
+                    .push(format!("Added {} to group {}", name, member_of));
+            }
+            if let Some(row) = gshadow.as_mut().and_then(|g| g.find(member_of)) {
+                add_member(&mut row[3], name);
+            }
+        }
+
+        if !user.authorized_keys.is_empty() {
+            let mut keys = String::new();
+            for key in &user.authorized_keys {
+                if key.contains('\n') {
+                    anyhow::bail!("Invalid SSH key for {}", name);
+                }
+                writeln!(keys, "{}", key.trim())?;
+            }
+            let ssh = format!("{}/.ssh", home.trim_end_matches('/'));
+            plan.dirs.push((ssh.clone(), 0o700, (uid, gid)));
+            let path = format!("{}/authorized_keys", ssh);
+            if plan.file(root, &path, keys, 0o600, Some((uid, gid)))? {
+                plan.changes.push(format!(
+                    "Installed {} SSH key(s) for {}",
+                    user.authorized_keys.len(),
+                    name
+                ));
+            }
+        }
+    }
+
+    for table in [Some(passwd), Some(shadow), Some(group), gshadow]
+        .into_iter()
+        .flatten()
+    {
+        let mode = if table.path.ends_with("shadow") {
+            0o640
+        } else {
+            0o644
+        };
+        plan.file(root, table.path, table.content(), mode, None)?;
+    }
+    Ok(())
+}
+
+fn check_interface(iface: &InterfaceConfig) -> Result<()> {
+    // The name is written verbatim into netplan, networkd and NetworkManager files
+    let name = &iface.name;
+    if name.is_empty()
+        || name.len() > 15
+        || name == "."
+        || name == ".."
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        anyhow::bail!("Invalid interface name: {:?}", name);
+    }
+    if let Some(mac) = &iface.mac {
+        let octets: Vec<&str> = mac.split(':').collect();
+        if octets.len() != 6
+            || !octets
+                .iter()
+                .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
+        {
+            anyhow::bail!("Invalid MAC address: {}", mac);
+        }
+    }
+    for address in &iface.addresses {
+        let valid = address.split_once('/').is_some_and(|(ip, prefix)| {
+            match (ip.parse::<IpAddr>(), prefix.parse::<u8>()) {
+                (Ok(IpAddr::V4(_)), Ok(prefix)) => prefix <= 32,
+                (Ok(IpAddr::V6(_)), Ok(prefix)) => prefix <= 128,
+                _ => false,
+            }
+        });
+        if !valid {
+            anyhow::bail!("Invalid address, expected CIDR notation: {}", address);
+        }
+    }
+    for ip in iface.gateway.iter().chain(&iface.dns) {
+        ip.parse::<IpAddr>()
+            .map_err(|_| anyhow::anyhow!("Invalid IP address: {}", ip))?;
+    }
+    Ok(())
+}
+
+fn is_v6(ip: &str) -> bool {
+    ip.contains(':')
+}
+
+fn netplan(interfaces: &[InterfaceConfig]) -> Result<String> {
+    let mut out = String::from("network:\n  version: 2\n  ethernets:\n");
+    for iface in interfaces {
+        writeln!(out, "    \"{}\":", iface.name)?;
+        if let Some(mac) = &iface.mac {
+            writeln!(out, "      match:\n        macaddress: \"{}\"", mac)?;
+            writeln!(out, "      set-name: \"{}\"", iface.name)?;
+        }
+        writeln!(out, "      dhcp4: {}", iface.dhcp)?;
+        if !iface.addresses.is_empty() {
+            writeln!(out, "      addresses:")?;
+            for address in &iface.addresses {
+                writeln!(out, "        - \"{}\"", address)?;
+            }
+        }
+        if let Some(gateway) = &iface.gateway {
+            let to = if is_v6(gateway) {
+                "\"::/0\""
+            } else {
+                "default"
+            };
+            writeln!(
+                out,
+                "      routes:\n        - to: {}\n          via: \"{}\"",
+                to, gateway
+            )?;
+        }
+        if !iface.dns.is_empty() {
+            writeln!(out, "      nameservers:\n        addresses:")?;
+            for dns in &iface.dns {
+                writeln!(out, "          - \"{}\"", dns)?;
+            }
+        }
+        if let Some(mtu) = iface.mtu {
+            writeln!(out, "      mtu: {}", mtu)?;
+        }
+    }
+    Ok(out)
+}
+
+fn networkd_link(iface: &InterfaceConfig) -> Option<String> {
+    let mac = iface.mac.as_ref()?;
+    Some(format!(
+        "[Match]\nMACAddress={}\n\n[Link]\nName={}\n",
+        mac, iface.name
+    ))
+}
+
+fn networkd(iface: &InterfaceConfig) -> Result<String> {
+    let mut out = format!("[Match]\nName={}\n", iface.name);
+    if let Some(mtu) = iface.mtu {
+        write!(out, "\n[Link]\nMTUBytes={}\n", mtu)?;
+    }
+    out.push_str("\n[Network]\n");
+    if iface.dhcp {
+        out.push_str("DHCP=ipv4\n");
+    }
+    for address in &iface.addresses {
+        writeln!(out, "Address={}", address)?;
+    }
+    if let Some(gateway) = &iface.gateway {
+        writeln!(out, "Gateway={}", gateway)?;
+    }
+    for dns in &iface.dns {
+        writeln!(out, "DNS={}", dns)?;
+    }
+    Ok(out)
+}
+
+/// A stable UUID for a connection, so rewriting it changes nothing.
+fn connection_uuid(name: &str) -> String {
+    let mut hash: [u8; 16] = Sha256::digest(format!("mxa-{}", name))[..16]
+        .try_into()
+        .unwrap();
+    hash[6] = (hash[6] & 0x0f) | 0x40;
+    hash[8] = (hash[8] & 0x3f) | 0x80;
+    let hex = hex::encode(hash);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn network_manager(iface: &InterfaceConfig) -> Result<String> {
+    let id = format!("mxa-{}", iface.name);
+    let mut out = format!(
+        "[connection]\nid={}\nuuid={}\ntype=ethernet\nautoconnect=true\n",
+        id,
+        connection_uuid(&iface.name)
+    );
+    // NetworkManager cannot rename, so a MAC match replaces the name match
+    if iface.mac.is_none() {
+        writeln!(out, "interface-name={}", iface.name)?;
+    }
+    out.push_str("\n[ethernet]\n");
+    if let Some(mac) = &iface.mac {
+        writeln!(out, "mac-address={}", mac)?;
+    }
+    if let Some(mtu) = iface.mtu {
+        writeln!(out, "mtu={}", mtu)?;
+    }
+    for (section, v6) in [("ipv4", false), ("ipv6", true)] {
+        let addresses: Vec<&String> = iface.addresses.iter().filter(|a| is_v6(a) == v6).collect();
+        let method = match (addresses.is_empty(), iface.dhcp, v6) {
+            (false, _, _) => "manual",
+            (true, true, _) => "auto",
+            (true, false, false) => "disabled",
+            (true, false, true) => "ignore",
+        };
+        write!(out, "\n[{}]\nmethod={}\n", section, method)?;
+        for (i, address) in addresses.iter().enumerate() {
+            writeln!(out, "address{}={}", i + 1, address)?;
+        }
+        if let Some(gateway) = iface.gateway.as_ref().filter(|g| is_v6(g) == v6) {
+            writeln!(out, "gateway={}", gateway)?;
+        }
+        let dns: Vec<&String> = iface.dns.iter().filter(|d| is_v6(d) == v6).collect();
+        if !dns.is_empty() {
+            let dns: String = dns.iter().map(|d| format!("{};", d)).collect();
+            writeln!(out, "dns={}", dns)?;
+        }
+    }
+    Ok(out)
+}
+
+fn plan_network(root: &Root, plan: &mut Plan, network: &NetworkConfig) -> Result<()> {
+    for iface in &network.interfaces {
+        check_interface(iface)?;
+    }
+    let backend = match network.backend {
+        Some(backend) => backend,
+        None if root.host("/etc/netplan")?.is_dir() => NetworkBackend::Netplan,
+        None if root.host("/etc/NetworkManager")?.is_dir() => NetworkBackend::NetworkManager,
+        None => NetworkBackend::Networkd,
+    };
+    let mut files = Vec::new();
+    match backend {
+        NetworkBackend::Netplan => {
+            let path = format!("/etc/netplan/{}.yaml", NETWORK_PREFIX);
+            files.push((path, netplan(&network.interfaces)?, 0o600));
+        }
+        NetworkBackend::Networkd => {
+            for iface in &network.interfaces {
+                let base = format!("/etc/systemd/network/{}-{}", NETWORK_PREFIX, iface.name);
+                if let Some(link) = networkd_link(iface) {
+                    files.push((format!("{}.link", base), link, 0o644));
+                }
+                files.push((format!("{}.network", base), networkd(iface)?, 0o644));
+            }
+        }
+        NetworkBackend::NetworkManager => {
+            for iface in &network.interfaces {
+                let path = format!(
+                    "/etc/NetworkManager/system-connections/mxa-{}.nmconnection",
+                    iface.name
+                );
+                files.push((path, network_manager(iface)?, 0o600));
+            }
+        }
+    }
+    for (path, content, mode) in files {
+        if plan.file(root, &path, content, mode, Some((0, 0)))? {
+            plan.changes
+                .push(format!("Wrote {:?} configuration {}", backend, path));
+        }
+    }
+    Ok(())
+}
+
+/// Set the hostname, users, SSH keys, network configuration and machine id of
+/// an installed root. Nothing is written unless the whole request is valid.
+pub(crate) fn personalize(req: &PersonalizeRequest) -> Result<PersonalizeResponse> {
+    let path = match &req.root {
+        Some(root) => PathBuf::from(root),
+        None => mounts::global().root().to_path_buf(),
+    };
+    let root = Root::open(path)?;
+    info!("Personalizing {}", root.path.display());
+    let mut plan = Plan::default();
+    if let Some(hostname) = &req.hostname {
+        plan_hostname(&root, &mut plan, hostname)?;
+    }
+    if let Some(id) = &req.machine_id {
+        plan_machine_id(&root, &mut plan, id)?;
+    }
+    if !req.users.is_empty() {
+        plan_users(&root, &mut plan, &req.users)?;
+    }
+    if let Some(network) = &req.network {
+        plan_network(&root, &mut plan, network)?;
+    }
+    let resp = plan.apply(&root)?;
+    info!(
+        "Personalized {}: {} change(s), {} file(s) written",
+        root.path.display(),
+        resp.changes.len(),
+        resp.files.len()
+    );
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWD: &str = "root:x:0:0:root:/root:/bin/bash\n\
+                          bob:x:1000:1000:Bob:/home/bob:/bin/sh\n";
+    const SHADOW: &str = "root:*:19000:0:99999:7:::\n";
+    const GROUP: &str = "root:x:0:\nsudo:x:27:\nbob:x:1000:\n";
+
+    fn target() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let etc = dir.path().join("etc");
+        fs::create_dir_all(etc.join("skel")).unwrap();
+        fs::write(etc.join("skel/.profile"), "# profile\n").unwrap();
+        fs::write(etc.join("passwd"), PASSWD).unwrap();
+        fs::write(etc.join("shadow"), SHADOW).unwrap();
+        fs::write(etc.join("group"), GROUP).unwrap();
+        dir
+    }
+
+    fn request(root: &Path) -> PersonalizeRequest {
+        PersonalizeRequest {
+            root: Some(root.to_str().unwrap().to_string()),
+            hostname: None,
+            users: Vec::new(),
+            network: None,
+            machine_id: None,
+        }
+    }
+
+    fn user(name: &str) -> UserSpec {
+        UserSpec {
+            name: name.to_string(),
+            uid: None,
+            password_hash: None,
+            groups: Vec::new(),
+            shell: None,
+            gecos: None,
+            authorized_keys: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn resolve_in_root() {
+        let dir = target();
+        let root = Root::open(dir.path().to_path_buf()).unwrap();
+        std::os::unix::fs::symlink("/etc", dir.path().join("abs")).unwrap();
+        std::os::unix::fs::symlink("../../../..", dir.path().join("etc/up")).unwrap();
+        let inside = |path: &str| dir.path().join(path);
+        assert_eq!(root.host("/abs/passwd").unwrap(), inside("etc/passwd"));
+        assert_eq!(root.host("/etc/up/etc/group").unwrap(), inside("etc/group"));
+        assert_eq!(
+            root.host("/../../etc/./shadow").unwrap(),
+            inside("etc/shadow")
+        );
+        assert_eq!(root.host_entry("/etc/up").unwrap(), inside("etc/up"));
+        assert_eq!(root.host("/etc/up").unwrap(), inside(""));
+
+        std::os::unix::fs::symlink("loop", dir.path().join("loop")).unwrap();
+        assert!(root.host("/loop/x").is_err());
+    }
+
+    #[test]
+    fn symlinks_do_not_escape() {
+        let dir = target();
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("hostname"), "outside\n").unwrap();
+        let escape = |link: &str, to: &Path| {
+            std::os::unix::fs::symlink(to, dir.path().join(link)).unwrap();
+        };
+        escape("home", outside.path());
+        escape("etc/hostname", &outside.path().join("hostname"));
+
+        let mut req = request(dir.path());
+        req.hostname = Some("node1".to_string());
+        req.users = vec![UserSpec {
+            authorized_keys: vec!["ssh-ed25519 AAAA alice".to_string()],
+            ..user("alice")
+        }];
+        personalize(&req).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(outside.path().join("hostname")).unwrap(),
+            "outside\n"
+        );
+        assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 1);
+        let hostname = dir.path().join("etc/hostname");
+        assert!(fs::symlink_metadata(&hostname).unwrap().is_file());
+        // The absolute link is followed inside the root
+        let home = dir
+            .path()
+            .join(outside.path().strip_prefix("/").unwrap())
+            .join("alice");
+        assert!(home.join(".profile").is_file());
+        assert!(home.join(".ssh/authorized_keys").is_file());
+    }
+
+    #[test]
+    fn malformed_tables() {
+        let dir = target();
+        fs::write(dir.path().join("etc/passwd"), "root:x:0:0\n").unwrap();
+        let mut req = request(dir.path());
+        req.users = vec![user("alice")];
+        let err = personalize(&req).unwrap_err();
+        assert!(err.to_string().contains("Line 1 of /etc/passwd"), "{}", err);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("etc/shadow")).unwrap(),
+            SHADOW
+        );
+    }
+
+    #[test]
+    fn reports_what_changed() {
+        let dir = target();
+        let mut req = request(dir.path());
+        req.users = vec![UserSpec {
+            shell: Some("/bin/sh".to_string()),
+            gecos: Some("Bob".to_string()),
+            groups: vec!["sudo".to_string()],
+            ..user("bob")
+        }];
+        let resp = personalize(&req).unwrap();
+        assert_eq!(
+            resp.changes,
+            ["Added bob to /etc/shadow", "Added bob to group sudo"]
+        );
+        assert_eq!(resp.files, ["/etc/shadow", "/etc/group"]);
+
+        // Nothing left to do the second time
+        let resp = personalize(&req).unwrap();
+        assert!(resp.changes.is_empty() && resp.files.is_empty());
+
+        req.users[0].shell = Some("/bin/bash".to_string());
+        let resp = personalize(&req).unwrap();
+        assert_eq!(resp.changes, ["Updated user bob"]);
+    }
+
+    #[test]
+    fn interface_names() {
+        let iface = |name: &str| InterfaceConfig {
+            name: name.to_string(),
+            mac: None,
+            dhcp: true,
+            addresses: Vec::new(),
+            gateway: None,
+            dns: Vec::new(),
+            mtu: None,
+        };
+        for name in ["eth0", "enp0s31f6", "br-lan.10", "wg_0"] {
+            assert!(check_interface(&iface(name)).is_ok(), "{}", name);
+        }
+        let long = "a".repeat(16);
+        for name in [
+            "",
+            ".",
+            "..",
+            "eth/0",
+            "eth 0",
+            "eth0:1",
+            "eth0\n[Match]",
+            &long,
+        ] {
+            assert!(check_interface(&iface(name)).is_err(), "{:?}", name);
+        }
+    }
+}
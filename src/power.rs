@@ -0,0 +1,180 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    events,
+    facts::read_trimmed,
+    hostfs,
+    messages::{AgentEventPayload, AgentState, PlannedPower, PowerAction},
+    mounts,
+    render::write_atomic,
+    utils::state_dir,
+};
+
+const PLANNED_FILE: &str = "planned-power.json";
+/// Present when systemd is the init system.
+const SYSTEMD_RUNTIME: &str = "/run/systemd/system";
+/// How long init gets to shut the host down before the agent does it itself.
+const INIT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Set between acknowledging a power action and carrying it out, so the agent
+/// does not reconnect in the meantime.
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// The power action that ended the previous boot, read once at startup.
+static PREVIOUS: LazyLock<Option<PlannedPower>> = LazyLock::new(|| {
+    let path = state_file();
+    let content = std::fs::read(&path).ok()?;
+    if let Err(err) = std::fs::remove_file(&path) {
+        warn!("Failed to remove {}: {}", path.display(), err);
+    }
+    let state: State = match serde_json::from_slice(&content) {
+        Ok(state) => state,
+        Err(err) => {
+            warn!("Ignoring invalid {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    if state.boot_id.is_some() && state.boot_id == boot_id() {
+        warn!(
+            "Planned {:?} from request[id={}] did not happen",
+            state.planned.action, state.planned.request_id
+        );
+        return None;
+    }
+    info!(
+        "Previous boot ended with a planned {:?}",
+        state.planned.action
+    );
+    Some(state.planned)
+});
+
+/// What is kept in the state directory across a power action.
+#[derive(Serialize, Deserialize)]
+struct State {
+    boot_id: Option<String>,
+    planned: PlannedPower,
+}
+
+fn state_file() -> PathBuf {
+    state_dir().join(PLANNED_FILE)
+}
+
+pub(crate) fn boot_id() -> Option<String> {
+    read_trimmed(hostfs::path("/proc/sys/kernel/random/boot_id"))
+}
+
+pub(crate) fn previous() -> Option<PlannedPower> {
+    PREVIOUS.clone()
+}
+
+pub(crate) fn pending() -> bool {
+    PENDING.load(Ordering::Relaxed)
+}
+
+/// Record a power action in the state directory before acknowledging it.
+pub(crate) fn plan(request_id: u64, action: PowerAction) -> Result<()> {
+    // Settle the previous boot's record before it is overwritten
+    LazyLock::force(&PREVIOUS);
+    let state = State {
+        boot_id: boot_id(),
+        planned: PlannedPower {
+            action,
+            request_id,
+            requested_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        },
+    };
+    let path = state_file();
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    write_atomic(&path, &serde_json::to_vec(&state)?, 0o644)?;
+    PENDING.store(true, Ordering::Relaxed);
+    events::emit(AgentEventPayload::StateChanged(AgentState::PowerPending(
+        action,
+    )));
+    Ok(())
+}
+
+/// Forget a planned action that could not be carried out.
+pub(crate) fn abandon() {
+    PENDING.store(false, Ordering::Relaxed);
+    let _ = std::fs::remove_file(state_file());
+    events::emit(AgentEventPayload::StateChanged(AgentState::Ready));
+}
+
+/// Ask systemd as PID 1 for an orderly shutdown, with `systemctl` or else the
+/// signal it documents for the action. Returns whether it accepted.
+async fn ask_systemd(action: PowerAction) -> bool {
+    if std::process::id() == 1 || !std::path::Path::new(SYSTEMD_RUNTIME).is_dir() {
+        return false;
+    }
+    let verb = match action {
+        PowerAction::Reboot => "reboot",
+        PowerAction::Poweroff => "poweroff",
+        PowerAction::Halt => "halt",
+        PowerAction::Kexec => "kexec",
+    };
+    let status = tokio::process::Command::new("systemctl")
+        .args(["--no-block", verb])
+        .stdin(std::process::Stdio::null())
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => return true,
+        Ok(status) => warn!("systemctl {} failed: {}", verb, status),
+        Err(err) => warn!("Failed to run systemctl {}: {}", verb, err),
+    }
+    let signal = match action {
+        PowerAction::Reboot => libc::SIGINT,
+        PowerAction::Poweroff => libc::SIGRTMIN() + 4,
+        PowerAction::Halt => libc::SIGRTMIN() + 3,
+        PowerAction::Kexec => return false,
+    };
+    // SAFETY: kill takes no pointers
+    unsafe { libc::kill(1, signal) == 0 }
+}
+
+/// Unmount the target, flush filesystems and carry out the action after `delay`.
+///
+/// A host booted with systemd is shut down through it, so services stop
+/// cleanly; the kernel is called directly when the agent is PID 1 itself, when
+/// systemd refuses, or when it has not finished in time. Only returns on
+/// failure, after abandoning the plan.
+pub(crate) async fn execute(action: PowerAction, delay: Duration) -> Result<()> {
+    tokio::time::sleep(delay).await;
+    if let Err(err) = mounts::global().unmount(None, true) {
+        warn!("Failed to unmount the target: {}", err);
+    }
+    if ask_systemd(action).await {
+        info!("Handed {:?} to systemd", action);
+        tokio::time::sleep(INIT_TIMEOUT).await;
+        warn!("systemd did not carry out {:?} in time", action);
+    }
+    let cmd = match action {
+        PowerAction::Reboot => libc::LINUX_REBOOT_CMD_RESTART,
+        PowerAction::Poweroff => libc::LINUX_REBOOT_CMD_POWER_OFF,
+        PowerAction::Halt => libc::LINUX_REBOOT_CMD_HALT,
+        PowerAction::Kexec => libc::LINUX_REBOOT_CMD_KEXEC,
+    };
+    info!("Carrying out {:?}", action);
+    // SAFETY: sync and reboot take no pointers
+    unsafe { libc::sync() };
+    if unsafe { libc::reboot(cmd) } < 0 {
+        let err = std::io::Error::last_os_error();
+        abandon();
+        anyhow::bail!("{:?} failed: {}", action, err);
+    }
+    Ok(())
+}
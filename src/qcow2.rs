@@ -0,0 +1,416 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs::File,
+    io::Read,
+    os::unix::fs::FileExt,
+};
+
+use anyhow::Result;
+use log::{debug, warn};
+
+use crate::image::{Sink, read_full};
+
+pub(crate) const MAGIC: &[u8] = b"QFI\xfb";
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const COMPRESSED: u64 = 1 << 62;
+const ZERO: u64 = 1;
+/// Incompatible features we can read: dirty refcounts and the compression type field.
+const KNOWN_INCOMPATIBLE: u64 = 1 | 1 << 3;
+/// Recently passed clusters kept in memory, for compressed data packed across clusters.
+const RECENT_CLUSTERS: usize = 4;
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> u64 {
+    u64::from_be_bytes(buf[pos..pos + 8].try_into().unwrap())
+}
+
+/// What a host cluster turned out to hold, once a table pointing at it was seen.
+#[derive(Debug, Clone, Copy)]
+enum Role {
+    /// Part of the L1 table, starting with entry `first`.
+    L1 {
+        first: u64,
+    },
+    /// An L2 table mapping the guest range starting at `guest`.
+    L2 {
+        guest: u64,
+    },
+    Data {
+        guest: u64,
+    },
+}
+
+/// A compressed cluster, gathered from the stream until complete.
+struct Compressed {
+    len: u64,
+    guest: u64,
+    data: Vec<u8>,
+}
+
+/// Expands a qcow2 image read front to back.
+///
+/// Tables are followed as they stream past. Clusters arriving before anything
+/// points at them are spilled to a temporary file and picked up from there once
+/// they are referenced.
+struct Expander<'s, 'a> {
+    sink: &'s mut Sink<'a>,
+    cluster_bits: u32,
+    cluster_size: u64,
+    size: u64,
+    l1_size: u64,
+    zstd: bool,
+    /// Host offset of the next byte from the stream.
+    pos: u64,
+    roles: HashMap<u64, Vec<Role>>,
+    /// Pending compressed clusters by host offset.
+    compressed: BTreeMap<u64, Vec<Compressed>>,
+    recent: VecDeque<(u64, Vec<u8>)>,
+    spill: Option<File>,
+    spilled: HashSet<u64>,
+}
+
+impl Expander<'_, '_> {
+    fn cluster_of(&self, offset: u64) -> u64 {
+        offset >> self.cluster_bits << self.cluster_bits
+    }
+
+    /// Copy bytes that have already streamed past from memory or the spill file.
+    fn read_passed(&self, mut offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let cluster = self.cluster_of(offset);
+            let within = (offset - cluster) as usize;
+            let want = (buf.len() - done).min(self.cluster_size as usize - within);
+            let out = &mut buf[done..done + want];
+            if let Some((_, data)) = self.recent.iter().find(|(c, _)| *c == cluster) {
+                if data.len() < within + want {
+                    anyhow::bail!("qcow2 image truncated at {:#x}", offset);
+                }
+                out.copy_from_slice(&data[within..within + want]);
+            } else if let Some(spill) = &self.spill
+                && self.spilled.contains(&cluster)
+            {
+                spill.read_exact_at(out, offset)?;
+            } else {
+                anyhow::bail!("qcow2 image refers back to unkept data at {:#x}", offset);
+            }
+            done += want;
+            offset += want as u64;
+        }
+        Ok(())
+    }
+
+    fn register(&mut self, host: u64, role: Role) -> Result<()> {
+        if host < self.pos {
+            debug!("qcow2 cluster {:#x} arrived before its reference", host);
+            let mut buf = vec![0u8; self.cluster_size as usize];
+            self.read_passed(host, &mut buf)?;
+            return self.apply(role, &buf);
+        }
+        self.roles.entry(host).or_default().push(role);
+        Ok(())
+    }
+
+    fn register_compressed(&mut self, host: u64, len: u64, guest: u64) -> Result<()> {
+        let mut data = vec![0u8; (self.pos.saturating_sub(host)).min(len) as usize];
+        self.read_passed(host, &mut data)?;
+        let entry = Compressed { len, guest, data };
+        if entry.data.len() as u64 == len {
+            return self.inflate(entry);
+        }
+        self.compressed.entry(host).or_default().push(entry);
+        Ok(())
+    }
+
+    fn apply(&mut self, role: Role, buf: &[u8]) -> Result<()> {
+        match role {
+            Role::L1 { first } => {
+                let span = self.cluster_size << (self.cluster_bits - 3);
+                let last = self.l1_size.min(first + buf.len() as u64 / 8);
+                for index in first..last {
+                    let guest = index * span;
+                    if guest >= self.size {
+                        break;
+                    }
+                    let entry = u64_at(buf, ((index - first) * 8) as usize) & OFFSET_MASK;
+                    if entry == 0 {
+                        self.sink.hole(guest, span.min(self.size - guest));
+                    } else {
+                        self.register(entry, Role::L2 { guest })?;
+                    }
+                }
+            }
+            Role::L2 { guest: base } => {
+                let sector_bits = self.cluster_bits - 8;
+                let offset_bits = 62 - sector_bits;
+                for index in 0..buf.len() / 8 {
+                    let guest = base + index as u64 * self.cluster_size;
+                    if guest >= self.size {
+                        break;
+                    }
+                    let entry = u64_at(buf, index * 8);
+                    if entry & COMPRESSED != 0 {
+                        let host = entry & ((1 << offset_bits) - 1);
+                        let sectors = (entry >> offset_bits) & ((1 << sector_bits) - 1);
+                        let len = (sectors + 1) * 512 - (host & 511);
+                        self.register_compressed(host, len, guest)?;
+                    } else if entry & ZERO != 0 || entry & OFFSET_MASK == 0 {
+                        self.sink
+                            .hole(guest, self.cluster_size.min(self.size - guest));
+                    } else {
+                        self.register(entry & OFFSET_MASK, Role::Data { guest })?;
+                    }
+                }
+            }
+            Role::Data { guest } => {
+                let len = self.cluster_size.min(self.size - guest) as usize;
+                if buf.len() < len {
+                    anyhow::bail!("qcow2 image truncated in data for {:#x}", guest);
+                }
+                self.sink.write(guest, &buf[..len])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn inflate(&mut self, entry: Compressed) -> Result<()> {
+        let mut out = vec![0u8; self.cluster_size as usize];
+        if self.zstd {
+            let mut decoder =
+                zstd::stream::read::Decoder::with_buffer(&entry.data[..])?.single_frame();
+            decoder.read_exact(&mut out)?;
+        } else {
+            let mut inflater = flate2::Decompress::new(false);
+            inflater.decompress(&entry.data, &mut out, flate2::FlushDecompress::Finish)?;
+            if inflater.total_out() != self.cluster_size {
+                anyhow::bail!(
+                    "Compressed cluster for {:#x} inflated to {} bytes",
+                    entry.guest,
+                    inflater.total_out()
+                );
+            }
+        }
+        let len = self.cluster_size.min(self.size - entry.guest) as usize;
+        self.sink.write(entry.guest, &out[..len])
+    }
+
+    /// Feed a cluster to the compressed clusters overlapping it, returning whether
+    /// they cover it entirely.
+    fn feed_compressed(&mut self, start: u64, buf: &[u8]) -> Result<bool> {
+        let end = start + buf.len() as u64;
+        let from = start.saturating_sub(2 * self.cluster_size);
+        let hosts: Vec<u64> = self.compressed.range(from..end).map(|(h, _)| *h).collect();
+        let mut ranges = Vec::new();
+        for host in hosts {
+            let entries = self.compressed.remove(&host).unwrap_or_default();
+            let mut pending = Vec::new();
+            for mut entry in entries {
+                let have = host + entry.data.len() as u64;
+                let until = end.min(host + entry.len);
+                if have >= start && have < until {
+                    entry
+                        .data
+                        .extend_from_slice(&buf[(have - start) as usize..(until - start) as usize]);
+                    ranges.push((have, until));
+                }
+                if entry.data.len() as u64 == entry.len {
+                    self.inflate(entry)?;
+                } else {
+                    pending.push(entry);
+                }
+            }
+            if !pending.is_empty() {
+                self.compressed.insert(host, pending);
+            }
+        }
+        ranges.sort_unstable();
+        let mut covered = start;
+        for (from, until) in ranges {
+            if from > covered {
+                break;
+            }
+            covered = covered.max(until);
+        }
+        Ok(covered >= end)
+    }
+
+    fn process(&mut self, start: u64, buf: Vec<u8>) -> Result<()> {
+        self.pos = start + buf.len() as u64;
+        let covered = self.feed_compressed(start, &buf)?;
+        let roles = self.roles.remove(&start).unwrap_or_default();
+        if self.recent.len() == RECENT_CLUSTERS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((start, buf.clone()));
+        for role in &roles {
+            self.apply(*role, &buf)?;
+        }
+        if roles.is_empty() && !covered {
+            let spill = match &mut self.spill {
+                Some(spill) => spill,
+                None => self.spill.insert(tempfile::tempfile()?),
+            };
+            spill.write_all_at(&buf, start)?;
+            self.spilled.insert(start);
+        }
+        Ok(())
+    }
+
+    fn finished(&self) -> bool {
+        self.roles.is_empty() && self.compressed.is_empty()
+    }
+}
+
+/// Expand a qcow2 image onto the sink, returning the size of the disk.
+///
+/// Standard, zero and unallocated clusters are supported, as are zlib and zstd
+/// compressed ones; images with backing files, encryption or external data are not.
+pub(crate) fn expand(reader: &mut impl Read, sink: &mut Sink) -> Result<u64> {
+    let mut header = vec![0u8; 1 << MIN_CLUSTER_BITS];
+    if read_full(reader, &mut header)? != header.len() || !header.starts_with(MAGIC) {
+        anyhow::bail!("Not a qcow2 image");
+    }
+    let version = u32_at(&header, 4);
+    let cluster_bits = u32_at(&header, 20);
+    if !(2..=3).contains(&version) {
+        anyhow::bail!("Unsupported qcow2 version {}", version);
+    }
+    if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+        anyhow::bail!("Invalid qcow2 cluster size 2^{}", cluster_bits);
+    }
+    if u64_at(&header, 8) != 0 {
+        anyhow::bail!("qcow2 images with a backing file are not supported");
+    }
+    if u32_at(&header, 32) != 0 {
+        anyhow::bail!("Encrypted qcow2 images are not supported");
+    }
+    let cluster_size = 1u64 << cluster_bits;
+    header.resize(cluster_size as usize, 0);
+    if read_full(reader, &mut header[1 << MIN_CLUSTER_BITS..])? as u64
+        != cluster_size - (1 << MIN_CLUSTER_BITS)
+    {
+        anyhow::bail!("qcow2 image truncated in header");
+    }
+    let mut zstd = false;
+    if version == 3 {
+        let incompatible = u64_at(&header, 72);
+        if incompatible & !KNOWN_INCOMPATIBLE != 0 {
+            anyhow::bail!("Unsupported qcow2 features {:#x}", incompatible);
+        }
+        if incompatible & 1 != 0 {
+            warn!("qcow2 image was not closed cleanly");
+        }
+        let header_len = u32_at(&header, 100);
+        if incompatible & 1 << 3 != 0 && header_len > 104 {
+            zstd = match header[104] {
+                0 => false,
+                1 => true,
+                other => anyhow::bail!("Unknown qcow2 compression type {}", other),
+            };
+        }
+    }
+    let size = u64_at(&header, 24);
+    let l1_size = u32_at(&header, 36) as u64;
+    let l1_offset = u64_at(&header, 40);
+    if !l1_offset.is_multiple_of(cluster_size) || (l1_size > 0 && l1_offset == 0) {
+        anyhow::bail!("Invalid qcow2 L1 table offset {:#x}", l1_offset);
+    }
+    debug!(
+        "qcow2 v{}: {} bytes, {} byte clusters, {} L1 entries at {:#x}",
+        version, size, cluster_size, l1_size, l1_offset
+    );
+
+    let mut expander = Expander {
+        sink,
+        cluster_bits,
+        cluster_size,
+        size,
+        l1_size,
+        zstd,
+        pos: cluster_size,
+        roles: HashMap::new(),
+        compressed: BTreeMap::new(),
+        recent: VecDeque::new(),
+        spill: None,
+        spilled: HashSet::new(),
+    };
+    let per_cluster = cluster_size / 8;
+    for first in (0..l1_size).step_by(per_cluster as usize) {
+        expander.register(l1_offset + first * 8, Role::L1 { first })?;
+    }
+    while !expander.finished() {
+        let start = expander.pos;
+        let mut buf = vec![0u8; cluster_size as usize];
+        let len = read_full(reader, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+        buf.truncate(len);
+        expander.process(start, buf)?;
+    }
+    // Compressed sizes are rounded up and may run past the end of the file
+    for (_, entries) in std::mem::take(&mut expander.compressed) {
+        for entry in entries {
+            expander.inflate(entry)?;
+        }
+    }
+    if let Some(host) = expander.roles.keys().min() {
+        anyhow::bail!("qcow2 image truncated, cluster {:#x} is missing", host);
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::expand_to_vec;
+
+    /// Eight 4 KiB guest clusters, the last one half inside the disk: raw data
+    /// (one stored ahead of the L2 table pointing at it), a zero-flagged cluster,
+    /// unallocated ones, and two compressed clusters packed back to back.
+    const RAW: &[u8] = include_bytes!("../tests/fixtures/images/disk.raw");
+    const ZLIB: &[u8] = include_bytes!("../tests/fixtures/images/disk-zlib.qcow2");
+    const ZSTD: &[u8] = include_bytes!("../tests/fixtures/images/disk-zstd.qcow2");
+
+    fn expand_image(image: &[u8]) -> Result<Vec<u8>> {
+        expand_to_vec(|sink| {
+            let size = expand(&mut &image[..], sink)?;
+            assert_eq!(size, RAW.len() as u64);
+            Ok(size)
+        })
+    }
+
+    #[test]
+    fn zlib_clusters() {
+        assert!(expand_image(ZLIB).unwrap() == RAW);
+    }
+
+    #[test]
+    fn zstd_clusters() {
+        assert!(expand_image(ZSTD).unwrap() == RAW);
+    }
+
+    #[test]
+    fn truncated_image() {
+        // Cut inside the raw cluster stored after the L2 table
+        let err = expand_image(&ZLIB[..6 * 4096 + 100]).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+    }
+
+    #[test]
+    fn unsupported_images() {
+        assert!(expand_image(&RAW[..4096]).is_err());
+        let mut backing = ZLIB.to_vec();
+        backing[8..16].copy_from_slice(&512u64.to_be_bytes());
+        let err = expand_image(&backing).unwrap_err();
+        assert!(err.to_string().contains("backing file"));
+        let mut compression = ZSTD.to_vec();
+        compression[104] = 7;
+        assert!(expand_image(&compression).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    fs,
+    io::{ErrorKind, Write},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+};
+
+use anyhow::Result;
+use log::info;
+use minijinja::Environment;
+use similar::TextDiff;
+
+use crate::{facts, messages::RenderFileResponse};
+
+/// Write a file by renaming a fully written sibling over it.
+pub(crate) fn write_atomic(path: &Path, content: &[u8], mode: u32) -> Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid path: {}", path.display()))?;
+    let tmp = path.with_file_name(format!(".{}.mxa-tmp", name.to_string_lossy()));
+    // A leftover temporary file, or a symlink planted in its place, is not reused
+    let _ = fs::remove_file(&tmp);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)?;
+    file.write_all(content)?;
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    file.sync_all()?;
+    if let Err(err) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(err.into());
+    }
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Render a template with controller variables and host facts, then write it atomically.
+///
+/// Variables are available at the top level and facts as `facts`. The file is only
+/// rewritten when the content changes; its mode is kept, or `mode` is used for new files.
+pub(crate) fn render_file(
+    template: &str,
+    variables: &serde_json::Map<String, serde_json::Value>,
+    path: &str,
+    mode: Option<u32>,
+    dry_run: bool,
+) -> Result<RenderFileResponse> {
+    info!("Rendering template into {}", path);
+    let mut env = Environment::new();
+    env.set_undefined_behavior(minijinja::UndefinedBehavior::Strict);
+    env.set_keep_trailing_newline(true);
+    env.add_template("template", template)?;
+    let mut context = variables.clone();
+    context.insert("facts".to_string(), serde_json::to_value(facts::collect())?);
+    let rendered = env.get_template("template")?.render(&context)?;
+
+    let (old, old_mode) = match fs::read_to_string(path) {
+        Ok(old) => (old, Some(fs::metadata(path)?.permissions().mode() & 0o7777)),
+        Err(err) if err.kind() == ErrorKind::NotFound => (String::new(), None),
+        Err(err) => return Err(err.into()),
+    };
+    let changed = old_mode.is_none() || old != rendered;
+    let diff = TextDiff::from_lines(&old, &rendered)
+        .unified_diff()
+        .header(path, path)
+        .to_string();
+    if changed && !dry_run {
+        let mode = old_mode.or(mode).unwrap_or(0o644);
+        write_atomic(Path::new(path), rendered.as_bytes(), mode)?;
+    }
+    Ok(RenderFileResponse {
+        changed,
+        diff,
+        error: None,
+    })
+}
@@ -0,0 +1,335 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    os::unix::fs::FileExt,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use reqwest::{StatusCode, header};
+use tokio::task::JoinSet;
+
+use crate::{throttle::Throttle, utils::download_file};
+
+/// Segments are fetched in pieces of this size, so fast connections take on more of them.
+const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+/// A mirror failing this many times is not used for further segments.
+const MAX_MIRROR_FAILURES: u32 = 3;
+
+struct Mirror {
+    url: String,
+    failures: AtomicU32,
+}
+
+impl Mirror {
+    fn healthy(&self) -> bool {
+        self.failures.load(Ordering::Relaxed) < MAX_MIRROR_FAILURES
+    }
+}
+
+/// A mirror serving a file of another size than the one being assembled.
+#[derive(Debug)]
+struct SizeMismatch {
+    expected: u64,
+    actual: Option<u64>,
+}
+
+impl std::fmt::Display for SizeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.actual {
+            Some(actual) => write!(f, "file is {} bytes, expected {}", actual, self.expected),
+            None => write!(f, "file size not announced, expected {}", self.expected),
+        }
+    }
+}
+
+impl std::error::Error for SizeMismatch {}
+
+/// Parse a `Content-Range` header such as `bytes 0-0/1234` into the range and
+/// the complete length, which may be unknown (`*`).
+fn content_range(response: &reqwest::Response) -> Option<(u64, u64, Option<u64>)> {
+    let value = response
+        .headers()
+        .get(header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?;
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let total = match total {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    Some((start.parse().ok()?, end.parse().ok()?, total))
+}
+
+/// Find the size of the file and check that the server honours range requests.
+async fn probe(client: &reqwest::Client, url: &str) -> Result<Option<u64>> {
+    let response = client
+        .get(url)
+        .header(header::RANGE, "bytes=0-0")
+        .send()
+        .await?;
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        if !response.status().is_success() {
+            anyhow::bail!("Server returned {} for {}", response.status(), url);
+        }
+        return Ok(None);
+    }
+    Ok(content_range(&response).and_then(|(_, _, total)| total))
+}
+
+async fn fetch_range(
+    client: &reqwest::Client,
+    url: &str,
+    out: &File,
+    (start, len, size): (u64, u64, u64),
+    throttle: &Throttle,
+) -> Result<()> {
+    let mut response = client
+        .get(url)
+        .header(
+            header::RANGE,
+            format!("bytes={}-{}", start, start + len - 1),
+        )
+        .send()
+        .await?;
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        anyhow::bail!("Server returned {} for a range request", response.status());
+    }
+    let range = content_range(&response);
+    if range.is_none_or(|(_, _, total)| total != Some(size)) {
+        return Err(SizeMismatch {
+            expected: size,
+            actual: range.and_then(|(_, _, total)| total),
+        }
+        .into());
+    }
+    if range.is_some_and(|(first, last, _)| (first, last) != (start, start + len - 1)) {
+        anyhow::bail!("Server sent another range than requested");
+    }
+    let mut pos = start;
+    while let Some(chunk) = response.chunk().await? {
+        if pos + chunk.len() as u64 > start + len {
+            anyhow::bail!("Server sent more data than requested");
+        }
+        throttle.consume(chunk.len()).await;
+        out.write_all_at(&chunk, pos)?;
+        pos += chunk.len() as u64;
+    }
+    if pos != start + len {
+        anyhow::bail!("Range ended after {} of {} bytes", pos - start, len);
+    }
+    Ok(())
+}
+
+/// Fetch one segment of a file of `size` bytes, walking the mirror list in
+/// order until one succeeds. A mirror with a file of another size is not used again.
+async fn fetch_segment(
+    client: &reqwest::Client,
+    mirrors: &[Mirror],
+    out: &File,
+    (start, len, size): (u64, u64, u64),
+    throttle: &Throttle,
+) -> Result<()> {
+    let mut last_err = None;
+    for mirror in mirrors.iter().filter(|m| m.healthy()) {
+        match fetch_range(client, &mirror.url, out, (start, len, size), throttle).await {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                warn!(
+                    "Failed to fetch bytes {}+{} from {}: {}",
+                    start, len, mirror.url, err
+                );
+                if err.is::<SizeMismatch>() {
+                    mirror
+                        .failures
+                        .store(MAX_MIRROR_FAILURES, Ordering::Relaxed);
+                } else {
+                    mirror.failures.fetch_add(1, Ordering::Relaxed);
+                }
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow::anyhow!("All mirrors failed")))
+}
+
+/// Download a file from an ordered list of mirrors, fetching byte ranges concurrently.
+///
+/// Falls back to a plain download, mirror by mirror, when no mirror supports range requests.
+pub(crate) async fn download_segmented(
+    urls: &[String],
+    path: &str,
+    parallelism: u32,
+    throttle: &Throttle,
+) -> Result<()> {
+    info!(
+        "Downloading {} from {} mirror(s) with {} connection(s)",
+        path,
+        urls.len(),
+        parallelism
+    );
+    let client = reqwest::Client::new();
+    let mut size = None;
+    for url in urls {
+        match probe(&client, url).await {
+            Ok(Some(s)) => {
+                size = Some(s);
+                break;
+            }
+            Ok(None) => debug!("{} does not support range requests", url),
+            Err(err) => warn!("Failed to probe {}: {}", url, err),
+        }
+    }
+    let size = match size {
+        Some(size) if parallelism > 1 => size,
+        _ => {
+            let mut last_err = anyhow::anyhow!("No mirror given");
+            for url in urls {
+                match download_file(url, path, throttle).await {
+                    Ok(()) => return Ok(()),
+                    Err(err) => {
+                        warn!("Failed to download from {}: {}", url, err);
+                        last_err = err;
+                    }
+                }
+            }
+            return Err(last_err);
+        }
+    };
+
+    let out = Arc::new(File::create(path)?);
+    out.set_len(size)?;
+    let segments: VecDeque<(u64, u64)> = (0..size)
+        .step_by(SEGMENT_SIZE as usize)
+        .map(|start| (start, SEGMENT_SIZE.min(size - start)))
+        .collect();
+    let queue = Arc::new(Mutex::new(segments));
+    let mirrors: Arc<Vec<Mirror>> = Arc::new(
+        urls.iter()
+            .map(|url| Mirror {
+                url: url.clone(),
+                failures: AtomicU32::new(0),
+            })
+            .collect(),
+    );
+
+    let mut workers: JoinSet<Result<()>> = JoinSet::new();
+    for _ in 0..parallelism {
+        let (client, out, queue, mirrors, throttle) = (
+            client.clone(),
+            out.clone(),
+            queue.clone(),
+            mirrors.clone(),
+            throttle.clone(),
+        );
+        workers.spawn(async move {
+            loop {
+                let segment = queue.lock().unwrap().pop_front();
+                let Some((start, len)) = segment else {
+                    return Ok(());
+                };
+                fetch_segment(&client, &mirrors, &out, (start, len, size), &throttle).await?;
+            }
+        });
+    }
+    while let Some(result) = workers.join_next().await {
+        if let Err(err) = result? {
+            workers.abort_all();
+            return Err(err);
+        }
+    }
+    out.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serve `data` with range support, announcing `range_total` as the length
+    /// in every answer but the `bytes=0-0` probe. Returns the URL and a
+    /// counter of requests.
+    async fn serve(data: Arc<Vec<u8>>, range_total: usize) -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                let data = data.clone();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0u8];
+                        if stream.read(&mut byte).await.unwrap() == 0 {
+                            return;
+                        }
+                        head.push(byte[0]);
+                    }
+                    let head = String::from_utf8(head).unwrap().to_ascii_lowercase();
+                    let range = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("range: bytes="))
+                        .unwrap();
+                    let (start, end) = range.split_once('-').unwrap();
+                    let (start, end): (usize, usize) =
+                        (start.parse().unwrap(), end.parse().unwrap());
+                    let total = if (start, end) == (0, 0) {
+                        data.len()
+                    } else {
+                        range_total
+                    };
+                    let header = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n",
+                        start,
+                        end,
+                        total,
+                        end + 1 - start
+                    );
+                    stream.write_all(header.as_bytes()).await.unwrap();
+                    stream.write_all(&data[start..=end]).await.unwrap();
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn mirror_with_another_size_is_dropped() {
+        let data: Arc<Vec<u8>> = Arc::new((0..40 << 20).map(|i| (i % 251) as u8).collect());
+        // Probes fine, then turns out to serve a larger file
+        let (changed, changed_requests) = serve(data.clone(), data.len() + 1).await;
+        let (good, _) = serve(data.clone(), data.len()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let path = path.to_str().unwrap();
+        download_segmented(&[changed, good], path, 2, &Throttle::new(None))
+            .await
+            .unwrap();
+        assert!(std::fs::read(path).unwrap() == *data);
+        // The probe, and one range from each of the two workers at most
+        assert!(changed_requests.load(Ordering::Relaxed) <= 3);
+    }
+
+    #[tokio::test]
+    async fn all_mirrors_with_another_size() {
+        let data = Arc::new(vec![1u8; 1024]);
+        let (changed, _) = serve(data, 4096).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let err = download_segmented(&[changed], path.to_str().unwrap(), 2, &Throttle::new(None))
+            .await
+            .unwrap_err();
+        assert!(err.is::<SizeMismatch>(), "{}", err);
+    }
+}
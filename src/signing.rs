@@ -0,0 +1,89 @@
+use std::sync::LazyLock;
+
+use anyhow::Result;
+use ed25519_dalek::{Signer, SigningKey};
+use log::{info, warn};
+
+use crate::messages::SignedSummary;
+
+/// Key used to sign reports, read from the file named by `MXA_SIGNING_KEY`
+/// holding a 32 byte seed, raw or hex encoded. Without it reports go out unsigned.
+static KEY: LazyLock<Result<Option<SigningKey>, String>> = LazyLock::new(|| {
+    let Ok(path) = std::env::var("MXA_SIGNING_KEY") else {
+        warn!("MXA_SIGNING_KEY is not set, reports are not signed");
+        return Ok(None);
+    };
+    let key = load(&path).map_err(|err| format!("Failed to load signing key {}: {}", path, err))?;
+    info!("Signing with the key from {}", path);
+    Ok(Some(key))
+});
+
+fn load(path: &str) -> Result<SigningKey> {
+    let content = std::fs::read(path)?;
+    let seed = match content.len() {
+        32 => content,
+        _ => hex::decode(String::from_utf8_lossy(&content).trim())?,
+    };
+    let seed: [u8; 32] = seed
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Expected a 32 byte seed"))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// Sign a JSON document, keeping the exact bytes that were signed.
+pub(crate) fn sign(summary: String) -> Result<SignedSummary> {
+    let key = KEY.as_ref().map_err(|err| anyhow::anyhow!("{}", err))?;
+    Ok(sign_with(key.as_ref(), summary))
+}
+
+fn sign_with(key: Option<&SigningKey>, summary: String) -> SignedSummary {
+    SignedSummary {
+        signature: key.map(|key| hex::encode(key.sign(summary.as_bytes()).to_bytes())),
+        public_key: key.map(|key| hex::encode(key.verifying_key().to_bytes())),
+        summary,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    #[test]
+    fn load_seeds() {
+        let dir = tempfile::tempdir().unwrap();
+        let (raw, hex_file) = (dir.path().join("raw"), dir.path().join("hex"));
+        std::fs::write(&raw, [7u8; 32]).unwrap();
+        std::fs::write(&hex_file, format!("{}\n", hex::encode([7u8; 32]))).unwrap();
+        let key = load(raw.to_str().unwrap()).unwrap();
+        assert_eq!(key.to_bytes(), [7u8; 32]);
+        assert_eq!(
+            load(hex_file.to_str().unwrap()).unwrap().to_bytes(),
+            [7u8; 32]
+        );
+        std::fs::write(&raw, [7u8; 16]).unwrap();
+        assert!(load(raw.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn signed_and_unsigned() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let signed = sign_with(Some(&key), "{}".to_string());
+        let public: [u8; 32] = hex::decode(signed.public_key.unwrap())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let signature: [u8; 64] = hex::decode(signed.signature.unwrap())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        VerifyingKey::from_bytes(&public)
+            .unwrap()
+            .verify(b"{}", &Signature::from_bytes(&signature))
+            .unwrap();
+
+        let unsigned = sign_with(None, "{}".to_string());
+        assert_eq!(unsigned.summary, "{}");
+        assert!(unsigned.signature.is_none() && unsigned.public_key.is_none());
+    }
+}
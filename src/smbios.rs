@@ -0,0 +1,519 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::{
+    hostfs,
+    messages::{
+        BaseboardInfo, BiosInfo, ChassisInfo, DmiInfo, MemoryDevice, ProcessorInfo, SystemInfo,
+    },
+};
+
+const ENTRY_POINT: &str = "/sys/firmware/dmi/tables/smbios_entry_point";
+const TABLE: &str = "/sys/firmware/dmi/tables/DMI";
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_BASEBOARD: u8 = 2;
+const TYPE_CHASSIS: u8 = 3;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END: u8 = 127;
+
+/// UUIDs shipped unchanged on many boards, which identify nothing.
+const BOGUS_UUIDS: &[&str] = &[
+    "03000200-0400-0500-0006-000700080009",
+    "12345678-1234-5678-90ab-cddeefaabbcc",
+];
+
+/// Strings firmware vendors leave in fields they did not fill in.
+const PLACEHOLDERS: &[&str] = &[
+    "to be filled by o.e.m.",
+    "not specified",
+    "not applicable",
+    "default string",
+    "system product name",
+    "system manufacturer",
+    "system serial number",
+    "system version",
+    "none",
+    "n/a",
+];
+
+/// One structure of the table: its formatted area, header included, and its
+/// string set.
+pub(crate) struct Structure<'a> {
+    kind: u8,
+    data: &'a [u8],
+    strings: Vec<&'a [u8]>,
+}
+
+impl Structure<'_> {
+    fn byte(&self, offset: usize) -> Option<u8> {
+        self.data.get(offset).copied()
+    }
+
+    fn word(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn dword(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// The string referenced by the byte at `offset`, unless it is unset or a
+    /// placeholder.
+    fn string(&self, offset: usize) -> Option<String> {
+        let index = self.byte(offset)? as usize;
+        let raw = self.strings.get(index.checked_sub(1)?)?;
+        let value = String::from_utf8_lossy(raw).trim().to_string();
+        if value.is_empty() || PLACEHOLDERS.contains(&value.to_lowercase().as_str()) {
+            return None;
+        }
+        Some(value)
+    }
+}
+
+/// Split a raw table into its structures, stopping at the end-of-table marker
+/// or at the first truncated structure.
+pub(crate) fn structures(table: &[u8]) -> Vec<Structure<'_>> {
+    let mut result = Vec::new();
+    let mut rest = table;
+    while rest.len() >= 4 {
+        let length = rest[1] as usize;
+        if length < 4 || rest.len() < length {
+            break;
+        }
+        let (data, tail) = rest.split_at(length);
+        // The string set ends with two NULs, also when it is empty
+        let Some(end) = tail.windows(2).position(|w| w == [0, 0]) else {
+            break;
+        };
+        let strings = tail[..end]
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .collect();
+        let structure = Structure {
+            kind: data[0],
+            data,
+            strings,
+        };
+        rest = &tail[end + 2..];
+        let kind = structure.kind;
+        result.push(structure);
+        if kind == TYPE_END {
+            break;
+        }
+    }
+    result
+}
+
+/// SMBIOS version announced by a 32-bit (`_SM_`) or 64-bit (`_SM3_`) entry point.
+pub(crate) fn entry_version(entry: &[u8]) -> Option<(u8, u8)> {
+    if entry.starts_with(b"_SM3_") {
+        Some((*entry.get(7)?, *entry.get(8)?))
+    } else if entry.starts_with(b"_SM_") {
+        Some((*entry.get(6)?, *entry.get(7)?))
+    } else {
+        None
+    }
+}
+
+/// Format 16 bytes in the usual 8-4-4-4-12 form.
+pub(crate) fn format_uuid(bytes: &[u8; 16]) -> String {
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Format a system UUID, unless it is unset or known to be bogus. Since SMBIOS
+/// 2.6 its first three fields are stored little endian.
+fn uuid(bytes: &[u8], version: Option<(u8, u8)>) -> Option<String> {
+    let bytes: [u8; 16] = bytes.try_into().ok()?;
+    if bytes.iter().all(|&b| b == 0) || bytes.iter().all(|&b| b == 0xff) {
+        return None;
+    }
+    let mut bytes = bytes;
+    if version.is_none_or(|v| v >= (2, 6)) {
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+    }
+    let uuid = format_uuid(&bytes);
+    (!BOGUS_UUIDS.contains(&uuid.as_str())).then_some(uuid)
+}
+
+fn chassis_kind(kind: u8) -> Option<&'static str> {
+    Some(match kind & 0x7f {
+        0x01 => "Other",
+        0x03 => "Desktop",
+        0x04 => "Low Profile Desktop",
+        0x05 => "Pizza Box",
+        0x06 => "Mini Tower",
+        0x07 => "Tower",
+        0x08 => "Portable",
+        0x09 => "Laptop",
+        0x0a => "Notebook",
+        0x0b => "Hand Held",
+        0x0c => "Docking Station",
+        0x0d => "All in One",
+        0x0e => "Sub Notebook",
+        0x0f => "Space-saving",
+        0x10 => "Lunch Box",
+        0x11 => "Main Server Chassis",
+        0x12 => "Expansion Chassis",
+        0x13 => "SubChassis",
+        0x14 => "Bus Expansion Chassis",
+        0x15 => "Peripheral Chassis",
+        0x16 => "RAID Chassis",
+        0x17 => "Rack Mount Chassis",
+        0x18 => "Sealed-case PC",
+        0x19 => "Multi-system Chassis",
+        0x1a => "Compact PCI",
+        0x1b => "Advanced TCA",
+        0x1c => "Blade",
+        0x1d => "Blade Enclosure",
+        0x1e => "Tablet",
+        0x1f => "Convertible",
+        0x20 => "Detachable",
+        0x21 => "IoT Gateway",
+        0x22 => "Embedded PC",
+        0x23 => "Mini PC",
+        0x24 => "Stick PC",
+        _ => return None,
+    })
+}
+
+fn memory_kind(kind: u8) -> Option<&'static str> {
+    Some(match kind {
+        0x01 => "Other",
+        0x03 => "DRAM",
+        0x04 => "EDRAM",
+        0x05 => "VRAM",
+        0x06 => "SRAM",
+        0x07 => "RAM",
+        0x08 => "ROM",
+        0x09 => "Flash",
+        0x0a => "EEPROM",
+        0x0b => "FEPROM",
+        0x0c => "EPROM",
+        0x0d => "CDRAM",
+        0x0e => "3DRAM",
+        0x0f => "SDRAM",
+        0x10 => "SGRAM",
+        0x11 => "RDRAM",
+        0x12 => "DDR",
+        0x13 => "DDR2",
+        0x14 => "DDR2 FB-DIMM",
+        0x18 => "DDR3",
+        0x19 => "FBD2",
+        0x1a => "DDR4",
+        0x1b => "LPDDR",
+        0x1c => "LPDDR2",
+        0x1d => "LPDDR3",
+        0x1e => "LPDDR4",
+        0x1f => "Logical non-volatile device",
+        0x20 => "HBM",
+        0x21 => "HBM2",
+        0x22 => "DDR5",
+        0x23 => "LPDDR5",
+        0x24 => "HBM3",
+        _ => return None,
+    })
+}
+
+/// A 16-bit count or speed, with 0 and 0xffff meaning unknown.
+fn known(value: Option<u16>) -> Option<u32> {
+    value.filter(|v| *v != 0 && *v != 0xffff).map(u32::from)
+}
+
+/// A count that moved to a 16-bit field in SMBIOS 3.0 once it outgrew 8 bits.
+fn count(s: &Structure, offset: usize, extended: usize) -> Option<u32> {
+    match s.byte(offset)? {
+        0 => None,
+        0xff => known(s.word(extended)),
+        n => Some(n as u32),
+    }
+}
+
+/// A memory speed in MT/s, with the 32-bit extended field of SMBIOS 3.3.
+fn speed(s: &Structure, offset: usize, extended: usize) -> Option<u32> {
+    match s.word(offset)? {
+        0 => None,
+        0xffff => s
+            .dword(extended)
+            .map(|v| v & 0x7fff_ffff)
+            .filter(|v| *v != 0),
+        n => Some(n as u32),
+    }
+}
+
+fn processor(s: &Structure) -> ProcessorInfo {
+    ProcessorInfo {
+        socket: s.string(0x04),
+        manufacturer: s.string(0x07),
+        version: s.string(0x10),
+        max_speed_mhz: known(s.word(0x14)),
+        current_speed_mhz: known(s.word(0x16)),
+        cores: count(s, 0x23, 0x2a),
+        threads: count(s, 0x25, 0x2e),
+        populated: s.byte(0x18).is_some_and(|status| status & 0x40 != 0),
+        serial: s.string(0x20),
+        part_number: s.string(0x22),
+    }
+}
+
+fn memory_device(s: &Structure) -> MemoryDevice {
+    let size = match s.word(0x0c) {
+        None | Some(0) | Some(0xffff) => 0,
+        Some(0x7fff) => s.dword(0x1c).map_or(0, |mb| (mb & 0x7fff_ffff) as u64) << 20,
+        Some(size) if size & 0x8000 != 0 => ((size & 0x7fff) as u64) << 10,
+        Some(size) => (size as u64) << 20,
+    };
+    let slot = MemoryDevice {
+        locator: s.string(0x10),
+        bank: s.string(0x11),
+        ..Default::default()
+    };
+    // Empty slots often carry filler such as "NO DIMM" in every string
+    if size == 0 {
+        return slot;
+    }
+    MemoryDevice {
+        size,
+        kind: s.byte(0x12).and_then(memory_kind).map(str::to_string),
+        speed: speed(s, 0x15, 0x54),
+        configured_speed: speed(s, 0x20, 0x58),
+        manufacturer: s.string(0x17),
+        serial: s.string(0x18),
+        asset_tag: s.string(0x19),
+        part_number: s.string(0x1a),
+        ..slot
+    }
+}
+
+/// Build the inventory from a raw table, such as the content of
+/// `/sys/firmware/dmi/tables/DMI` or a captured copy of it.
+pub(crate) fn parse(table: &[u8], version: Option<(u8, u8)>) -> DmiInfo {
+    let mut info = DmiInfo {
+        version: version.map(|(major, minor)| format!("{}.{}", major, minor)),
+        ..Default::default()
+    };
+    for s in structures(table) {
+        match s.kind {
+            TYPE_BIOS => {
+                info.bios.get_or_insert(BiosInfo {
+                    vendor: s.string(0x04),
+                    version: s.string(0x05),
+                    release_date: s.string(0x08),
+                });
+            }
+            TYPE_SYSTEM => {
+                info.system.get_or_insert(SystemInfo {
+                    manufacturer: s.string(0x04),
+                    product: s.string(0x05),
+                    version: s.string(0x06),
+                    serial: s.string(0x07),
+                    uuid: s.data.get(0x08..0x18).and_then(|b| uuid(b, version)),
+                    sku: s.string(0x19),
+                    family: s.string(0x1a),
+                });
+            }
+            TYPE_BASEBOARD => {
+                info.baseboard.get_or_insert(BaseboardInfo {
+                    manufacturer: s.string(0x04),
+                    product: s.string(0x05),
+                    version: s.string(0x06),
+                    serial: s.string(0x07),
+                    asset_tag: s.string(0x08),
+                });
+            }
+            TYPE_CHASSIS => {
+                info.chassis.get_or_insert(ChassisInfo {
+                    manufacturer: s.string(0x04),
+                    kind: s.byte(0x05).and_then(chassis_kind).map(str::to_string),
+                    version: s.string(0x06),
+                    serial: s.string(0x07),
+                    asset_tag: s.string(0x08),
+                });
+            }
+            TYPE_PROCESSOR => info.processors.push(processor(&s)),
+            TYPE_MEMORY_DEVICE => info.memory.push(memory_device(&s)),
+            _ => {}
+        }
+    }
+    info
+}
+
+fn load(table: &Path, entry: &Path) -> Result<DmiInfo> {
+    let content = std::fs::read(table)
+        .map_err(|err| anyhow::anyhow!("Failed to read {}: {}", table.display(), err))?;
+    let version = std::fs::read(entry)
+        .ok()
+        .and_then(|entry| entry_version(&entry));
+    Ok(parse(&content, version))
+}
+
+/// Read and parse the tables the kernel exposes.
+pub(crate) fn read() -> Result<DmiInfo> {
+    load(&hostfs::path(TABLE), &hostfs::path(ENTRY_POINT))
+}
+
+/// Read the tables of the running host, whatever `MXA_HOST_ROOT` says.
+pub(crate) fn read_live() -> Result<DmiInfo> {
+    load(Path::new(TABLE), Path::new(ENTRY_POINT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SMBIOS 3.3 tables of a server, as in the host fixture.
+    const V3_TABLE: &[u8] = include_bytes!("../tests/fixtures/host/sys/firmware/dmi/tables/DMI");
+    const V3_ENTRY: &[u8] =
+        include_bytes!("../tests/fixtures/host/sys/firmware/dmi/tables/smbios_entry_point");
+    /// SMBIOS 2.4 tables of a virtual machine, with short processor and memory
+    /// structures.
+    const V2_TABLE: &[u8] = include_bytes!("../tests/fixtures/dmi/smbios-2.4.bin");
+    const V2_ENTRY: &[u8] = include_bytes!("../tests/fixtures/dmi/smbios-2.4.entry");
+
+    #[test]
+    fn read_fixture() {
+        let dmi = read().unwrap();
+        let system = dmi.system.unwrap();
+        assert_eq!(system.manufacturer.as_deref(), Some("Dell Inc."));
+        assert_eq!(system.product.as_deref(), Some("PowerEdge R640"));
+        assert_eq!(
+            system.uuid.as_deref(),
+            Some("4c4c4544-0031-3310-8051-b4c04f334d32")
+        );
+        assert_eq!(dmi.processors.len(), 2);
+        assert_eq!(dmi.memory.len(), 4);
+    }
+
+    #[test]
+    fn entry_points() {
+        assert_eq!(entry_version(V3_ENTRY), Some((3, 3)));
+        assert_eq!(entry_version(V2_ENTRY), Some((2, 4)));
+        assert_eq!(entry_version(b"_SM3_"), None);
+        assert_eq!(entry_version(b"_DMI_\0\0\0"), None);
+    }
+
+    #[test]
+    fn smbios_3() {
+        let dmi = parse(V3_TABLE, entry_version(V3_ENTRY));
+        assert_eq!(dmi.version.as_deref(), Some("3.3"));
+        let system = dmi.system.unwrap();
+        // Placeholders are dropped
+        assert_eq!(system.version, None);
+        assert_eq!(system.family.as_deref(), Some("PowerEdge"));
+        let baseboard = dmi.baseboard.unwrap();
+        assert_eq!(baseboard.serial.as_deref(), Some("CN123"));
+        assert_eq!(baseboard.asset_tag, None);
+        assert_eq!(
+            dmi.chassis.unwrap().kind.as_deref(),
+            Some("Rack Mount Chassis")
+        );
+
+        // A core count of 0xff defers to Core Count 2
+        let cpu = &dmi.processors[0];
+        assert_eq!((cpu.cores, cpu.threads), (Some(300), Some(600)));
+        assert_eq!(cpu.max_speed_mhz, Some(4000));
+        assert!(cpu.populated);
+        assert!(!dmi.processors[1].populated);
+
+        let memory = &dmi.memory;
+        assert_eq!(memory[0].size, 16 << 30);
+        assert_eq!(memory[0].part_number.as_deref(), Some("M393A2K43BB1-CTD"));
+        assert_eq!(
+            (memory[0].speed, memory[0].configured_speed),
+            (Some(2666), Some(2400))
+        );
+        // Size 0x7fff and speed 0xffff defer to the extended fields
+        assert_eq!(memory[1].size, 64 << 30);
+        assert_eq!(memory[1].kind.as_deref(), Some("DDR5"));
+        assert_eq!(
+            (memory[1].speed, memory[1].configured_speed),
+            (Some(6400), Some(5600))
+        );
+        assert_eq!(memory[1].asset_tag, None);
+        // Empty slot with filler strings
+        assert_eq!(memory[2].size, 0);
+        assert_eq!(memory[2].locator.as_deref(), Some("A3"));
+        assert_eq!(memory[2].manufacturer, None);
+        // Size given in KiB
+        assert_eq!(memory[3].size, 512 << 10);
+        assert_eq!(memory[3].configured_speed, None);
+    }
+
+    #[test]
+    fn smbios_2() {
+        let dmi = parse(V2_TABLE, entry_version(V2_ENTRY));
+        assert_eq!(dmi.version.as_deref(), Some("2.4"));
+        let system = dmi.system.unwrap();
+        assert_eq!(system.product.as_deref(), Some("VMware Virtual Platform"));
+        assert_eq!(system.version, None);
+        // Before 2.6 the UUID is stored as it reads
+        assert_eq!(
+            system.uuid.as_deref(),
+            Some("564d8f2a-1b3c-4d5e-8f70-112233445566")
+        );
+        assert_eq!(system.sku, None);
+        assert_eq!(dmi.baseboard.unwrap().serial, None);
+        assert_eq!(dmi.chassis.unwrap().kind.as_deref(), Some("Other"));
+
+        let cpu = &dmi.processors[0];
+        assert_eq!((cpu.cores, cpu.threads), (None, None));
+        assert_eq!(
+            (cpu.max_speed_mhz, cpu.current_speed_mhz),
+            (None, Some(2400))
+        );
+
+        let memory = &dmi.memory[0];
+        assert_eq!(memory.size, 4 << 30);
+        assert_eq!(memory.kind.as_deref(), Some("RAM"));
+        assert_eq!((memory.speed, memory.configured_speed), (None, None));
+        assert_eq!(memory.manufacturer.as_deref(), Some("VMware Virtual RAM"));
+    }
+
+    #[test]
+    fn uuid_byte_order() {
+        let bytes: Vec<u8> = (1..=16).collect();
+        let swapped = "04030201-0605-0807-090a-0b0c0d0e0f10";
+        let stored = "01020304-0506-0708-090a-0b0c0d0e0f10";
+        assert_eq!(uuid(&bytes, Some((3, 3))).as_deref(), Some(swapped));
+        assert_eq!(uuid(&bytes, Some((2, 6))).as_deref(), Some(swapped));
+        assert_eq!(uuid(&bytes, None).as_deref(), Some(swapped));
+        assert_eq!(uuid(&bytes, Some((2, 5))).as_deref(), Some(stored));
+        assert_eq!(uuid(&[0; 16], None), None);
+        assert_eq!(uuid(&[0xff; 16], None), None);
+        let bogus = [0, 2, 0, 3, 0, 4, 0, 5, 0, 6, 0, 7, 0, 8, 0, 9];
+        assert_eq!(uuid(&bogus, Some((3, 0))), None);
+    }
+
+    #[test]
+    fn truncated_tables() {
+        let complete = structures(V3_TABLE).len();
+        assert_eq!(structures(V3_TABLE).last().unwrap().kind, TYPE_END);
+        let mut previous = 0;
+        for len in 0..V3_TABLE.len() {
+            let found = structures(&V3_TABLE[..len]);
+            // Only whole structures are kept, and never one too many
+            assert!(found.len() >= previous && found.len() < complete, "{}", len);
+            previous = found.len();
+            parse(&V3_TABLE[..len], Some((3, 3)));
+        }
+        // A structure claiming to be shorter than its header ends the table
+        let mut table = V3_TABLE.to_vec();
+        table[1] = 2;
+        assert!(structures(&table).is_empty());
+    }
+}
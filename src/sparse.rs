@@ -0,0 +1,168 @@
+use std::io::Read;
+
+use anyhow::Result;
+use log::debug;
+
+use crate::image::{Sink, read_full};
+
+pub(crate) const MAGIC: u32 = 0xed26ff3a;
+const CHUNK_RAW: u16 = 0xcac1;
+const CHUNK_FILL: u16 = 0xcac2;
+const CHUNK_DONT_CARE: u16 = 0xcac3;
+const CHUNK_CRC32: u16 = 0xcac4;
+const FILE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+const COPY_SIZE: usize = 1024 * 1024;
+
+fn u16_at(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(buf[pos..pos + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+/// Read a header of `size` bytes, of which the first `len` are known fields.
+fn read_header(reader: &mut impl Read, size: usize, len: usize, what: &str) -> Result<Vec<u8>> {
+    if size < len {
+        anyhow::bail!("Sparse {} header too short: {} bytes", what, size);
+    }
+    let mut buf = vec![0u8; size];
+    if read_full(reader, &mut buf)? != size {
+        anyhow::bail!("Sparse image truncated in {} header", what);
+    }
+    Ok(buf)
+}
+
+/// Expand an Android sparse image onto the sink, returning the size of the disk.
+///
+/// Zero fills and "don't care" chunks become holes.
+pub(crate) fn expand(reader: &mut impl Read, sink: &mut Sink) -> Result<u64> {
+    let mut header = vec![0u8; FILE_HEADER_SIZE];
+    if read_full(reader, &mut header)? != FILE_HEADER_SIZE || u32_at(&header, 0) != MAGIC {
+        anyhow::bail!("Not an Android sparse image");
+    }
+    let major = u16_at(&header, 4);
+    if major != 1 {
+        anyhow::bail!("Unsupported sparse image version {}", major);
+    }
+    let file_header_size = u16_at(&header, 8) as usize;
+    let chunk_header_size = u16_at(&header, 10) as usize;
+    let block_size = u32_at(&header, 12) as u64;
+    let total_blocks = u32_at(&header, 16) as u64;
+    let total_chunks = u32_at(&header, 20);
+    if block_size == 0 || !block_size.is_multiple_of(4) {
+        anyhow::bail!("Invalid sparse block size {}", block_size);
+    }
+    if file_header_size > FILE_HEADER_SIZE {
+        read_header(reader, file_header_size - FILE_HEADER_SIZE, 0, "file")?;
+    }
+    debug!(
+        "Sparse image: {} chunks, {} blocks of {} bytes",
+        total_chunks, total_blocks, block_size
+    );
+
+    let mut buf = vec![0u8; COPY_SIZE];
+    let mut block = 0u64;
+    for _ in 0..total_chunks {
+        let chunk = read_header(reader, chunk_header_size, CHUNK_HEADER_SIZE, "chunk")?;
+        let kind = u16_at(&chunk, 0);
+        let blocks = u32_at(&chunk, 4) as u64;
+        let body = (u32_at(&chunk, 8) as u64)
+            .checked_sub(chunk_header_size as u64)
+            .ok_or_else(|| anyhow::anyhow!("Invalid sparse chunk size"))?;
+        let (offset, len) = (block * block_size, blocks * block_size);
+        if block + blocks > total_blocks {
+            anyhow::bail!("Sparse chunk runs past the end of the image");
+        }
+        match kind {
+            CHUNK_RAW => {
+                if body != len {
+                    anyhow::bail!("Raw chunk of {} blocks carries {} bytes", blocks, body);
+                }
+                let mut done = 0;
+                while done < len {
+                    let want = (len - done).min(COPY_SIZE as u64) as usize;
+                    if read_full(reader, &mut buf[..want])? != want {
+                        anyhow::bail!("Sparse image truncated in raw chunk");
+                    }
+                    sink.write(offset + done, &buf[..want])?;
+                    done += want as u64;
+                }
+            }
+            CHUNK_FILL => {
+                let fill = read_header(reader, body as usize, 4, "fill")?;
+                if fill[..4] == [0; 4] {
+                    sink.hole(offset, len);
+                } else {
+                    for word in buf.chunks_exact_mut(4) {
+                        word.copy_from_slice(&fill[..4]);
+                    }
+                    let mut done = 0;
+                    while done < len {
+                        let want = (len - done).min(COPY_SIZE as u64) as usize;
+                        sink.write(offset + done, &buf[..want])?;
+                        done += want as u64;
+                    }
+                }
+            }
+            CHUNK_DONT_CARE => sink.hole(offset, len),
+            CHUNK_CRC32 => {
+                read_header(reader, body as usize, 4, "checksum")?;
+            }
+            _ => anyhow::bail!("Unknown sparse chunk type {:#x}", kind),
+        }
+        block += blocks;
+    }
+    if block != total_blocks {
+        debug!(
+            "Sparse chunks cover {} of {} blocks, the rest is a hole",
+            block, total_blocks
+        );
+        sink.hole(block * block_size, (total_blocks - block) * block_size);
+    }
+    Ok(total_blocks * block_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::expand_to_vec;
+
+    /// Raw, fill, don't-care, zero fill and raw chunks of 4 KiB blocks, then a
+    /// CRC32 chunk.
+    const SPARSE: &[u8] = include_bytes!("../tests/fixtures/images/sparse.simg");
+    const RAW: &[u8] = include_bytes!("../tests/fixtures/images/sparse.raw");
+
+    fn expand_image(image: &[u8]) -> Result<Vec<u8>> {
+        expand_to_vec(|sink| expand(&mut &image[..], sink))
+    }
+
+    #[test]
+    fn chunks() {
+        assert!(expand_image(SPARSE).unwrap() == RAW);
+    }
+
+    #[test]
+    fn truncated_image() {
+        let err = expand_image(&SPARSE[..SPARSE.len() - 5000]).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+    }
+
+    #[test]
+    fn chunk_past_the_end() {
+        let mut image = SPARSE.to_vec();
+        // Claim one block fewer than the chunks cover
+        let blocks = u32_at(&image, 16) - 1;
+        image[16..20].copy_from_slice(&blocks.to_le_bytes());
+        let err = expand_image(&image).unwrap_err();
+        assert!(err.to_string().contains("past the end"));
+    }
+
+    #[test]
+    fn unknown_chunk() {
+        let mut image = SPARSE.to_vec();
+        image[FILE_HEADER_SIZE..FILE_HEADER_SIZE + 2].copy_from_slice(&0xcac9u16.to_le_bytes());
+        assert!(expand_image(&image).is_err());
+    }
+}
@@ -0,0 +1,360 @@
+use std::{
+    collections::HashSet,
+    ffi::CString,
+    path::Path,
+    sync::LazyLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info};
+use tokio::sync::watch;
+
+use crate::{
+    facts::{read_trimmed, sorted_entries},
+    hostfs,
+    messages::{
+        CpuSample, DiskSample, FilesystemSample, InterfaceSample, MemorySample, Telemetry,
+        TemperatureSample,
+    },
+};
+
+/// Shortest interval accepted from the controller.
+pub(crate) const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often samples are pushed, kept across reconnects; `None` when off.
+/// Starts from `MXA_TELEMETRY_INTERVAL` in seconds.
+static INTERVAL: LazyLock<watch::Sender<Option<Duration>>> = LazyLock::new(|| {
+    let interval = std::env::var("MXA_TELEMETRY_INTERVAL")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|secs| *secs > 0)
+        .map(|secs| Duration::from_secs(secs).max(MIN_INTERVAL));
+    watch::Sender::new(interval)
+});
+
+pub(crate) fn interval() -> Option<Duration> {
+    *INTERVAL.borrow()
+}
+
+pub(crate) fn configure(interval: Option<Duration>) {
+    let interval = interval
+        .filter(|i| !i.is_zero())
+        .map(|i| i.max(MIN_INTERVAL));
+    match interval {
+        Some(interval) => info!("Pushing telemetry every {:?}", interval),
+        None => info!("Telemetry stopped"),
+    }
+    INTERVAL.send_replace(interval);
+}
+
+pub(crate) fn subscribe() -> watch::Receiver<Option<Duration>> {
+    INTERVAL.subscribe()
+}
+
+/// Total and idle jiffies of all CPUs, from the first line of `/proc/stat`.
+#[derive(Clone, Copy)]
+pub(crate) struct CpuTimes {
+    total: u64,
+    idle: u64,
+}
+
+pub(crate) fn cpu_times() -> Option<CpuTimes> {
+    let stat = std::fs::read_to_string(hostfs::path("/proc/stat")).ok()?;
+    let fields: Vec<u64> = stat
+        .lines()
+        .next()?
+        .strip_prefix("cpu ")?
+        .split_whitespace()
+        .filter_map(|v| v.parse().ok())
+        .collect();
+    // Guest time is already counted in user time
+    let total = fields.iter().take(8).sum();
+    // idle and iowait
+    let idle = fields.get(3)? + fields.get(4).unwrap_or(&0);
+    Some(CpuTimes { total, idle })
+}
+
+fn cpu(previous: &mut Option<CpuTimes>) -> CpuSample {
+    let times = cpu_times();
+    let usage = match (*previous, times) {
+        (Some(prev), Some(now)) if now.total > prev.total => {
+            let total = (now.total - prev.total) as f64;
+            let idle = now.idle.saturating_sub(prev.idle) as f64;
+            Some(((total - idle) / total * 1000.0).round() / 10.0)
+        }
+        _ => None,
+    };
+    *previous = times;
+    let mut load = [0.0; 3];
+    if let Some(loadavg) = read_trimmed(hostfs::path("/proc/loadavg")) {
+        for (slot, value) in load.iter_mut().zip(loadavg.split_whitespace()) {
+            *slot = value.parse().unwrap_or(0.0);
+        }
+    }
+    CpuSample { usage, load }
+}
+
+fn memory() -> MemorySample {
+    let meminfo = std::fs::read_to_string(hostfs::path("/proc/meminfo")).unwrap_or_default();
+    let mut memory = MemorySample::default();
+    for line in meminfo.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        // Values are in kB
+        let bytes = value
+            .split_whitespace()
+            .next()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0)
+            * 1024;
+        match key {
+            "MemTotal" => memory.total = bytes,
+            "MemFree" => memory.free = bytes,
+            "MemAvailable" => memory.available = bytes,
+            "Buffers" => memory.buffers = bytes,
+            "Cached" => memory.cached = bytes,
+            "SwapTotal" => memory.swap_total = bytes,
+            "SwapFree" => memory.swap_free = bytes,
+            _ => {}
+        }
+    }
+    memory
+}
+
+/// Counters of disks backed by a device, the same ones the inventory lists.
+fn disks() -> Vec<DiskSample> {
+    let stats = std::fs::read_to_string(hostfs::path("/proc/diskstats")).unwrap_or_default();
+    stats
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let name = fields.get(2)?.to_string();
+            if !hostfs::path(format!("/sys/block/{}/device", name)).exists() {
+                return None;
+            }
+            let field = |i: usize| fields.get(i).and_then(|v| v.parse::<u64>().ok());
+            Some(DiskSample {
+                reads: field(3)?,
+                read_bytes: field(5)? * 512,
+                writes: field(7)?,
+                written_bytes: field(9)? * 512,
+                io_time: field(12)?,
+                name,
+            })
+        })
+        .collect()
+}
+
+fn interfaces() -> Vec<InterfaceSample> {
+    let dev = std::fs::read_to_string(hostfs::path("/proc/net/dev")).unwrap_or_default();
+    dev.lines()
+        .skip(2)
+        .filter_map(|line| {
+            let (name, counters) = line.split_once(':')?;
+            let name = name.trim();
+            if name == "lo" {
+                return None;
+            }
+            let fields: Vec<u64> = counters
+                .split_whitespace()
+                .filter_map(|v| v.parse().ok())
+                .collect();
+            Some(InterfaceSample {
+                name: name.to_string(),
+                rx_bytes: *fields.first()?,
+                rx_packets: *fields.get(1)?,
+                rx_errors: *fields.get(2)?,
+                rx_dropped: *fields.get(3)?,
+                tx_bytes: *fields.get(8)?,
+                tx_packets: *fields.get(9)?,
+                tx_errors: *fields.get(10)?,
+                tx_dropped: *fields.get(11)?,
+            })
+        })
+        .collect()
+}
+
+fn statvfs(path: &[u8]) -> Option<libc::statvfs> {
+    let path = CString::new(path).ok()?;
+    // SAFETY: an all-zero statvfs is valid
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: the path is NUL terminated and `stat` outlives the call
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } < 0 {
+        return None;
+    }
+    Some(stat)
+}
+
+/// Undo the octal escapes (`\040` for a space, `\134` for a backslash, ...)
+/// the kernel writes for special characters in mount table fields.
+pub(crate) fn unescape(field: &str) -> Vec<u8> {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| bytes[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)))
+            // Three octal digits may exceed a byte, as in \777
+            .and_then(|digits| {
+                let value = digits
+                    .iter()
+                    .fold(0u32, |acc, d| acc * 8 + (d - b'0') as u32);
+                u8::try_from(value).ok()
+            });
+        match octal {
+            Some(byte) => {
+                out.push(byte);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Device, mount point and type of the mounts of block devices in a mount
+/// table, each device once.
+fn device_mounts(table: &str) -> Vec<(String, Vec<u8>, String)> {
+    let mut seen = HashSet::new();
+    table
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let (device, mountpoint, kind) = (fields.next()?, fields.next()?, fields.next()?);
+            let device = String::from_utf8_lossy(&unescape(device)).to_string();
+            if !device.starts_with("/dev/") || !seen.insert(device.clone()) {
+                return None;
+            }
+            Some((device, unescape(mountpoint), kind.to_string()))
+        })
+        .collect()
+}
+
+/// Usage of filesystems on block devices, each device once.
+fn filesystems() -> Vec<FilesystemSample> {
+    // Usage is only known for filesystems mounted on this host
+    if !hostfs::is_live() {
+        return Vec::new();
+    }
+    let table = std::fs::read_to_string(hostfs::path("/proc/self/mounts")).unwrap_or_default();
+    device_mounts(&table)
+        .into_iter()
+        .filter_map(|(device, mountpoint, kind)| {
+            let stat = statvfs(&mountpoint)?;
+            let block = stat.f_frsize;
+            Some(FilesystemSample {
+                device,
+                mountpoint: String::from_utf8_lossy(&mountpoint).to_string(),
+                kind,
+                size: stat.f_blocks * block,
+                free: stat.f_bfree * block,
+                available: stat.f_bavail * block,
+            })
+        })
+        .collect()
+}
+
+/// A value in millidegrees, as hwmon reports it.
+fn celsius(path: &Path) -> Option<f64> {
+    let millis: i64 = read_trimmed(path)?.parse().ok()?;
+    Some(millis as f64 / 1000.0)
+}
+
+fn temperatures() -> Vec<TemperatureSample> {
+    let hwmon = hostfs::path("/sys/class/hwmon");
+    if !hwmon.is_dir() {
+        return Vec::new();
+    }
+    let mut temperatures = Vec::new();
+    for chip in sorted_entries(&hwmon) {
+        let dir = hwmon.join(&chip);
+        let name = read_trimmed(dir.join("name")).unwrap_or_else(|| chip.clone());
+        for entry in sorted_entries(&dir) {
+            let Some(sensor) = entry
+                .strip_suffix("_input")
+                .filter(|s| s.starts_with("temp"))
+            else {
+                continue;
+            };
+            // Reading fails for sensors that are present but not connected
+            let Some(current) = celsius(&dir.join(&entry)) else {
+                continue;
+            };
+            let attr = |suffix: &str| dir.join(format!("{}_{}", sensor, suffix));
+            temperatures.push(TemperatureSample {
+                chip: name.clone(),
+                label: read_trimmed(attr("label")).unwrap_or_else(|| sensor.to_string()),
+                current,
+                max: celsius(&attr("max")),
+                critical: celsius(&attr("crit")),
+            });
+        }
+    }
+    temperatures
+}
+
+/// Take a sample; `previous` carries CPU times from one sample to the next.
+pub(crate) fn sample(previous: &mut Option<CpuTimes>) -> Telemetry {
+    let telemetry = Telemetry {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        cpu: cpu(previous),
+        memory: memory(),
+        disks: disks(),
+        interfaces: interfaces(),
+        filesystems: filesystems(),
+        temperatures: temperatures(),
+    };
+    debug!(
+        "Sampled telemetry: cpu {:?}%, {} temperature(s)",
+        telemetry.cpu.usage,
+        telemetry.temperatures.len()
+    );
+    telemetry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn octal_escapes() {
+        assert_eq!(unescape(r"/mnt/with\040space"), b"/mnt/with space");
+        assert_eq!(
+            unescape(r"/mnt/tab\011and\012newline"),
+            b"/mnt/tab\tand\nnewline"
+        );
+        assert_eq!(unescape(r"/mnt/back\134slash"), b"/mnt/back\\slash");
+        assert_eq!(unescape(r"/mnt/caf\303\251"), "/mnt/café".as_bytes());
+        // Not an escape: too short, or not octal
+        assert_eq!(unescape(r"/mnt/a\04"), br"/mnt/a\04");
+        assert_eq!(unescape(r"/mnt/a\089"), br"/mnt/a\089");
+    }
+
+    #[test]
+    fn block_device_mounts() {
+        let table = "sysfs /sys sysfs rw,nosuid 0 0\n\
+                     /dev/vda1 / ext4 rw,relatime 0 0\n\
+                     /dev/vdb1 /srv/my\\040data xfs rw 0 0\n\
+                     /dev/vda1 /var/lib/bind ext4 rw,relatime 0 0\n";
+        let mounts = device_mounts(table);
+        assert_eq!(
+            mounts,
+            [
+                ("/dev/vda1".to_string(), b"/".to_vec(), "ext4".to_string()),
+                (
+                    "/dev/vdb1".to_string(),
+                    b"/srv/my data".to_vec(),
+                    "xfs".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    pin::Pin,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use futures_util::{Stream, StreamExt};
+use log::info;
+use tokio::{sync::Notify, time::Instant};
+
+/// Agent-wide limit shared by all transfers, initialised from `MXA_RATE_LIMIT` (bytes per second).
+static GLOBAL: LazyLock<TokenBucket> = LazyLock::new(|| {
+    let rate = std::env::var("MXA_RATE_LIMIT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|r| *r > 0);
+    TokenBucket::new(rate)
+});
+
+pub(crate) fn global() -> &'static TokenBucket {
+    &GLOBAL
+}
+
+pub(crate) type ThrottledStream<B, E> = Pin<Box<dyn Stream<Item = Result<B, E>> + Send + Sync>>;
+
+#[derive(Debug)]
+struct BucketState {
+    rate: Option<u64>,
+    tokens: f64,
+    /// Total tokens added since creation, which waiters use to tell when their debt is repaid.
+    refilled: f64,
+    updated: Instant,
+}
+
+impl BucketState {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.rate {
+            let added = now.duration_since(self.updated).as_secs_f64() * rate as f64;
+            self.tokens = (self.tokens + added).min(rate as f64);
+            self.refilled += added;
+        }
+        self.updated = now;
+    }
+}
+
+/// A token bucket holding up to one second worth of bytes.
+///
+/// Callers may overdraw the bucket and then wait until the debt is refilled, so
+/// concurrent transfers are served in the order they asked. Waiters are woken when
+/// the rate changes so they finish at the new rate.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    state: Mutex<BucketState>,
+    changed: Notify,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: Option<u64>) -> Self {
+        TokenBucket {
+            state: Mutex::new(BucketState {
+                rate,
+                tokens: rate.unwrap_or(0) as f64,
+                refilled: 0.0,
+                updated: Instant::now(),
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    pub(crate) fn rate(&self) -> Option<u64> {
+        self.state.lock().unwrap().rate
+    }
+
+    /// Change the rate in bytes per second; `None` removes the limit.
+    pub(crate) fn set_rate(&self, rate: Option<u64>) {
+        info!("Set transfer rate limit to {:?} bytes/s", rate);
+        {
+            let mut state = self.state.lock().unwrap();
+            // Settle what accrued at the old rate before switching
+            state.refill();
+            state.rate = rate.filter(|r| *r > 0);
+            state.tokens = state.tokens.min(state.rate.unwrap_or(0) as f64);
+        }
+        self.changed.notify_waiters();
+    }
+
+    pub(crate) async fn acquire(&self, n: usize) {
+        let target = {
+            let mut state = self.state.lock().unwrap();
+            if state.rate.is_none() {
+                return;
+            }
+            state.refill();
+            state.tokens -= n as f64;
+            if state.tokens >= 0.0 {
+                return;
+            }
+            state.refilled - state.tokens
+        };
+        loop {
+            // Register before checking so a rate change in between is not missed
+            let changed = self.changed.notified();
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let Some(rate) = state.rate else {
+                    return;
+                };
+                state.refill();
+                let debt = target - state.refilled;
+                if debt <= 0.0 {
+                    return;
+                }
+                Duration::from_secs_f64(debt / rate as f64)
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = changed => {}
+            }
+        }
+    }
+}
+
+/// Rate limit for one transfer: its own bucket, if any, plus the agent-wide one.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Throttle {
+    local: Option<Arc<TokenBucket>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(rate: Option<u64>) -> Self {
+        Throttle {
+            local: rate
+                .filter(|r| *r > 0)
+                .map(|r| Arc::new(TokenBucket::new(Some(r)))),
+        }
+    }
+
+    pub(crate) async fn consume(&self, n: usize) {
+        if let Some(local) = &self.local {
+            local.acquire(n).await;
+        }
+        global().acquire(n).await;
+    }
+
+    /// Delay the chunks of a byte stream to honour the limits.
+    pub(crate) fn wrap<S, B, E>(self, stream: S) -> ThrottledStream<B, E>
+    where
+        S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+        B: AsRef<[u8]> + Send + Sync + 'static,
+        E: Send + Sync + 'static,
+    {
+        Box::pin(stream.then(move |chunk| {
+            let throttle = self.clone();
+            async move {
+                if let Ok(bytes) = &chunk {
+                    throttle.consume(bytes.as_ref().len()).await;
+                }
+                chunk
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u64 = 4 * 1024 * 1024;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    /// Let spawned waiters run without moving the paused clock.
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited() {
+        let bucket = TokenBucket::new(None);
+        let start = Instant::now();
+        bucket.acquire(usize::MAX).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_bucket_then_rate() {
+        let bucket = Arc::new(TokenBucket::new(Some(RATE)));
+        // One second worth of bytes is available right away
+        bucket.acquire(RATE as usize).await;
+        // Then a quarter second worth takes a quarter second
+        let waiter = tokio::spawn({
+            let bucket = bucket.clone();
+            async move { bucket.acquire(RATE as usize / 4).await }
+        });
+        tokio::time::advance(ms(240)).await;
+        settle().await;
+        assert!(!waiter.is_finished());
+        tokio::time::advance(ms(20)).await;
+        settle().await;
+        assert!(waiter.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn overdraw_is_served_in_order() {
+        let bucket = Arc::new(TokenBucket::new(Some(RATE)));
+        bucket.acquire(RATE as usize).await;
+        let start = Instant::now();
+        // Each caller waits for the debt of the callers before it as well
+        let waits = futures_util::future::join_all((0..3).map(|_| {
+            let bucket = bucket.clone();
+            async move {
+                bucket.acquire(RATE as usize / 8).await;
+                start.elapsed()
+            }
+        }))
+        .await;
+        for (wait, expected) in waits.iter().zip([125, 250, 375]) {
+            assert!(*wait >= ms(expected), "{:?}", waits);
+            assert!(*wait <= ms(expected + 1), "{:?}", waits);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiters_follow_rate_changes() {
+        let bucket = Arc::new(TokenBucket::new(Some(RATE)));
+        bucket.acquire(RATE as usize).await;
+        let waiter = tokio::spawn({
+            let bucket = bucket.clone();
+            async move { bucket.acquire(RATE as usize).await }
+        });
+        settle().await;
+        // A tenth is repaid at the old rate, the rest takes 90ms at ten times that
+        tokio::time::advance(ms(100)).await;
+        bucket.set_rate(Some(RATE * 10));
+        settle().await;
+        tokio::time::advance(ms(85)).await;
+        settle().await;
+        assert!(!waiter.is_finished());
+        tokio::time::advance(ms(10)).await;
+        settle().await;
+        assert!(waiter.is_finished());
+
+        // Lowering the rate holds waiters back
+        let waiter = tokio::spawn({
+            let bucket = bucket.clone();
+            async move { bucket.acquire(RATE as usize * 10).await }
+        });
+        settle().await;
+        bucket.set_rate(Some(RATE));
+        settle().await;
+        tokio::time::advance(ms(5000)).await;
+        settle().await;
+        assert!(!waiter.is_finished());
+        // And removing the limit releases them
+        bucket.set_rate(None);
+        settle().await;
+        assert!(waiter.is_finished());
+    }
+
+    #[test]
+    fn lowering_the_rate_drops_saved_tokens() {
+        let bucket = TokenBucket::new(Some(RATE));
+        bucket.set_rate(Some(1024));
+        assert_eq!(bucket.rate(), Some(1024));
+        assert!(bucket.state.lock().unwrap().tokens <= 1024.0);
+        bucket.set_rate(Some(0));
+        assert_eq!(bucket.rate(), None);
+    }
+
+    #[test]
+    fn throttle_without_a_rate_has_no_bucket() {
+        assert!(Throttle::new(None).local.is_none());
+        assert!(Throttle::new(Some(0)).local.is_none());
+        assert_eq!(Throttle::new(Some(RATE)).local.unwrap().rate(), Some(RATE));
+    }
+}
@@ -0,0 +1,564 @@
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{
+    messages::FileOperationResponse, net::Context, throttle::Throttle, utils::verify_digest,
+};
+
+/// Size of the fixed header in front of every binary frame.
+const FRAME_HEADER_LEN: usize = 17;
+/// Give up a transfer when the peer stays silent for this long.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub(crate) const DEFAULT_CHUNK_SIZE: u32 = 256 * 1024;
+pub(crate) const DEFAULT_WINDOW: u32 = 16;
+/// Upper bounds for the controller-chosen chunk size and window, keeping
+/// buffers and data in flight reasonable.
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+const MAX_WINDOW: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameKind {
+    /// File content starting at `offset`.
+    Data = 0,
+    /// Everything before `offset` has been persisted by the sender of the ack.
+    Ack = 1,
+    /// No more data, the file is `offset` bytes long.
+    End = 2,
+    /// The transfer is cancelled by the sender.
+    Abort = 3,
+}
+
+/// A binary WebSocket frame used for file transfers.
+///
+/// Layout (big endian): `id: u64 | kind: u8 | offset: u64 | data`.
+#[derive(Debug, Clone)]
+pub(crate) struct Frame {
+    pub id: u64,
+    pub kind: FrameKind,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub(crate) fn new(id: u64, kind: FrameKind, offset: u64) -> Self {
+        Frame {
+            id,
+            kind,
+            offset,
+            data: Vec::new(),
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + self.data.len());
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.push(self.kind as u8);
+        buf.extend_from_slice(&self.offset.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    pub(crate) fn decode(mut buf: Vec<u8>) -> Result<Self> {
+        if buf.len() < FRAME_HEADER_LEN {
+            anyhow::bail!("Binary frame too short: {} bytes", buf.len());
+        }
+        let id = u64::from_be_bytes(buf[0..8].try_into()?);
+        let kind = match buf[8] {
+            0 => FrameKind::Data,
+            1 => FrameKind::Ack,
+            2 => FrameKind::End,
+            3 => FrameKind::Abort,
+            k => anyhow::bail!("Unknown binary frame kind: {}", k),
+        };
+        let offset = u64::from_be_bytes(buf[9..17].try_into()?);
+        let data = buf.split_off(FRAME_HEADER_LEN);
+        Ok(Frame {
+            id,
+            kind,
+            offset,
+            data,
+        })
+    }
+}
+
+/// Routes incoming binary frames to the transfer waiting for them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FrameRouter {
+    routes: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Frame>>>>,
+}
+
+impl FrameRouter {
+    pub(crate) fn subscribe(&self, id: u64) -> FrameSubscription {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.routes.lock().unwrap().insert(id, tx);
+        FrameSubscription {
+            id,
+            rx,
+            router: self.clone(),
+        }
+    }
+
+    pub(crate) fn dispatch(&self, buf: Vec<u8>) -> Result<()> {
+        let frame = Frame::decode(buf)?;
+        let routes = self.routes.lock().unwrap();
+        match routes.get(&frame.id) {
+            Some(tx) => {
+                let id = frame.id;
+                if tx.send(frame).is_err() {
+                    debug!("Transfer[id={}] is gone, frame dropped", id);
+                }
+            }
+            None => warn!("No transfer for binary frame[id={}], dropped", frame.id),
+        }
+        Ok(())
+    }
+}
+
+/// Receiving side of a [`FrameRouter`] route, unregistered on drop.
+pub(crate) struct FrameSubscription {
+    id: u64,
+    rx: mpsc::UnboundedReceiver<Frame>,
+    router: FrameRouter,
+}
+
+impl FrameSubscription {
+    async fn recv(&mut self) -> Result<Frame> {
+        match tokio::time::timeout(FRAME_TIMEOUT, self.rx.recv()).await {
+            Ok(Some(frame)) => Ok(frame),
+            Ok(None) => anyhow::bail!("Transfer[id={}] channel closed", self.id),
+            Err(_) => anyhow::bail!("Transfer[id={}] timed out waiting for peer", self.id),
+        }
+    }
+}
+
+impl Drop for FrameSubscription {
+    fn drop(&mut self) {
+        self.router.routes.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The connection a transfer exchanges frames over.
+pub(crate) trait FrameLink {
+    /// Transfer id carried in every frame.
+    fn id(&self) -> u64;
+    fn subscribe_frames(&self) -> FrameSubscription;
+    async fn send_frame(&self, frame: Frame) -> Result<()>;
+}
+
+impl FrameLink for Context {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn subscribe_frames(&self) -> FrameSubscription {
+        Context::subscribe_frames(self)
+    }
+
+    async fn send_frame(&self, frame: Frame) -> Result<()> {
+        Context::send_frame(self, frame).await
+    }
+}
+
+/// Hash the first `len` bytes of an open file.
+async fn hash_prefix(file: &mut tokio::fs::File, len: u64, hasher: &mut Sha256) -> Result<()> {
+    file.seek(SeekFrom::Start(0)).await?;
+    let mut buf = vec![0u8; 1024 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let want = left.min(buf.len() as u64) as usize;
+        let n = file.read(&mut buf[..want]).await?;
+        if n == 0 {
+            anyhow::bail!("File is shorter than resume offset {}", len);
+        }
+        hasher.update(&buf[..n]);
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+/// Send a local file to the controller as binary frames, starting at `offset`.
+///
+/// At most `window` chunks are in flight; the controller acknowledges them with
+/// cumulative `Ack` frames and must acknowledge the final `End` frame too.
+pub(crate) async fn send_file(
+    ctx: &impl FrameLink,
+    path: &str,
+    offset: u64,
+    chunk_size: u32,
+    window: u32,
+    throttle: &Throttle,
+) -> Result<FileOperationResponse> {
+    info!(
+        "Sending file {} over websocket from offset {}",
+        path, offset
+    );
+    if chunk_size > MAX_CHUNK_SIZE {
+        anyhow::bail!(
+            "Chunk size {} exceeds the maximum of {}",
+            chunk_size,
+            MAX_CHUNK_SIZE
+        );
+    }
+    if window > MAX_WINDOW {
+        anyhow::bail!("Window {} exceeds the maximum of {}", window, MAX_WINDOW);
+    }
+    let mut sub = ctx.subscribe_frames();
+    let mut file = tokio::fs::File::open(path).await?;
+    let size = file.metadata().await?.len();
+    if offset > size {
+        anyhow::bail!("Resume offset {} is beyond file size {}", offset, size);
+    }
+    let mut hasher = Sha256::new();
+    hash_prefix(&mut file, offset, &mut hasher).await?;
+
+    let in_flight = chunk_size as u64 * window.max(1) as u64;
+    let mut buf = vec![0u8; chunk_size.max(1) as usize];
+    let mut sent = offset;
+    let mut acked = offset;
+    while sent < size {
+        while sent < size && sent - acked < in_flight {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                anyhow::bail!("File {} shrank during transfer", path);
+            }
+            hasher.update(&buf[..n]);
+            throttle.consume(n).await;
+            let mut frame = Frame::new(ctx.id(), FrameKind::Data, sent);
+            frame.data = buf[..n].to_vec();
+            ctx.send_frame(frame).await?;
+            sent += n as u64;
+        }
+        let frame = sub.recv().await?;
+        match frame.kind {
+            FrameKind::Ack => acked = acked.max(frame.offset.min(sent)),
+            FrameKind::Abort => anyhow::bail!("Transfer aborted by controller"),
+            kind => debug!("Unexpected {:?} frame during upload, ignored", kind),
+        }
+    }
+
+    ctx.send_frame(Frame::new(ctx.id(), FrameKind::End, size))
+        .await?;
+    loop {
+        let frame = sub.recv().await?;
+        match frame.kind {
+            FrameKind::Ack if frame.offset >= size => break,
+            FrameKind::Ack => {}
+            FrameKind::Abort => anyhow::bail!("Transfer aborted by controller"),
+            kind => debug!("Unexpected {:?} frame during upload, ignored", kind),
+        }
+    }
+    Ok(FileOperationResponse {
+        success: true,
+        size: Some(size),
+        digest: Some(hex::encode(hasher.finalize())),
+        cache_hit: false,
+        error: None,
+    })
+}
+
+/// Receive a file from the controller as binary frames, resuming at `offset`.
+///
+/// The agent announces the resume point with an `Ack` frame and acknowledges
+/// every data frame. Out-of-order frames are answered with the current offset.
+pub(crate) async fn receive_file(
+    ctx: &impl FrameLink,
+    path: &str,
+    offset: u64,
+    digest: Option<&str>,
+) -> Result<FileOperationResponse> {
+    info!(
+        "Receiving file {} over websocket from offset {}",
+        path, offset
+    );
+    let mut sub = ctx.subscribe_frames();
+    let mut file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .await?;
+    if file.metadata().await?.len() < offset {
+        anyhow::bail!("Local file is shorter than resume offset {}", offset);
+    }
+    file.set_len(offset).await?;
+    let mut hasher = Sha256::new();
+    hash_prefix(&mut file, offset, &mut hasher).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut pos = offset;
+    ctx.send_frame(Frame::new(ctx.id(), FrameKind::Ack, pos))
+        .await?;
+    loop {
+        let frame = sub.recv().await?;
+        match frame.kind {
+            FrameKind::Data if frame.offset == pos => {
+                file.write_all(&frame.data).await?;
+                hasher.update(&frame.data);
+                pos += frame.data.len() as u64;
+            }
+            FrameKind::Data => {
+                debug!(
+                    "Data frame at offset {} while expecting {}, ignored",
+                    frame.offset, pos
+                );
+            }
+            FrameKind::End if frame.offset == pos => break,
+            FrameKind::End => anyhow::bail!(
+                "Transfer ended at offset {} but {} bytes were received",
+                frame.offset,
+                pos
+            ),
+            FrameKind::Abort => anyhow::bail!("Transfer aborted by controller"),
+            FrameKind::Ack => continue,
+        }
+        ctx.send_frame(Frame::new(ctx.id(), FrameKind::Ack, pos))
+            .await?;
+    }
+    file.sync_all().await?;
+
+    let actual = hex::encode(hasher.finalize());
+    verify_digest(digest, &actual)?;
+    ctx.send_frame(Frame::new(ctx.id(), FrameKind::Ack, pos))
+        .await?;
+    Ok(FileOperationResponse {
+        success: true,
+        size: Some(pos),
+        digest: Some(actual),
+        cache_hit: false,
+        error: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_codec() {
+        let mut frame = Frame::new(0x0102030405060708, FrameKind::Data, 1 << 40);
+        frame.data = b"chunk".to_vec();
+        let buf = frame.encode();
+        assert_eq!(buf.len(), FRAME_HEADER_LEN + 5);
+        assert_eq!(&buf[..9], &[1, 2, 3, 4, 5, 6, 7, 8, 0]);
+        assert_eq!(&buf[9..17], &(1u64 << 40).to_be_bytes());
+        let decoded = Frame::decode(buf).unwrap();
+        assert_eq!(decoded.id, frame.id);
+        assert_eq!(decoded.kind, FrameKind::Data);
+        assert_eq!(decoded.offset, frame.offset);
+        assert_eq!(decoded.data, b"chunk");
+
+        for kind in [FrameKind::Ack, FrameKind::End, FrameKind::Abort] {
+            let decoded = Frame::decode(Frame::new(9, kind, 42).encode()).unwrap();
+            assert_eq!((decoded.kind, decoded.offset), (kind, 42));
+            assert!(decoded.data.is_empty());
+        }
+    }
+
+    #[test]
+    fn malformed_frames() {
+        assert!(Frame::decode(vec![0; FRAME_HEADER_LEN - 1]).is_err());
+        let mut buf = Frame::new(1, FrameKind::End, 0).encode();
+        buf[8] = 4;
+        assert!(Frame::decode(buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn routing() {
+        let router = FrameRouter::default();
+        let mut subscription = router.subscribe(7);
+        router
+            .dispatch(Frame::new(7, FrameKind::Ack, 100).encode())
+            .unwrap();
+        // Frames for unknown transfers are dropped, not errors
+        router
+            .dispatch(Frame::new(8, FrameKind::Ack, 100).encode())
+            .unwrap();
+        let frame = subscription.recv().await.unwrap();
+        assert_eq!((frame.id, frame.offset), (7, 100));
+        drop(subscription);
+        assert!(router.routes.lock().unwrap().is_empty());
+        assert!(router.dispatch(vec![1, 2, 3]).is_err());
+    }
+
+    const ID: u64 = 5;
+
+    /// The agent end of an in-memory connection; frames it sends arrive on the
+    /// controller's receiver and the controller answers through the router.
+    struct Loopback {
+        router: FrameRouter,
+        peer: mpsc::UnboundedSender<Frame>,
+    }
+
+    impl FrameLink for Loopback {
+        fn id(&self) -> u64 {
+            ID
+        }
+
+        fn subscribe_frames(&self) -> FrameSubscription {
+            self.router.subscribe(ID)
+        }
+
+        async fn send_frame(&self, frame: Frame) -> Result<()> {
+            Ok(self.peer.send(frame)?)
+        }
+    }
+
+    struct Controller {
+        router: FrameRouter,
+        rx: mpsc::UnboundedReceiver<Frame>,
+    }
+
+    impl Controller {
+        async fn expect(&mut self, kind: FrameKind, offset: u64) -> Vec<u8> {
+            let frame = tokio::time::timeout(Duration::from_secs(5), self.rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!((frame.id, frame.kind, frame.offset), (ID, kind, offset));
+            frame.data
+        }
+
+        /// Let the agent run and check that it sent nothing more.
+        async fn expect_nothing(&mut self) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert!(self.rx.try_recv().is_err());
+        }
+
+        fn send(&self, kind: FrameKind, offset: u64, data: &[u8]) {
+            let mut frame = Frame::new(ID, kind, offset);
+            frame.data = data.to_vec();
+            self.router.dispatch(frame.encode()).unwrap();
+        }
+    }
+
+    fn loopback() -> (Loopback, Controller) {
+        let router = FrameRouter::default();
+        let (peer, rx) = mpsc::unbounded_channel();
+        let controller = Controller {
+            router: router.clone(),
+            rx,
+        };
+        (Loopback { router, peer }, controller)
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    const CONTENT: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    #[tokio::test]
+    async fn upload_window_and_end_ack() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, CONTENT).unwrap();
+        let (link, mut controller) = loopback();
+        let path = path.to_str().unwrap().to_string();
+        // Resume after the first 6 bytes, 4 byte chunks, 3 of them in flight
+        let upload =
+            tokio::spawn(
+                async move { send_file(&link, &path, 6, 4, 3, &Throttle::default()).await },
+            );
+
+        for offset in [6, 10, 14] {
+            let data = controller.expect(FrameKind::Data, offset).await;
+            assert_eq!(data, &CONTENT[offset as usize..offset as usize + 4]);
+        }
+        controller.expect_nothing().await;
+        // Acknowledging two chunks opens room for two more
+        controller.send(FrameKind::Ack, 14, b"");
+        controller.expect(FrameKind::Data, 18).await;
+        controller.expect(FrameKind::Data, 22).await;
+        controller.expect_nothing().await;
+        controller.send(FrameKind::Ack, 26, b"");
+        for offset in [26, 30, 34] {
+            controller.expect(FrameKind::Data, offset).await;
+        }
+        controller.send(FrameKind::Ack, 36, b"");
+        controller.expect(FrameKind::End, 36).await;
+        // The upload is not done until the end is acknowledged
+        controller.expect_nothing().await;
+        assert!(!upload.is_finished());
+        controller.send(FrameKind::Ack, 36, b"");
+        let response = upload.await.unwrap().unwrap();
+        assert_eq!(response.size, Some(36));
+        assert_eq!(response.digest.unwrap(), sha256(CONTENT));
+    }
+
+    #[tokio::test]
+    async fn upload_limits() {
+        let (link, _controller) = loopback();
+        let throttle = Throttle::default();
+        let err = send_file(&link, "/nonexistent", 0, MAX_CHUNK_SIZE + 1, 1, &throttle)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Chunk size"), "{}", err);
+        let err = send_file(&link, "/nonexistent", 0, 1024, MAX_WINDOW + 1, &throttle)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Window"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn download_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        // The part after the resume offset is stale and gets replaced
+        std::fs::write(&path, b"0123456789stale").unwrap();
+        let (link, mut controller) = loopback();
+        let digest = format!("sha256:{}", sha256(CONTENT));
+        let download = tokio::spawn({
+            let path = path.to_str().unwrap().to_string();
+            async move { receive_file(&link, &path, 10, Some(&digest)).await }
+        });
+
+        controller.expect(FrameKind::Ack, 10).await;
+        controller.send(FrameKind::Data, 10, &CONTENT[10..20]);
+        controller.expect(FrameKind::Ack, 20).await;
+        // A frame at the wrong offset is answered with the current one
+        controller.send(FrameKind::Data, 30, &CONTENT[30..]);
+        controller.expect(FrameKind::Ack, 20).await;
+        controller.send(FrameKind::Data, 20, &CONTENT[20..]);
+        controller.expect(FrameKind::Ack, 36).await;
+        controller.send(FrameKind::End, 36, b"");
+        controller.expect(FrameKind::Ack, 36).await;
+        let response = download.await.unwrap().unwrap();
+        assert_eq!(response.size, Some(36));
+        assert_eq!(std::fs::read(&path).unwrap(), CONTENT);
+    }
+
+    #[tokio::test]
+    async fn download_digest_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let (link, mut controller) = loopback();
+        let digest = sha256(b"something else");
+        let download = tokio::spawn({
+            let path = path.to_str().unwrap().to_string();
+            async move { receive_file(&link, &path, 0, Some(&digest)).await }
+        });
+
+        controller.expect(FrameKind::Ack, 0).await;
+        controller.send(FrameKind::Data, 0, CONTENT);
+        controller.expect(FrameKind::Ack, 36).await;
+        controller.send(FrameKind::End, 36, b"");
+        let err = download.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("Digest mismatch"), "{}", err);
+        // No final ack, so the controller does not take the file as delivered
+        controller.expect_nothing().await;
+    }
+}
@@ -1,36 +1,27 @@
-use std::{
-    fs::File,
-    io::{Read, Write},
-    process::Stdio,
-};
+use std::{fs::File, io::Write, path::PathBuf, process::Stdio};
 
 use anyhow::Result;
 use log::{error, info};
-use tokio::process::Command;
+use sha2::{Digest, Sha256};
+use tokio::{io::AsyncReadExt, process::Command};
+use tokio_util::io::ReaderStream;
 
-/// Get the machine UUID from the DMI table.
-pub(crate) fn get_machine_id() -> Result<String> {
-    let mut fd = File::open("/sys/firmware/dmi/entries/1-0/raw")?;
-    let mut buf: [u8; 24] = [0u8; 24];
-    fd.read_exact(&mut buf)?;
-    let buf2: [u8; 16] = buf[8..24].try_into()?;
-    Ok(buf2.iter().map(|b| format!("{:02x}", b)).collect())
+use crate::throttle::Throttle;
+
+const DEFAULT_STATE_DIR: &str = "/var/lib/mxa";
+
+/// Directory for what the agent keeps across restarts and reboots.
+pub(crate) fn state_dir() -> PathBuf {
+    let dir = std::env::var("MXA_STATE_DIR").unwrap_or_else(|_| DEFAULT_STATE_DIR.to_string());
+    PathBuf::from(dir)
 }
 
-/// Download a file from the given URL and save it to the given path.
-pub(crate) async fn download_file(url: &str, path: &str) -> Result<()> {
-    info!("Downloading file from {} to {}", url, path);
+/// Send a GET request and fail unless the server answers with a success status.
+pub(crate) async fn http_get(url: &str) -> Result<reqwest::Response> {
     let client = reqwest::Client::new();
-    let mut response = client.get(url).send().await?;
+    let response = client.get(url).send().await?;
     if response.status().is_success() {
-        let mut out = File::create(path)?;
-        loop {
-            let chunk = response.chunk().await?;
-            if chunk.is_none() {
-                return Ok(());
-            }
-            out.write_all(&chunk.unwrap())?;
-        }
+        Ok(response)
     } else {
         error!(
             "Failed to download file from {}. Server returned an error.",
@@ -40,14 +31,53 @@ pub(crate) async fn download_file(url: &str, path: &str) -> Result<()> {
     }
 }
 
+/// Download a file from the given URL and save it to the given path.
+pub(crate) async fn download_file(url: &str, path: &str, throttle: &Throttle) -> Result<()> {
+    info!("Downloading file from {} to {}", url, path);
+    let mut response = http_get(url).await?;
+    let mut out = File::create(path)?;
+    while let Some(chunk) = response.chunk().await? {
+        throttle.consume(chunk.len()).await;
+        out.write_all(&chunk)?;
+    }
+    Ok(())
+}
+
+/// Check a SHA-256 digest against an optional expected value, which may carry a `sha256:` prefix.
+pub(crate) fn verify_digest(expected: Option<&str>, actual: &str) -> Result<()> {
+    if let Some(expected) = expected {
+        let hex = expected.strip_prefix("sha256:").unwrap_or(expected);
+        if !hex.eq_ignore_ascii_case(actual) {
+            anyhow::bail!("Digest mismatch: expected {}, got {}", expected, actual);
+        }
+    }
+    Ok(())
+}
+
+/// Compute the hex-encoded SHA-256 of a file.
+pub(crate) async fn sha256_file(path: &str) -> Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
 /// Upload a file to the given URL.
-pub(crate) async fn upload_file(url: &str, path: &str) -> Result<()> {
+pub(crate) async fn upload_file(url: &str, path: &str, throttle: &Throttle) -> Result<()> {
     info!("Uploading file from {} to {}", path, url);
     let client = reqwest::Client::new();
-    let file = File::open(path)?;
+    let file = tokio::fs::File::open(path).await?;
+    let body = throttle.clone().wrap(ReaderStream::new(file));
     let req = client
         .put(url)
-        .body(tokio::fs::File::from_std(file))
+        .body(reqwest::Body::wrap_stream(body))
         .send()
         .await?;
     if req.status().is_success() {
@@ -62,7 +92,7 @@ pub(crate) async fn upload_file(url: &str, path: &str) -> Result<()> {
 }
 
 /// Execute an external command and return its output.
-pub(crate) async fn execute_command_with_output<'a>(
+pub(crate) async fn execute_command_with_output(
     cmd: &String,
     args: Vec<String>,
 ) -> Result<(i32, String, String)> {
@@ -72,6 +102,7 @@ pub(crate) async fn execute_command_with_output<'a>(
         .stdin(Stdio::null())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
+        .kill_on_drop(true)
         .spawn()?;
     let output = child.wait_with_output().await?;
     Ok((
@@ -81,6 +112,6 @@ pub(crate) async fn execute_command_with_output<'a>(
     ))
 }
 
-pub(crate) async fn execute_shell_with_output<'a>(cmd: &String) -> Result<(i32, String, String)> {
+pub(crate) async fn execute_shell_with_output(cmd: &String) -> Result<(i32, String, String)> {
     execute_command_with_output(&("sh".to_string()), vec!["-c".to_string(), cmd.to_string()]).await
 }
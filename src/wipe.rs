@@ -0,0 +1,363 @@
+use std::{
+    fs::File,
+    os::{fd::AsRawFd, unix::fs::FileExt},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use rand::{RngCore, SeedableRng, rngs::SmallRng};
+
+use crate::{
+    facts::read_trimmed,
+    hostfs,
+    image::{AlignedBuf, DEFAULT_BLOCK_SIZE, PROGRESS_INTERVAL, Target},
+    messages::{AgentResponsePayload, WipeMode, WipeRequest, WipeResponse, WipeSummary},
+    net::Context,
+    partition::{self, Disk, mounted},
+    signing,
+};
+
+/// Bytes zeroed at each end of the disk and its partitions by a signature wipe.
+const SIGNATURE_REGION: u64 = 1024 * 1024;
+/// Unit of random data generation and of read-back verification.
+const BLOCK: usize = 4096;
+const DEFAULT_SAMPLES: u32 = 64;
+/// Discards are issued in pieces so progress can be reported and cancelled.
+const DISCARD_CHUNK: u64 = 1024 * 1024 * 1024;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Kernel name of the block device at `path`, following links such as `/dev/disk/by-id`.
+fn device_name(path: &str) -> Option<String> {
+    std::fs::canonicalize(path)
+        .ok()
+        .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+}
+
+/// Serial number and model of the disk holding `path`, when sysfs knows them.
+fn identity(path: &str) -> (Option<String>, Option<String>) {
+    let Some(name) = device_name(path) else {
+        return (None, None);
+    };
+    // Partitions have no device directory of their own, their disk does
+    for dir in ["device", "../device"] {
+        let dir = hostfs::path(format!("/sys/class/block/{}/{}", name, dir));
+        let (serial, model) = (
+            read_trimmed(dir.join("serial")),
+            read_trimmed(dir.join("model")),
+        );
+        if serial.is_some() || model.is_some() {
+            return (serial, model);
+        }
+    }
+    (None, None)
+}
+
+/// Whether discarded ranges of `target` are known to read back as zeros. Holes
+/// punched in a regular file do; a device has to report it in sysfs.
+fn discard_zeroes(path: &str, target: &Target) -> bool {
+    if target.capacity.is_none() {
+        return true;
+    }
+    let Some(name) = device_name(path) else {
+        return false;
+    };
+    // Partitions share the queue of their disk
+    ["queue", "../queue"].iter().any(|dir| {
+        let flag = format!("/sys/class/block/{}/{}/discard_zeroes_data", name, dir);
+        read_trimmed(hostfs::path(flag)).as_deref() == Some("1")
+    })
+}
+
+/// The first and last [`SIGNATURE_REGION`] of the disk and each partition, merged.
+fn signature_regions(path: &str, size: u64) -> Vec<(u64, u64)> {
+    let partitions = match partition::inspect(path) {
+        Ok(disk) => disk.partitions,
+        Err(err) => {
+            warn!("Failed to read partitions of {}: {}", path, err);
+            Vec::new()
+        }
+    };
+    let mut regions = Vec::new();
+    let areas = partitions.iter().map(|p| (p.start, p.size));
+    for (start, len) in std::iter::once((0, size)).chain(areas) {
+        let end = (start + len).min(size);
+        let len = end.saturating_sub(start).min(SIGNATURE_REGION);
+        regions.extend([(start, start + len), (end - len, end)]);
+    }
+    regions.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (start, end) in regions {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+        .into_iter()
+        .filter(|(start, end)| end > start)
+        .map(|(start, end)| (start, end - start))
+        .collect()
+}
+
+/// Random data for a pass, reproducible from the seed so it can be verified.
+fn random_fill(seed: u64, pass: u32, offset: u64, buf: &mut [u8]) {
+    for (i, block) in buf.chunks_mut(BLOCK).enumerate() {
+        let index = offset / BLOCK as u64 + i as u64;
+        let mut rng = SmallRng::seed_from_u64(seed ^ ((pass as u64) << 56) ^ index);
+        rng.fill_bytes(block);
+    }
+}
+
+struct Progress {
+    pass: AtomicU32,
+    written: AtomicU64,
+    cancel: AtomicBool,
+}
+
+struct Wipe {
+    mode: WipeMode,
+    passes: u32,
+    seed: u64,
+    extents: Vec<(u64, u64)>,
+}
+
+impl Wipe {
+    /// What a block of the last pass should read back as.
+    fn expected(&self, offset: u64, buf: &mut [u8]) {
+        match self.mode {
+            WipeMode::Random => random_fill(self.seed, self.passes, offset, buf),
+            _ => buf.fill(0),
+        }
+    }
+
+    fn run(&self, target: &mut Target, progress: &Progress) -> Result<()> {
+        let mut buf = AlignedBuf::new(DEFAULT_BLOCK_SIZE as usize);
+        for pass in 1..=self.passes {
+            progress.pass.store(pass, Ordering::Relaxed);
+            progress.written.store(0, Ordering::Relaxed);
+            for &(start, len) in &self.extents {
+                let mut offset = start;
+                while offset < start + len {
+                    if progress.cancel.load(Ordering::Relaxed) {
+                        anyhow::bail!("Wipe cancelled");
+                    }
+                    let n = if self.mode == WipeMode::Discard {
+                        let n = (start + len - offset).min(DISCARD_CHUNK);
+                        target.discard(offset, n)?;
+                        n
+                    } else {
+                        let n = (start + len - offset).min(buf.as_slice().len() as u64);
+                        let data = &mut buf.as_mut_slice()[..n as usize];
+                        if self.mode == WipeMode::Random {
+                            random_fill(self.seed, pass, offset, data);
+                        }
+                        target.write_at(data, offset)?;
+                        n
+                    };
+                    offset += n;
+                    progress.written.fetch_add(n, Ordering::Relaxed);
+                }
+            }
+            target.file.sync_all()?;
+            debug!(
+                "Finished pass {} of {} on {}",
+                pass, self.passes, target.path
+            );
+        }
+        Ok(())
+    }
+
+    /// Offsets and lengths of the blocks to read back: all of them when there
+    /// are no more than `count`, otherwise the first, the last and random ones.
+    fn samples(&self, count: u32) -> Vec<(u64, usize)> {
+        let blocks = |len: u64| len.div_ceil(BLOCK as u64);
+        let total: u64 = self.extents.iter().map(|(_, len)| blocks(*len)).sum();
+        let mut indices: Vec<u64> = if total <= count as u64 {
+            (0..total).collect()
+        } else {
+            let mut indices = vec![0, total - 1];
+            while indices.len() < count as usize {
+                indices.push(rand::random_range(0..total));
+            }
+            indices
+        };
+        indices.truncate(count as usize);
+        indices
+            .into_iter()
+            .map(|mut index| {
+                for &(start, len) in &self.extents {
+                    if index < blocks(len) {
+                        let offset = start + index * BLOCK as u64;
+                        return (offset, (start + len - offset).min(BLOCK as u64) as usize);
+                    }
+                    index -= blocks(len);
+                }
+                unreachable!("block index within the extents")
+            })
+            .collect()
+    }
+
+    /// Read sampled blocks back from the device, past the page cache, and
+    /// count those that differ from what was written.
+    fn verify(&self, path: &str, count: u32) -> Result<(u32, u32)> {
+        let file = File::open(path)?;
+        // SAFETY: posix_fadvise on a file descriptor owned by `file`
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+        let samples = self.samples(count);
+        let (mut actual, mut expected) = (vec![0u8; BLOCK], vec![0u8; BLOCK]);
+        let mut mismatches = 0;
+        for &(offset, len) in &samples {
+            file.read_exact_at(&mut actual[..len], offset)?;
+            self.expected(offset, &mut expected[..len]);
+            if actual[..len] != expected[..len] {
+                warn!(
+                    "Block at {} of {} did not read back as written",
+                    offset, path
+                );
+                mismatches += 1;
+            }
+        }
+        Ok((samples.len() as u32, mismatches))
+    }
+}
+
+/// Wipe a device or image file, report progress while at it, and finish with a
+/// sampled read-back and a signed summary.
+pub(crate) async fn wipe(ctx: &Context, req: &WipeRequest) -> Result<WipeResponse> {
+    let path = req.target.clone();
+    std::fs::metadata(&path).map_err(|err| anyhow::anyhow!("{}: {}", path, err))?;
+    if mounted(&path) {
+        anyhow::bail!(
+            "{} or one of its partitions is mounted, used as swap or held by another device",
+            path
+        );
+    }
+    let mut target = Target::open(&path, true, false)?;
+    let size = match target.capacity {
+        Some(capacity) => capacity,
+        None => target.file.metadata()?.len(),
+    };
+    // Discarded blocks may read back as anything, so only check what is known to be zeroed
+    let verify = req.mode != WipeMode::Discard || discard_zeroes(&path, &target);
+    let passes = match req.mode {
+        WipeMode::Random => req.passes.unwrap_or(1).max(1),
+        _ => 1,
+    };
+    let extents = match req.mode {
+        WipeMode::Signatures => signature_regions(&path, size),
+        _ => vec![(0, size)],
+    };
+    let wipe = Arc::new(Wipe {
+        mode: req.mode,
+        passes,
+        seed: rand::random(),
+        extents,
+    });
+    let total: u64 = wipe.extents.iter().map(|(_, len)| len).sum();
+    let (serial, model) = identity(&path);
+    info!(
+        "Wiping {} ({:?}, {} pass(es), {} bytes)",
+        path, req.mode, passes, total
+    );
+
+    let started = now();
+    let progress = Arc::new(Progress {
+        pass: AtomicU32::new(1),
+        written: AtomicU64::new(0),
+        cancel: AtomicBool::new(false),
+    });
+    let mut worker = {
+        let (wipe, progress) = (wipe.clone(), progress.clone());
+        tokio::task::spawn_blocking(move || wipe.run(&mut target, &progress))
+    };
+    let mut ticks = tokio::time::interval(PROGRESS_INTERVAL);
+    loop {
+        tokio::select! {
+            result = &mut worker => break result??,
+            _ = ctx.cancelled(), if !progress.cancel.load(Ordering::Relaxed) => {
+                progress.cancel.store(true, Ordering::Relaxed);
+            }
+            _ = ticks.tick() => {
+                ctx.respond2(
+                    true,
+                    AgentResponsePayload::WipeResponse(WipeResponse {
+                        pass: progress.pass.load(Ordering::Relaxed),
+                        passes,
+                        written: progress.written.load(Ordering::Relaxed),
+                        total,
+                        ..Default::default()
+                    }),
+                )
+                .await;
+            }
+        }
+    }
+    if let Ok(disk) = Disk::open(&path, false)
+        && disk.block_device
+        && let Err(err) = disk.reread()
+    {
+        // Expected for partitions and devices that cannot be partitioned
+        debug!("Partition table of {} not re-read: {}", path, err);
+    }
+
+    let count = match req.mode {
+        WipeMode::Signatures => u32::MAX,
+        _ => req.samples.unwrap_or(DEFAULT_SAMPLES),
+    };
+    let (samples, mismatches) = if verify {
+        let (wipe, path) = (wipe.clone(), path.clone());
+        tokio::task::spawn_blocking(move || wipe.verify(&path, count)).await??
+    } else {
+        info!(
+            "{} does not report zeroing on discard, read-back skipped",
+            path
+        );
+        (0, 0)
+    };
+    let summary = WipeSummary {
+        target: path.clone(),
+        serial,
+        model,
+        size,
+        mode: req.mode,
+        passes,
+        wiped: total,
+        started,
+        finished: now(),
+        samples,
+        mismatches,
+        verified: verify && mismatches == 0,
+        verification_skipped: !verify,
+    };
+    info!(
+        "Wiped {}: {} of {} sampled block(s) read back as written",
+        path,
+        samples - mismatches,
+        samples
+    );
+    Ok(WipeResponse {
+        pass: passes,
+        passes,
+        written: total,
+        total,
+        summary: Some(signing::sign(serde_json::to_string(&summary)?)?),
+        done: true,
+        error: (mismatches > 0).then(|| {
+            format!(
+                "Verification failed: {} of {} sampled block(s) differ",
+                mismatches, samples
+            )
+        }),
+    })
+}